@@ -0,0 +1,146 @@
+//! Pluggable authentication of API requests with bearer tokens.
+//!
+//! When an [`Authenticator`] is configured, every request to the API must carry
+//! a token, either in an `Authorization: Bearer` header or in an `access_token`
+//! query parameter for WebSocket clients. Two implementations are included: a
+//! fixed list of [`StaticTokens`], and HS256-signed JSON Web Tokens with [`Jwt`].
+
+use std::fmt::{self, Debug};
+use std::time::SystemTime;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// Identity of an authenticated user, taken from their token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    /// Display name of the user, which clients cannot change.
+    pub name: String,
+    /// Color hue of the user, if the token assigns one.
+    pub hue: Option<u32>,
+}
+
+/// A validator of bearer tokens.
+pub trait Authenticator: Debug + Send + Sync {
+    /// Validate a token, returning the user it identifies, or `None` if the
+    /// token is not valid.
+    fn authenticate(&self, token: &str) -> Option<User>;
+}
+
+/// Authenticates a fixed list of tokens, each identifying a user.
+#[derive(Default)]
+pub struct StaticTokens {
+    tokens: Vec<(String, User)>,
+}
+
+impl StaticTokens {
+    /// Construct an authenticator accepting the given tokens.
+    pub fn new(tokens: impl IntoIterator<Item = (String, User)>) -> Self {
+        Self {
+            tokens: tokens.into_iter().collect(),
+        }
+    }
+}
+
+impl Debug for StaticTokens {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Avoid leaking the tokens into logs.
+        f.debug_struct("StaticTokens")
+            .field("count", &self.tokens.len())
+            .finish()
+    }
+}
+
+impl Authenticator for StaticTokens {
+    fn authenticate(&self, token: &str) -> Option<User> {
+        // Compare against every token in constant time, so timing does not
+        // reveal how much of a guess was correct.
+        let mut found = None;
+        for (candidate, user) in &self.tokens {
+            if bool::from(candidate.as_bytes().ct_eq(token.as_bytes())) {
+                found = Some(user.clone());
+            }
+        }
+        found
+    }
+}
+
+/// Authenticates JSON Web Tokens signed with HS256 and a shared secret.
+///
+/// The user's name is read from the `name` claim, falling back to `sub`, and
+/// their hue from an optional `hue` claim. The `exp` and `nbf` claims are
+/// checked when present.
+#[derive(Clone)]
+pub struct Jwt {
+    mac: Hmac<Sha256>,
+}
+
+impl Jwt {
+    /// Construct an authenticator for tokens signed with a secret.
+    pub fn new(secret: &[u8]) -> Self {
+        let mac = Hmac::new_from_slice(secret).expect("HMAC accepts keys of any length");
+        Self { mac }
+    }
+}
+
+impl Debug for Jwt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Jwt")
+    }
+}
+
+/// Header of a JSON Web Token.
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+/// Claims of a JSON Web Token that are used for authentication.
+#[derive(Deserialize)]
+struct JwtClaims {
+    sub: Option<String>,
+    name: Option<String>,
+    hue: Option<u32>,
+    exp: Option<u64>,
+    nbf: Option<u64>,
+}
+
+impl Authenticator for Jwt {
+    fn authenticate(&self, token: &str) -> Option<User> {
+        let mut parts = token.split('.');
+        let (Some(encoded_header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return None;
+        };
+
+        let header: JwtHeader =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(encoded_header).ok()?).ok()?;
+        if header.alg != "HS256" {
+            return None;
+        }
+        let mut mac = self.mac.clone();
+        mac.update(encoded_header.as_bytes());
+        mac.update(b".");
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?)
+            .ok()?;
+
+        let claims: JwtClaims =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("SystemTime returned before UNIX_EPOCH")
+            .as_secs();
+        if claims.exp.is_some_and(|exp| now >= exp) || claims.nbf.is_some_and(|nbf| now < nbf) {
+            return None;
+        }
+        Some(User {
+            name: claims.name.or(claims.sub)?,
+            hue: claims.hue,
+        })
+    }
+}
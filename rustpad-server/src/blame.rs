@@ -0,0 +1,66 @@
+//! Tracking which user inserted each part of a document.
+
+use operational_transform::{Operation, OperationSeq};
+
+/// Authorship of the current text, as runs of characters by the same user.
+///
+/// An author of `None` marks text whose origin is unknown, such as a document
+/// restored from storage without its edit history.
+#[derive(Clone, Debug, Default)]
+pub struct Attribution {
+    /// Consecutive runs of `(author, length)`, with lengths in characters.
+    runs: Vec<(Option<u64>, u64)>,
+}
+
+impl Attribution {
+    /// Updates the attribution after an operation by an author is applied.
+    pub fn apply(&mut self, operation: &OperationSeq, author: Option<u64>) {
+        let mut old = std::mem::take(&mut self.runs).into_iter();
+        let mut current = old.next();
+        for op in operation.ops() {
+            match op {
+                &Operation::Retain(n) | &Operation::Delete(n) => {
+                    let retain = matches!(op, Operation::Retain(_));
+                    let mut n = n;
+                    while n > 0 {
+                        let Some((run_author, len)) = current.as_mut() else {
+                            break;
+                        };
+                        let count = n.min(*len);
+                        if retain {
+                            self.push(*run_author, count);
+                        }
+                        *len -= count;
+                        n -= count;
+                        if *len == 0 {
+                            current = old.next();
+                        }
+                    }
+                }
+                Operation::Insert(s) => {
+                    self.push(author, bytecount::num_chars(s.as_bytes()) as u64);
+                }
+            }
+        }
+    }
+
+    /// Returns the runs of text as `(start, end, author)`, in characters.
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64, Option<u64>)> + '_ {
+        self.runs.iter().scan(0, |start, &(author, len)| {
+            let range = (*start, *start + len, author);
+            *start += len;
+            Some(range)
+        })
+    }
+
+    /// Appends a run, merging it with the last one if they share an author.
+    fn push(&mut self, author: Option<u64>, len: u64) {
+        if len == 0 {
+            return;
+        }
+        match self.runs.last_mut() {
+            Some((last, last_len)) if *last == author => *last_len += len,
+            _ => self.runs.push((author, len)),
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! Clustering of servers, where each document is owned by a single node and
+//! the other nodes forward requests for it to the owner.
+
+use std::net::IpAddr;
+
+use anyhow::{Context, Result};
+use futures::prelude::*;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest, http, protocol::CloseFrame},
+    MaybeTlsStream, WebSocketStream,
+};
+use warp::{
+    http::{HeaderMap, HeaderValue, Method, Response},
+    hyper::body::Bytes,
+    ws::{Message, WebSocket},
+};
+
+/// Header of forwarded requests, carrying the secret shared by the cluster.
+const FORWARDED_HEADER: &str = "x-rustpad-forwarded";
+
+/// Header of forwarded requests, carrying the IP address of the client.
+const CLIENT_ADDR_HEADER: &str = "x-rustpad-client-addr";
+
+/// Headers that describe a single connection, and are not forwarded.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "proxy-connection",
+    "sec-websocket-accept",
+    "sec-websocket-extensions",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// A WebSocket connection to the node that owns a document.
+pub type Upstream = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Membership of this server in a cluster of nodes.
+///
+/// Documents are assigned to nodes by rendezvous hashing on their IDs, so only
+/// the documents of a node move when it joins or leaves the cluster. Every
+/// node must be configured with the same list of nodes and the same secret.
+#[derive(Clone, Debug)]
+pub struct Cluster {
+    /// Base URL of this node, as the other nodes reach it.
+    node: String,
+    /// Base URLs of every node in the cluster, including this one.
+    nodes: Vec<String>,
+    /// Secret that marks requests forwarded by other nodes.
+    secret: HeaderValue,
+    /// Client for forwarding HTTP requests.
+    client: reqwest::Client,
+}
+
+impl Cluster {
+    /// Construct a cluster from the base URLs of its nodes, such as
+    /// `http://10.0.0.1:3030`, and of this node among them.
+    pub fn new(node: &str, nodes: &[String], secret: &str) -> Self {
+        let node = node.trim_end_matches('/').to_owned();
+        let mut nodes: Vec<_> = nodes
+            .iter()
+            .map(|node| node.trim_end_matches('/').to_owned())
+            .collect();
+        if !nodes.contains(&node) {
+            nodes.push(node.clone());
+        }
+        Self {
+            node,
+            nodes,
+            secret: HeaderValue::from_str(secret).expect("cluster secret should be a valid header"),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Returns the base URL of the node that owns a document, or `None` if it
+    /// is owned by this node.
+    pub fn owner(&self, document_id: &str) -> Option<&str> {
+        let owner = self
+            .nodes
+            .iter()
+            .max_by_key(|node| rank(node, document_id))
+            .expect("cluster should contain this node");
+        (*owner != self.node).then_some(owner.as_str())
+    }
+
+    /// Returns whether a request was forwarded by another node.
+    pub fn is_forwarded(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(FORWARDED_HEADER)
+            .is_some_and(|value| bool::from(value.as_bytes().ct_eq(self.secret.as_bytes())))
+    }
+
+    /// Returns the address of the client that a forwarding node passed on.
+    pub fn client_addr(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        headers.get(CLIENT_ADDR_HEADER)?.to_str().ok()?.parse().ok()
+    }
+
+    /// Forwards an HTTP request to the node that owns its document, returning
+    /// the response of that node.
+    pub async fn forward(
+        &self,
+        owner: &str,
+        method: Method,
+        path: &str,
+        headers: HeaderMap,
+        body: Bytes,
+        addr: Option<IpAddr>,
+    ) -> Result<Response<Bytes>> {
+        let response = self
+            .client
+            .request(method, format!("{}{}", owner, path))
+            .headers(self.forwarded_headers(headers, addr))
+            .body(body)
+            .send()
+            .await
+            .with_context(|| format!("failed to forward request to {}", owner))?;
+        let mut builder = Response::builder().status(response.status());
+        for (name, value) in response.headers() {
+            if !HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+                builder = builder.header(name, value);
+            }
+        }
+        Ok(builder.body(response.bytes().await?)?)
+    }
+
+    /// Opens a WebSocket connection to the node that owns a document, on
+    /// behalf of a client. If the owner refuses the connection, its response
+    /// is returned as the error, to pass on to the client.
+    pub async fn connect(
+        &self,
+        owner: &str,
+        path: &str,
+        headers: HeaderMap,
+        addr: Option<IpAddr>,
+    ) -> Result<Upstream, Response<Bytes>> {
+        let url = match owner.strip_prefix("https://") {
+            Some(host) => format!("wss://{}{}", host, path),
+            None => format!("ws://{}{}", owner.trim_start_matches("http://"), path),
+        };
+        let mut request = url.into_client_request().map_err(bad_gateway)?;
+        // The WebSocket client uses a newer version of the `http` crate.
+        for (name, value) in &self.forwarded_headers(headers, addr) {
+            let name = http::HeaderName::from_bytes(name.as_ref()).expect("valid header name");
+            let value = http::HeaderValue::from_bytes(value.as_bytes()).expect("valid header");
+            request.headers_mut().append(name, value);
+        }
+        match tokio_tungstenite::connect_async(request).await {
+            Ok((upstream, _)) => Ok(upstream),
+            Err(tungstenite::Error::Http(response)) => {
+                let mut builder = Response::builder().status(response.status().as_u16());
+                for (name, value) in response.headers() {
+                    builder = builder.header(name.as_str(), value.as_bytes());
+                }
+                let body = response.into_body().unwrap_or_default();
+                Err(builder.body(body.into()).expect("valid response"))
+            }
+            Err(e) => Err(bad_gateway(e)),
+        }
+    }
+
+    /// Copies the headers of a request to forward, marking it as forwarded.
+    fn forwarded_headers(&self, headers: HeaderMap, addr: Option<IpAddr>) -> HeaderMap {
+        let mut forwarded = HeaderMap::new();
+        for (name, value) in &headers {
+            if !HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+                forwarded.append(name, value.clone());
+            }
+        }
+        forwarded.insert(FORWARDED_HEADER, self.secret.clone());
+        if let Some(addr) = addr {
+            let addr = HeaderValue::from_str(&addr.to_string()).expect("valid header");
+            forwarded.insert(CLIENT_ADDR_HEADER, addr);
+        }
+        forwarded
+    }
+}
+
+/// Relays messages between a client and the node that owns its document, until
+/// either side closes the connection.
+pub async fn relay(socket: WebSocket, upstream: Upstream) -> Result<()> {
+    let (mut client_tx, mut client_rx) = socket.split();
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+    let outgoing = async {
+        while let Some(message) = client_rx.next().await {
+            if let Some(message) = to_upstream(message?) {
+                upstream_tx.send(message).await?;
+            }
+        }
+        anyhow::Ok(())
+    };
+    let incoming = async {
+        while let Some(message) = upstream_rx.next().await {
+            if let Some(message) = to_client(message?) {
+                client_tx.send(message).await?;
+            }
+        }
+        anyhow::Ok(())
+    };
+    tokio::select! {
+        result = outgoing => result,
+        result = incoming => result,
+    }
+}
+
+/// Ranks a node for a document, where the node with the highest rank owns it.
+fn rank(node: &str, document_id: &str) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(node)
+        .chain_update([0])
+        .chain_update(document_id)
+        .finalize();
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is long enough"))
+}
+
+/// Response to a client when the owner of its document cannot be reached.
+fn bad_gateway(error: impl std::fmt::Display) -> Response<Bytes> {
+    Response::builder()
+        .status(502)
+        .body(format!("failed to reach the owner of the document: {}", error).into())
+        .expect("valid response")
+}
+
+/// Converts a message from the client to send upstream. Pings and pongs are
+/// answered by each connection on its own, so they are not relayed.
+fn to_upstream(message: Message) -> Option<tungstenite::Message> {
+    if let Ok(text) = message.to_str() {
+        Some(tungstenite::Message::Text(text.to_owned()))
+    } else if message.is_binary() {
+        Some(tungstenite::Message::Binary(message.into_bytes()))
+    } else if message.is_close() {
+        let frame = message.close_frame().map(|(code, reason)| CloseFrame {
+            code: code.into(),
+            reason: reason.to_owned().into(),
+        });
+        Some(tungstenite::Message::Close(frame))
+    } else {
+        None
+    }
+}
+
+/// Converts a message from upstream to send to the client.
+fn to_client(message: tungstenite::Message) -> Option<Message> {
+    match message {
+        tungstenite::Message::Text(text) => Some(Message::text(text)),
+        tungstenite::Message::Binary(data) => Some(Message::binary(data)),
+        tungstenite::Message::Close(Some(frame)) => {
+            Some(Message::close_with(frame.code, frame.reason.into_owned()))
+        }
+        tungstenite::Message::Close(None) => Some(Message::close()),
+        _ => None,
+    }
+}
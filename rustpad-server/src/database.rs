@@ -1,33 +1,168 @@
-//! Backend PostgreSQL database handlers for persisting documents.
+//! Backend SQL database handlers for persisting documents.
+//!
+//! Both PostgreSQL and SQLite are supported, with the backend chosen from the
+//! scheme of the connection URI (`postgres://` or `sqlite://`).
 
-use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
 use log::{info, error, debug};
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use operational_transform::OperationSeq;
+use serde::{Deserialize, Serialize};
+use sqlx::any::{AnyConnectOptions, AnyKind, AnyPool, AnyPoolOptions};
+
+use crate::store::{DocumentPage, DocumentQuery, DocumentStore, DocumentSummary, NotFound};
 
 /// Represents a document persisted in database storage.
-#[derive(sqlx::FromRow, PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug)]
 pub struct PersistedDocument {
     /// Text content of the document.
     pub text: String,
     /// Language of the document for editor syntax highlighting.
     pub language: Option<String>,
+    /// Hash of the password required to access the document, if any.
+    pub password: Option<String>,
+    /// Latest information of every user who has edited, by user ID.
+    pub authors: BTreeMap<u64, PersistedAuthor>,
+}
+
+/// Name and color shared by a user who edited a document.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct PersistedAuthor {
+    /// Display name of the user.
+    pub name: String,
+    /// Hue of the user's color.
+    pub hue: u32,
+}
+
+/// Represents a single operation in the persisted history of a document.
+///
+/// Operations are stored in an append-only log, where the revision of each
+/// operation is given by its position in the log.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct PersistedOperation {
+    /// ID of the user who made the edit.
+    pub id: u64,
+    /// The text operation itself.
+    pub operation: OperationSeq,
+    /// Time the edit was made, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub time: u64,
+    /// Client session that sent the edit, so that it is recognized if the
+    /// client resubmits it after the document is reloaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// Sequence number of the edit within its client session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+/// Represents a named version of a document, saved by a user.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct PersistedSnapshot {
+    /// Label given to the snapshot by the user.
+    pub label: String,
+    /// Revision of the document when the snapshot was taken.
+    pub revision: usize,
+    /// ID of the user who created the snapshot.
+    pub id: u64,
+    /// Time the snapshot was created, in milliseconds since the Unix epoch.
+    pub time: u64,
+    /// Text content of the document at the snapshot.
+    pub text: String,
+    /// Language of the document at the snapshot.
+    pub language: Option<String>,
+}
+
+/// Role of a principal on a document, in increasing order of privilege.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can read the document, but not change it.
+    Viewer,
+    /// Can read and change the document.
+    Editor,
+    /// Can also delete the document and manage who has access to it.
+    Owner,
+}
+
+impl Role {
+    /// Returns the name of the role, as stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "editor" => Ok(Role::Editor),
+            "owner" => Ok(Role::Owner),
+            _ => Err(anyhow!("unknown role {:?}", s)),
+        }
+    }
+}
+
+/// Represents the access control list of a document.
+///
+/// Documents without an owner are open to everyone, as they were created
+/// before access control or by an anonymous user.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Default, Debug)]
+pub struct PersistedAcl {
+    /// Principal who owns the document, if any.
+    pub owner: Option<String>,
+    /// Roles granted to principals other than the owner.
+    pub grants: BTreeMap<String, Role>,
+}
+
+impl PersistedAcl {
+    /// Returns the role of a principal, or `None` if they have no access.
+    pub fn role(&self, principal: Option<&str>) -> Option<Role> {
+        let Some(owner) = &self.owner else {
+            return Some(Role::Owner);
+        };
+        let principal = principal?;
+        if principal == owner {
+            Some(Role::Owner)
+        } else {
+            self.grants.get(principal).copied()
+        }
+    }
 }
 
 /// A driver for database operations wrapping a pool connection.
 #[derive(Clone, Debug)]
 pub struct Database {
-    pool: PgPool,
+    pool: AnyPool,
+    kind: AnyKind,
 }
 
 impl Database {
-    /// Construct a new database from PostgreSQL connection URI.
+    /// Construct a new database from a PostgreSQL or SQLite connection URI.
+    ///
+    /// SQLite database files are created if they do not already exist.
     pub async fn new(uri: &str) -> Result<Self> {
-        let pool = PgPoolOptions::new()
+        let mut options = AnyConnectOptions::from_str(uri)?;
+        if let Some(sqlite) = options.as_sqlite_mut() {
+            *sqlite = sqlite.clone().create_if_missing(true);
+        }
+        let kind = options.kind();
+
+        let pool = AnyPoolOptions::new()
             .max_connections(5)
-            .connect(uri)
+            .connect_with(options)
             .await?;
             
-        info!("PostgreSQL connection established");
+        info!("{:?} connection established", kind);
         
         // Create table if it doesn't exist
         sqlx::query(
@@ -35,7 +170,104 @@ impl Database {
             CREATE TABLE IF NOT EXISTS document (
                 id TEXT PRIMARY KEY,
                 text TEXT NOT NULL,
-                language TEXT
+                language TEXT,
+                password TEXT,
+                authors TEXT
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        // Tables created before passwords were supported lack the column.
+        let has_password = sqlx::query("SELECT password FROM document LIMIT 1")
+            .execute(&pool)
+            .await
+            .is_ok();
+        if !has_password {
+            sqlx::query("ALTER TABLE document ADD COLUMN password TEXT")
+                .execute(&pool)
+                .await?;
+        }
+
+        // Tables created before authors were persisted lack the column.
+        let has_authors = sqlx::query("SELECT authors FROM document LIMIT 1")
+            .execute(&pool)
+            .await
+            .is_ok();
+        if !has_authors {
+            sqlx::query("ALTER TABLE document ADD COLUMN authors TEXT")
+                .execute(&pool)
+                .await?;
+        }
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS operation (
+                document_id TEXT NOT NULL,
+                revision BIGINT NOT NULL,
+                user_id BIGINT NOT NULL,
+                operation TEXT NOT NULL,
+                time BIGINT NOT NULL DEFAULT 0,
+                session TEXT,
+                seq BIGINT,
+                PRIMARY KEY (document_id, revision)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        // Operations stored before edits were timestamped lack the column.
+        let has_time = sqlx::query("SELECT time FROM operation LIMIT 1")
+            .execute(&pool)
+            .await
+            .is_ok();
+        if !has_time {
+            sqlx::query("ALTER TABLE operation ADD COLUMN time BIGINT NOT NULL DEFAULT 0")
+                .execute(&pool)
+                .await?;
+        }
+
+        // Operations stored before client sessions were persisted lack the columns.
+        let has_session = sqlx::query("SELECT session FROM operation LIMIT 1")
+            .execute(&pool)
+            .await
+            .is_ok();
+        if !has_session {
+            sqlx::query("ALTER TABLE operation ADD COLUMN session TEXT")
+                .execute(&pool)
+                .await?;
+            sqlx::query("ALTER TABLE operation ADD COLUMN seq BIGINT")
+                .execute(&pool)
+                .await?;
+        }
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS snapshot (
+                document_id TEXT NOT NULL,
+                number BIGINT NOT NULL,
+                label TEXT NOT NULL,
+                revision BIGINT NOT NULL,
+                user_id BIGINT NOT NULL,
+                time BIGINT NOT NULL,
+                text TEXT NOT NULL,
+                language TEXT,
+                PRIMARY KEY (document_id, number)
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS acl (
+                document_id TEXT NOT NULL,
+                principal TEXT NOT NULL,
+                role TEXT NOT NULL,
+                PRIMARY KEY (document_id, principal)
             )
             "#,
         )
@@ -47,22 +279,43 @@ impl Database {
             .execute(&pool)
             .await?;
         
-        Ok(Database { pool })
+        Ok(Database { pool, kind })
+    }
+
+    /// Returns which database backend this driver is connected to.
+    pub fn kind(&self) -> AnyKind {
+        self.kind
     }
 
     /// Load the text of a document from the database.
     pub async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
         debug!("Loading document: {}", document_id);
-        let result = sqlx::query_as(r#"SELECT text, language FROM document WHERE id = $1"#)
-            .bind(document_id)
-            .fetch_one(&self.pool)
-            .await;
+        let result = sqlx::query_as(
+            r#"SELECT text, language, password, authors FROM document WHERE id = $1"#,
+        )
+        .bind(document_id)
+        .fetch_one(&self.pool)
+        .await;
         
-        if result.is_err() {
+        if matches!(result, Err(sqlx::Error::RowNotFound)) {
             debug!("Document not found: {}", document_id);
         }
         
-        result.map_err(|e| e.into())
+        let (text, language, password, authors): (String, _, _, Option<String>) = match result {
+            Ok(row) => row,
+            Err(sqlx::Error::RowNotFound) => return Err(NotFound(document_id.into()).into()),
+            Err(e) => return Err(e.into()),
+        };
+        let authors = match authors {
+            Some(authors) => serde_json::from_str(&authors)?,
+            None => BTreeMap::new(),
+        };
+        Ok(PersistedDocument {
+            text,
+            language,
+            password,
+            authors,
+        })
     }
 
     /// Store the text of a document in the database.
@@ -71,16 +324,20 @@ impl Database {
         let result = sqlx::query(
             r#"
 INSERT INTO
-    document (id, text, language)
+    document (id, text, language, password, authors)
 VALUES
-    ($1, $2, $3)
+    ($1, $2, $3, $4, $5)
 ON CONFLICT(id) DO UPDATE SET
     text = excluded.text,
-    language = excluded.language"#,
+    language = excluded.language,
+    password = excluded.password,
+    authors = excluded.authors"#,
         )
         .bind(document_id)
         .bind(&document.text)
         .bind(&document.language)
+        .bind(&document.password)
+        .bind(serde_json::to_string(&document.authors)?)
         .execute(&self.pool)
         .await?;
         
@@ -104,4 +361,416 @@ ON CONFLICT(id) DO UPDATE SET
             
         Ok(row.0 as usize)
     }
+
+    /// Count the operations in the history of a document.
+    pub async fn count_operations(&self, document_id: &str) -> Result<usize> {
+        let row: (i64,) = sqlx::query_as("SELECT count(*) FROM operation WHERE document_id = $1")
+            .bind(document_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.0 as usize)
+    }
+
+    /// Load the operation history of a document, starting at revision 0.
+    pub async fn load_operations(&self, document_id: &str) -> Result<Vec<PersistedOperation>> {
+        #[allow(clippy::type_complexity)]
+        let rows: Vec<(i64, i64, String, i64, Option<String>, Option<i64>)> = sqlx::query_as(
+            r#"
+SELECT
+    revision, user_id, operation, time, session, seq
+FROM
+    operation
+WHERE
+    document_id = $1
+ORDER BY
+    revision"#,
+        )
+        .bind(document_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut operations = Vec::with_capacity(rows.len());
+        for (revision, user_id, operation, time, session, seq) in rows {
+            if revision as usize != operations.len() {
+                error!(
+                    "gap in operation history of {} at revision {}",
+                    document_id, revision
+                );
+                break;
+            }
+            operations.push(PersistedOperation {
+                id: user_id as u64,
+                operation: serde_json::from_str(&operation)?,
+                time: time as u64,
+                session,
+                seq: seq.map(|seq| seq as u64),
+            });
+        }
+        Ok(operations)
+    }
+
+    /// Append operations to the history of a document, starting at a revision.
+    ///
+    /// Revisions that were already stored are left unchanged.
+    pub async fn store_operations(
+        &self,
+        document_id: &str,
+        start: usize,
+        operations: &[PersistedOperation],
+    ) -> Result<()> {
+        debug!(
+            "Storing {} operations for document: {}",
+            operations.len(),
+            document_id
+        );
+        let mut tx = self.pool.begin().await?;
+        let stored: (i64,) =
+            sqlx::query_as("SELECT count(*) FROM operation WHERE document_id = $1")
+                .bind(document_id)
+                .fetch_one(&mut tx)
+                .await?;
+        if start > stored.0 as usize {
+            bail!("got revision {}, but history has {}", start, stored.0);
+        }
+        for (i, op) in operations.iter().enumerate() {
+            sqlx::query(
+                r#"
+INSERT INTO
+    operation (document_id, revision, user_id, operation, time, session, seq)
+VALUES
+    ($1, $2, $3, $4, $5, $6, $7)
+ON CONFLICT(document_id, revision) DO NOTHING"#,
+            )
+            .bind(document_id)
+            .bind((start + i) as i64)
+            .bind(op.id as i64)
+            .bind(serde_json::to_string(&op.operation)?)
+            .bind(op.time as i64)
+            .bind(op.session.clone())
+            .bind(op.seq.map(|seq| seq as i64))
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Load the named snapshots of a document, in order of creation.
+    pub async fn load_snapshots(&self, document_id: &str) -> Result<Vec<PersistedSnapshot>> {
+        #[allow(clippy::type_complexity)]
+        let rows: Vec<(String, i64, i64, i64, String, Option<String>)> = sqlx::query_as(
+            r#"
+SELECT
+    label, revision, user_id, time, text, language
+FROM
+    snapshot
+WHERE
+    document_id = $1
+ORDER BY
+    number"#,
+        )
+        .bind(document_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(label, revision, user_id, time, text, language)| PersistedSnapshot {
+                    label,
+                    revision: revision as usize,
+                    id: user_id as u64,
+                    time: time as u64,
+                    text,
+                    language,
+                },
+            )
+            .collect())
+    }
+
+    /// Append named snapshots to a document, starting at a given number.
+    ///
+    /// Snapshots that were already stored are left unchanged.
+    pub async fn store_snapshots(
+        &self,
+        document_id: &str,
+        start: usize,
+        snapshots: &[PersistedSnapshot],
+    ) -> Result<()> {
+        debug!(
+            "Storing {} snapshots for document: {}",
+            snapshots.len(),
+            document_id
+        );
+        let mut tx = self.pool.begin().await?;
+        let stored: (i64,) = sqlx::query_as("SELECT count(*) FROM snapshot WHERE document_id = $1")
+            .bind(document_id)
+            .fetch_one(&mut tx)
+            .await?;
+        if start > stored.0 as usize {
+            bail!("got snapshot {}, but only {} are stored", start, stored.0);
+        }
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            sqlx::query(
+                r#"
+INSERT INTO
+    snapshot (document_id, number, label, revision, user_id, time, text, language)
+VALUES
+    ($1, $2, $3, $4, $5, $6, $7, $8)
+ON CONFLICT(document_id, number) DO NOTHING"#,
+            )
+            .bind(document_id)
+            .bind((start + i) as i64)
+            .bind(&snapshot.label)
+            .bind(snapshot.revision as i64)
+            .bind(snapshot.id as i64)
+            .bind(snapshot.time as i64)
+            .bind(&snapshot.text)
+            .bind(&snapshot.language)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Load the access control list of a document.
+    pub async fn load_acl(&self, document_id: &str) -> Result<PersistedAcl> {
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT principal, role FROM acl WHERE document_id = $1")
+                .bind(document_id)
+                .fetch_all(&self.pool)
+                .await?;
+
+        let mut acl = PersistedAcl::default();
+        for (principal, role) in rows {
+            match role.parse()? {
+                Role::Owner => acl.owner = Some(principal),
+                role => {
+                    acl.grants.insert(principal, role);
+                }
+            }
+        }
+        Ok(acl)
+    }
+
+    /// Store the access control list of a document, replacing any existing one.
+    pub async fn store_acl(&self, document_id: &str, acl: &PersistedAcl) -> Result<()> {
+        debug!("Storing access control list for document: {}", document_id);
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM acl WHERE document_id = $1")
+            .bind(document_id)
+            .execute(&mut tx)
+            .await?;
+        let owner = acl.owner.iter().map(|owner| (owner, &Role::Owner));
+        for (principal, role) in owner.chain(&acl.grants) {
+            sqlx::query("INSERT INTO acl (document_id, principal, role) VALUES ($1, $2, $3)")
+                .bind(document_id)
+                .bind(principal)
+                .bind(role.as_str())
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Delete a document with its history, snapshots and access control list,
+    /// returning whether it existed.
+    pub async fn delete(&self, document_id: &str) -> Result<bool> {
+        debug!("Deleting document: {}", document_id);
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM operation WHERE document_id = $1")
+            .bind(document_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM snapshot WHERE document_id = $1")
+            .bind(document_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM acl WHERE document_id = $1")
+            .bind(document_id)
+            .execute(&mut tx)
+            .await?;
+        let result = sqlx::query("DELETE FROM document WHERE id = $1")
+            .bind(document_id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// List the identifiers of all documents in the database.
+    pub async fn list(&self) -> Result<Vec<String>> {
+        let rows: Vec<(String,)> = sqlx::query_as("SELECT id FROM document ORDER BY id")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|row| row.0).collect())
+    }
+
+    /// Find documents matching a query, returning a page of them in sorted
+    /// order along with the total number of matches.
+    ///
+    /// Filtering and paging happen in the database, without loading the text
+    /// of any document.
+    pub async fn query(&self, query: &DocumentQuery) -> Result<DocumentPage> {
+        let postgres = self.kind == AnyKind::Postgres;
+        // Text is matched ignoring the case of ASCII letters only, which is
+        // what `lower` does on SQLite.
+        let lower = |expr: &str| match postgres {
+            true => format!(
+                "translate({}, 'ABCDEFGHIJKLMNOPQRSTUVWXYZ', 'abcdefghijklmnopqrstuvwxyz')",
+                expr
+            ),
+            false => format!("lower({})", expr),
+        };
+        let find = if postgres { "strpos" } else { "instr" };
+
+        let mut binds: Vec<&str> = Vec::new();
+        let mut param = |value| {
+            binds.push(value);
+            format!("${}", binds.len())
+        };
+        // Documents without an owner are open to everyone.
+        let mut viewable = "NOT EXISTS (SELECT 1 FROM acl a \
+            WHERE a.document_id = d.id AND a.role = 'owner')"
+            .to_owned();
+        if let Some(principal) = &query.principal {
+            viewable = format!(
+                "({} OR EXISTS (SELECT 1 FROM acl a \
+                WHERE a.document_id = d.id AND a.principal = {}))",
+                viewable,
+                param(principal)
+            );
+        }
+        let mut conditions = vec!["d.password IS NULL".to_owned(), viewable];
+        if let Some(prefix) = &query.prefix {
+            let prefix = param(prefix);
+            conditions.push(format!("substr(d.id, 1, length({0})) = {0}", prefix));
+        }
+        if let Some(part) = &query.id {
+            conditions.push(format!("{}(d.id, {}) > 0", find, param(part)));
+        }
+        if let Some(text) = &query.text {
+            let text = lower(&param(text));
+            conditions.push(format!("{}({}, {}) > 0", find, lower("d.text"), text));
+        }
+        if !query.exclude.is_empty() {
+            let excluded: Vec<_> = query.exclude.iter().map(|id| param(id)).collect();
+            conditions.push(format!("d.id NOT IN ({})", excluded.join(", ")));
+        }
+        let filter = conditions.join(" AND ");
+
+        let sql = format!("SELECT count(*) FROM document d WHERE {}", filter);
+        let mut total = sqlx::query_as::<_, (i64,)>(&sql);
+        for value in &binds {
+            total = total.bind(*value);
+        }
+        let total = total.fetch_one(&self.pool).await?.0 as usize;
+
+        // IDs are sorted by their bytes, as they are in memory.
+        let (size, collate) = match postgres {
+            true => ("octet_length(d.text)", r#" COLLATE "C""#),
+            false => ("length(CAST(d.text AS BLOB))", ""),
+        };
+        let sql = format!(
+            r#"
+SELECT
+    d.id,
+    CAST({} AS BIGINT),
+    d.language,
+    (SELECT count(*) FROM operation o WHERE o.document_id = d.id)
+FROM
+    document d
+WHERE
+    {}
+ORDER BY
+    d.id{}
+LIMIT {} OFFSET {}"#,
+            size, filter, collate, query.limit, query.offset
+        );
+        let mut rows = sqlx::query_as::<_, (String, i64, Option<String>, i64)>(&sql);
+        for value in &binds {
+            rows = rows.bind(*value);
+        }
+        let documents = rows
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|(id, size, language, revision)| DocumentSummary {
+                id,
+                size: size as usize,
+                language,
+                revision: revision as usize,
+            })
+            .collect();
+        Ok(DocumentPage { total, documents })
+    }
+}
+
+#[async_trait]
+impl DocumentStore for Database {
+    async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
+        Database::load(self, document_id).await
+    }
+
+    async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        Database::store(self, document_id, document).await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        Database::count(self).await
+    }
+
+    async fn load_operations(&self, document_id: &str) -> Result<Vec<PersistedOperation>> {
+        Database::load_operations(self, document_id).await
+    }
+
+    async fn count_operations(&self, document_id: &str) -> Result<usize> {
+        Database::count_operations(self, document_id).await
+    }
+
+    async fn store_operations(
+        &self,
+        document_id: &str,
+        start: usize,
+        operations: &[PersistedOperation],
+    ) -> Result<()> {
+        Database::store_operations(self, document_id, start, operations).await
+    }
+
+    async fn load_snapshots(&self, document_id: &str) -> Result<Vec<PersistedSnapshot>> {
+        Database::load_snapshots(self, document_id).await
+    }
+
+    async fn store_snapshots(
+        &self,
+        document_id: &str,
+        start: usize,
+        snapshots: &[PersistedSnapshot],
+    ) -> Result<()> {
+        Database::store_snapshots(self, document_id, start, snapshots).await
+    }
+
+    async fn load_acl(&self, document_id: &str) -> Result<PersistedAcl> {
+        Database::load_acl(self, document_id).await
+    }
+
+    async fn store_acl(&self, document_id: &str, acl: &PersistedAcl) -> Result<()> {
+        Database::store_acl(self, document_id, acl).await
+    }
+
+    async fn delete(&self, document_id: &str) -> Result<bool> {
+        Database::delete(self, document_id).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        Database::list(self).await
+    }
+
+    async fn query(&self, query: &DocumentQuery) -> Result<DocumentPage> {
+        Database::query(self, query).await
+    }
 }
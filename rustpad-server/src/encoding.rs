@@ -0,0 +1,85 @@
+//! Encodings of WebSocket messages, negotiated for each connection.
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use warp::ws::Message;
+
+/// Messages at least this large are compressed, if compression is enabled.
+const COMPRESS_THRESHOLD: usize = 1024;
+
+/// Format of the messages on a WebSocket connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// JSON in text frames, which is the default.
+    #[default]
+    Json,
+    /// MessagePack in binary frames, with the same structure as JSON.
+    Msgpack,
+}
+
+/// Compression of large messages sent by the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Messages are not compressed.
+    #[default]
+    None,
+    /// Large binary frames are compressed with Zstandard. Clients can tell
+    /// them apart from MessagePack by the magic number of the Zstandard frame.
+    Zstd,
+}
+
+/// How messages are encoded on a WebSocket connection.
+///
+/// Clients always send messages uncompressed. Text frames are parsed as JSON
+/// in every format, so clients can fall back to it for some messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Encoding {
+    format: Format,
+    compression: Compression,
+}
+
+impl Encoding {
+    /// Construct a new encoding, or return `None` if the compression cannot be
+    /// used with the format. Only binary frames are compressed.
+    pub fn new(format: Format, compression: Compression) -> Option<Self> {
+        if format == Format::Json && compression != Compression::None {
+            return None;
+        }
+        Some(Self {
+            format,
+            compression,
+        })
+    }
+
+    /// Encodes a message to send to the client.
+    pub fn encode<T: Serialize>(&self, msg: &T) -> Message {
+        match self.format {
+            Format::Json => {
+                let serialized = serde_json::to_string(msg).expect("failed serialize");
+                Message::text(serialized)
+            }
+            Format::Msgpack => {
+                let serialized = rmp_serde::to_vec_named(msg).expect("failed serialize");
+                if self.compression == Compression::Zstd && serialized.len() >= COMPRESS_THRESHOLD {
+                    let compressed = zstd::bulk::compress(&serialized, 0).expect("failed compress");
+                    return Message::binary(compressed);
+                }
+                Message::binary(serialized)
+            }
+        }
+    }
+
+    /// Decodes a message from the client, returning `None` for frames that do
+    /// not carry messages in this encoding.
+    pub fn decode<T: DeserializeOwned>(&self, message: &Message) -> Result<Option<T>> {
+        if let Ok(text) = message.to_str() {
+            return Ok(Some(serde_json::from_str(text)?));
+        }
+        if self.format == Format::Msgpack && message.is_binary() {
+            return Ok(Some(rmp_serde::from_slice(message.as_bytes())?));
+        }
+        Ok(None)
+    }
+}
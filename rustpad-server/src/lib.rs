@@ -3,21 +3,65 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
+use anyhow::Context;
 use dashmap::DashMap;
-use log::{error, info};
+use log::{error, info, warn};
+use operational_transform::OperationSeq;
 use rand::Rng;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
 use tokio::time::{self, Instant};
-use warp::{filters::BoxedFilter, ws::Ws, Filter, Rejection, Reply};
+use warp::{
+    filters::{
+        path::{FullPath, Tail},
+        BoxedFilter,
+    },
+    http::{header::AUTHORIZATION, HeaderMap, Method, StatusCode},
+    hyper::body::Bytes,
+    reject::LengthRequired,
+    ws::Ws,
+    Filter, Rejection, Reply,
+};
 
-use crate::{database::Database, rustpad::Rustpad};
+use crate::{
+    auth::{Authenticator, User},
+    cluster::Cluster,
+    database::{PersistedAcl, PersistedDocument, PersistedSnapshot, Role},
+    encoding::{Compression, Encoding, Format},
+    limit::{RateLimiter, RateLimits},
+    metrics::{Metrics, TimedStore},
+    password::VerifiedPasswords,
+    rustpad::{
+        Access, Closed, Compacted, DocumentLimits, Rustpad, TooLarge, DEFAULT_MAX_DOCUMENT_SIZE,
+        DEFAULT_MAX_HISTORY,
+    },
+    share::ShareKey,
+    shutdown::Shutdown,
+    store::{is_not_found, DocumentPage, DocumentQuery, DocumentStore, DocumentSummary},
+};
 
+pub use crate::rustpad::{protocol_schema, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+pub mod auth;
+mod blame;
+pub mod cluster;
 pub mod database;
+pub mod encoding;
+pub mod limit;
+mod metrics;
 mod ot;
+mod password;
 mod rustpad;
+mod share;
+pub mod shutdown;
+pub mod store;
 
 /// An entry stored in the global server map.
 ///
@@ -27,19 +71,28 @@ mod rustpad;
 struct Document {
     last_accessed: Instant,
     rustpad: Arc<Rustpad>,
+    /// Background task persisting the document, if persistence is enabled.
+    persister: Option<JoinHandle<()>>,
 }
 
 impl Document {
-    fn new(rustpad: Arc<Rustpad>) -> Self {
+    fn new(rustpad: Arc<Rustpad>, persister: Option<JoinHandle<()>>) -> Self {
+        if let Some(metrics) = rustpad.metrics() {
+            metrics.documents.inc();
+        }
         Self {
             last_accessed: Instant::now(),
             rustpad,
+            persister,
         }
     }
 }
 
 impl Drop for Document {
     fn drop(&mut self) {
+        if let Some(metrics) = self.rustpad.metrics() {
+            metrics.documents.dec();
+        }
         self.rustpad.kill();
     }
 }
@@ -50,13 +103,69 @@ struct CustomReject(anyhow::Error);
 
 impl warp::reject::Reject for CustomReject {}
 
+/// Rejection for requests without a valid bearer token, when required.
+#[derive(Debug)]
+struct Unauthenticated;
+
+impl warp::reject::Reject for Unauthenticated {}
+
+/// Rejection for requests to a protected document without its password.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Rejection for requests by a principal without the role they need.
+#[derive(Debug)]
+struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+/// Rejection for WebSocket clients asking for a protocol version we don't speak.
+#[derive(Debug)]
+struct UnsupportedVersion;
+
+impl warp::reject::Reject for UnsupportedVersion {}
+
+/// Rejection for WebSocket clients asking for compression without a binary format.
+#[derive(Debug)]
+struct UnsupportedEncoding;
+
+impl warp::reject::Reject for UnsupportedEncoding {}
+
+/// Rejection for clients trying too many passwords.
+#[derive(Debug)]
+struct RateLimited(String);
+
+impl warp::reject::Reject for RateLimited {}
+
+/// Rejection for WebSocket clients connecting while the server shuts down.
+#[derive(Debug)]
+struct ShuttingDown;
+
+impl warp::reject::Reject for ShuttingDown {}
+
 /// The shared state of the server, accessible from within request handlers.
 #[derive(Clone)]
 struct ServerState {
     /// Concurrent map storing in-memory documents.
     documents: Arc<DashMap<String, Document>>,
-    /// Connection to the database pool, if persistence is enabled.
-    database: Option<Database>,
+    /// Storage backend for documents, if persistence is enabled.
+    database: Option<Arc<dyn DocumentStore>>,
+    /// Limits applied to every document.
+    limits: DocumentLimits,
+    /// Key for deriving read-only share tokens.
+    share_key: ShareKey,
+    /// Rate limits on edits from WebSocket connections, and on password checks.
+    limiter: Arc<RateLimiter>,
+    /// Passwords that were recently verified, to skip hashing them again.
+    verified_passwords: Arc<VerifiedPasswords>,
+    /// Cluster that this server is a node of, if any.
+    cluster: Option<Arc<Cluster>>,
+    /// Set when the server starts shutting down, to refuse new connections.
+    shutting_down: Arc<AtomicBool>,
+    /// Metrics of the server, exported to Prometheus.
+    metrics: Arc<Metrics>,
 }
 
 /// Statistics about the server, returned from an API endpoint.
@@ -68,6 +177,8 @@ struct Stats {
     num_documents: usize,
     /// Number of documents persisted in the database.
     database_size: usize,
+    /// Limits applied to every document.
+    limits: DocumentLimits,
 }
 
 /// Server configuration.
@@ -75,8 +186,30 @@ struct Stats {
 pub struct ServerConfig {
     /// Number of days to clean up documents after inactivity.
     pub expiry_days: u32,
-    /// Database object, for persistence if desired.
-    pub database: Option<Database>,
+    /// Storage backend, for persistence if desired.
+    pub database: Option<Arc<dyn DocumentStore>>,
+    /// Number of recent operations kept in memory for each document, before
+    /// older history is compacted into a checkpoint.
+    pub max_history: usize,
+    /// Maximum size of the text of each document, in Unicode code points.
+    pub max_document_size: usize,
+    /// Maximum number of users connected to each document, if limited.
+    pub max_users: Option<usize>,
+    /// Secret for deriving read-only share tokens. If not set, a random secret
+    /// is used, and tokens are only valid until the server restarts.
+    pub share_secret: Option<String>,
+    /// Authenticator for bearer tokens, required on every API route if set.
+    pub auth: Option<Arc<dyn Authenticator>>,
+    /// Header holding the authenticated principal, set by a trusted reverse
+    /// proxy. Requests with this header skip bearer token authentication.
+    pub principal_header: Option<String>,
+    /// Rate limits on edits and connections from WebSocket clients.
+    pub rate_limits: RateLimits,
+    /// Cluster of servers that share the documents, if this is one of them.
+    /// Requests for documents owned by other nodes are forwarded to them.
+    pub cluster: Option<Cluster>,
+    /// Handle for shutting down the server gracefully, if desired.
+    pub shutdown: Option<Shutdown>,
 }
 
 impl Default for ServerConfig {
@@ -84,95 +217,1341 @@ impl Default for ServerConfig {
         Self {
             expiry_days: 1,
             database: None,
+            max_history: DEFAULT_MAX_HISTORY,
+            max_document_size: DEFAULT_MAX_DOCUMENT_SIZE,
+            max_users: None,
+            share_secret: None,
+            auth: None,
+            principal_header: None,
+            rate_limits: RateLimits::default(),
+            cluster: None,
+            shutdown: None,
         }
     }
 }
 
 /// A combined filter handling all server routes.
 pub fn server(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
+    let metrics = Arc::new(Metrics::default());
     warp::path("api")
-        .and(backend(config))
+        .and(backend(config, Arc::clone(&metrics)))
+        .or(metrics_route(metrics))
         .or(frontend())
         .boxed()
 }
 
+/// Construct the route for metrics in the Prometheus text format.
+fn metrics_route(metrics: Arc<Metrics>) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("metrics")
+        .and(warp::get())
+        .map(move || {
+            let reply = warp::reply::with_status(metrics.render(), StatusCode::OK);
+            warp::reply::with_header(reply, "content-type", "text/plain; version=0.0.4")
+        })
+        .boxed()
+}
+
 /// Construct routes for static files from React.
 fn frontend() -> BoxedFilter<(impl Reply,)> {
     warp::fs::dir("dist").boxed()
 }
 
 /// Construct backend routes, including WebSocket handlers.
-fn backend(config: ServerConfig) -> BoxedFilter<(impl Reply,)> {
+fn backend(config: ServerConfig, metrics: Arc<Metrics>) -> BoxedFilter<(impl Reply,)> {
     let state = ServerState {
         documents: Default::default(),
-        database: config.database,
+        database: config.database.map(|db| {
+            Arc::new(TimedStore::new(db, Arc::clone(&metrics))) as Arc<dyn DocumentStore>
+        }),
+        limits: DocumentLimits {
+            max_document_size: config.max_document_size,
+            max_history: config.max_history,
+            max_users: config.max_users,
+        },
+        share_key: match &config.share_secret {
+            Some(secret) => ShareKey::new(secret.as_bytes()),
+            None => ShareKey::random(),
+        },
+        limiter: Arc::new(RateLimiter::new(config.rate_limits)),
+        verified_passwords: Default::default(),
+        cluster: config.cluster.map(Arc::new),
+        shutting_down: Default::default(),
+        metrics,
     };
     tokio::spawn(cleaner(state.clone(), config.expiry_days));
+    if let Some(shutdown) = config.shutdown {
+        tokio::spawn(shutdown_task(state.clone(), shutdown));
+    }
 
+    let cluster = state.cluster.clone();
+    let owner = forward_owner(state.clone());
     let state_filter = warp::any().map(move || state.clone());
+    let user = authenticate(config.auth, config.principal_header);
+    // Requests for documents pass through `authorize`, or handlers check them.
+    let credentials = password_filter(cluster.clone()).and(user.clone());
+    let auth = |role: Role| {
+        credentials
+            .clone()
+            .and(warp::any().map(move || role))
+            .and(state_filter.clone())
+    };
+
+    let forward_socket = owner
+        .clone()
+        .and(warp::ws())
+        .and(path_and_query())
+        .and(warp::header::headers_cloned())
+        .and(client_addr(cluster.clone()))
+        .and_then(forward_socket_handler);
+
+    let forward = owner
+        .and(warp::method())
+        .and(path_and_query())
+        .and(warp::header::headers_cloned())
+        .and(forward_body())
+        .and(client_addr(cluster.clone()))
+        .and_then(forward_handler);
 
     let socket = warp::path!("socket" / String)
+        .and(credentials.clone())
         .and(warp::ws())
+        .and(client_addr(cluster.clone()))
+        .and(protocol_filter())
         .and(state_filter.clone())
         .and_then(socket_handler);
 
+    let readonly_socket = warp::path!("readonly" / String)
+        .and(password_filter(cluster.clone()))
+        .and(warp::ws())
+        .and(client_addr(cluster.clone()))
+        .and(user.clone())
+        .and(protocol_filter())
+        .and(state_filter.clone())
+        .and_then(readonly_socket_handler);
+
+    let share = warp::path!("share" / String)
+        .and(warp::get())
+        .and(auth(Role::Editor))
+        .and_then(authorize)
+        .and(state_filter.clone())
+        .and_then(share_handler);
+
     let text = warp::path!("text" / String)
+        .and(warp::get())
+        .and(auth(Role::Viewer))
+        .and_then(authorize)
+        .and(warp::query())
         .and(state_filter.clone())
         .and_then(text_handler);
 
+    let put_text = warp::path!("text" / String)
+        .and(warp::put())
+        .and(credentials.clone())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and_then(put_text_handler);
+
+    let patch_text = warp::path!("text" / String)
+        .and(warp::patch())
+        .and(credentials.clone())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(patch_text_handler);
+
+    let delete = warp::path!("document" / String)
+        .and(warp::delete())
+        .and(auth(Role::Owner))
+        .and_then(authorize)
+        .and(state_filter.clone())
+        .and_then(delete_handler);
+
+    let documents = warp::path!("documents")
+        .and(warp::get())
+        .and(warp::query())
+        .and(user.clone())
+        .and(state_filter.clone())
+        .and_then(documents_handler);
+
+    let blame = warp::path!("blame" / String)
+        .and(warp::get())
+        .and(auth(Role::Viewer))
+        .and_then(authorize)
+        .and(state_filter.clone())
+        .and_then(blame_handler);
+
+    let snapshots = warp::path!("snapshots" / String)
+        .and(warp::get())
+        .and(auth(Role::Viewer))
+        .and_then(authorize)
+        .and(state_filter.clone())
+        .and_then(snapshots_handler);
+
+    let snapshot = warp::path!("snapshots" / String / usize)
+        .and(warp::get())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(snapshot_handler);
+
+    let restore = warp::path!("snapshots" / String / usize / "restore")
+        .and(warp::post())
+        .and(credentials.clone())
+        .and(state_filter.clone())
+        .and_then(restore_handler);
+
+    let set_password = warp::path!("password" / String)
+        .and(warp::put())
+        .and(credentials.clone())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(password_handler);
+
+    let acl = warp::path!("acl" / String)
+        .and(warp::get())
+        .and(auth(Role::Owner))
+        .and_then(authorize)
+        .and(state_filter.clone())
+        .and_then(acl_handler);
+
+    let grant = warp::path!("acl" / String)
+        .and(warp::put())
+        .and(credentials.clone())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+        .and(state_filter.clone())
+        .and_then(grant_handler);
+
     let start_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("SystemTime returned before UNIX_EPOCH")
         .as_secs();
     let stats = warp::path!("stats")
+        .and(user.map(|_| ()).untuple_one())
         .and(warp::any().map(move || start_time))
-        .and(state_filter)
+        .and(state_filter.clone())
         .and_then(stats_handler);
 
-    socket.or(text).or(stats).boxed()
+    forward_socket
+        .or(forward)
+        .or(socket)
+        .or(readonly_socket)
+        .or(share)
+        .or(text)
+        .or(put_text)
+        .or(patch_text)
+        .or(delete)
+        .or(documents)
+        .or(blame)
+        .or(snapshots)
+        .or(snapshot)
+        .or(restore)
+        .or(set_password)
+        .or(acl)
+        .or(grant)
+        .or(stats)
+        .recover(handle_rejection)
+        .boxed()
+}
+
+/// Query parameters that carry a bearer token, for clients that cannot set
+/// headers.
+#[derive(Deserialize)]
+struct TokenQuery {
+    /// Bearer token of the user, as in RFC 6750.
+    access_token: Option<String>,
+}
+
+/// Extracts the user authenticated by a request's bearer token, rejecting it
+/// if the token is missing or invalid.
+///
+/// If a principal header is configured and present, its value is trusted as
+/// the name of the user instead. This extracts `None` when authentication is
+/// disabled, or for requests without the header if there is no authenticator.
+fn authenticate(
+    auth: Option<Arc<dyn Authenticator>>,
+    principal_header: Option<String>,
+) -> impl Filter<Extract = (Option<User>,), Error = Rejection> + Clone {
+    warp::header::headers_cloned().and(warp::query()).and_then(
+        move |headers: HeaderMap, query: TokenQuery| {
+            let auth = auth.clone();
+            let principal = principal_header
+                .as_ref()
+                .and_then(|name| headers.get(name))
+                .and_then(|value| value.to_str().ok())
+                .map(|name| User {
+                    name: name.into(),
+                    hue: None,
+                });
+            async move {
+                if principal.is_some() {
+                    return Ok(principal);
+                }
+                let Some(auth) = auth else {
+                    return Ok(None);
+                };
+                let token = headers
+                    .get(AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|header| header.strip_prefix("Bearer "))
+                    .map(str::to_owned)
+                    .or(query.access_token);
+                match token.and_then(|token| auth.authenticate(&token)) {
+                    Some(user) => Ok(Some(user)),
+                    None => Err(warp::reject::custom(Unauthenticated)),
+                }
+            }
+        },
+    )
+}
+
+/// Name of the header that carries the password of a protected document.
+const PASSWORD_HEADER: &str = "x-document-password";
+
+/// Query parameters that carry the password of a protected document.
+#[derive(Deserialize)]
+struct PasswordQuery {
+    /// Password of the document, for clients that cannot set headers.
+    password: Option<String>,
+}
+
+/// A password given with a request, along with the address it came from.
+struct PasswordAttempt {
+    password: String,
+    addr: Option<IpAddr>,
+}
+
+/// Extracts the password given with a request, from a header or query string.
+fn password_filter(
+    cluster: Option<Arc<Cluster>>,
+) -> impl Filter<Extract = (Option<PasswordAttempt>,), Error = Rejection> + Clone {
+    warp::header::optional(PASSWORD_HEADER)
+        .and(warp::query())
+        .and(client_addr(cluster))
+        .map(
+            |header: Option<String>, query: PasswordQuery, addr: Option<IpAddr>| {
+                let password = header.or(query.password)?;
+                Some(PasswordAttempt { password, addr })
+            },
+        )
+}
+
+/// Query parameters for choosing the protocol of a WebSocket.
+#[derive(Deserialize)]
+struct ProtocolQuery {
+    /// Protocol version spoken by the client.
+    version: Option<u32>,
+    /// Format of messages on the connection.
+    #[serde(default)]
+    encoding: Format,
+    /// Compression of large messages sent by the server.
+    #[serde(default)]
+    compression: Compression,
+    /// Revision the client already has, when it is reconnecting.
+    revision: Option<usize>,
+    /// Epoch of the document that the revision belongs to.
+    epoch: Option<String>,
+}
+
+/// Protocol negotiated with a WebSocket client.
+struct Protocol {
+    version: u32,
+    encoding: Encoding,
+    revision: Option<usize>,
+    epoch: Option<String>,
+}
+
+/// Extracts the protocol asked for by a WebSocket client, rejecting versions
+/// and encodings that are not supported. Clients that don't ask speak version
+/// 1 in JSON.
+fn protocol_filter() -> impl Filter<Extract = (Protocol,), Error = Rejection> + Clone {
+    warp::query().and_then(|query: ProtocolQuery| async move {
+        let version = query.version.unwrap_or(MIN_PROTOCOL_VERSION);
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err(warp::reject::custom(UnsupportedVersion));
+        }
+        let Some(encoding) = Encoding::new(query.encoding, query.compression) else {
+            return Err(warp::reject::custom(UnsupportedEncoding));
+        };
+        Ok(Protocol {
+            version,
+            encoding,
+            revision: query.revision,
+            epoch: query.epoch,
+        })
+    })
+}
+
+/// Extracts the node of the cluster that owns the document of a request, if
+/// that is not this node, and rejects requests to handle here.
+///
+/// Requests forwarded by other nodes are always handled here, so that nodes
+/// with a different view of the cluster cannot forward them back and forth.
+fn forward_owner(
+    state: ServerState,
+) -> impl Filter<Extract = (Arc<Cluster>, String), Error = Rejection> + Clone {
+    warp::path::tail()
+        .and(warp::header::headers_cloned())
+        .and_then(move |tail: Tail, headers: HeaderMap| {
+            let state = state.clone();
+            async move {
+                let Some(cluster) = state.cluster.clone() else {
+                    return Err(warp::reject::not_found());
+                };
+                if cluster.is_forwarded(&headers) {
+                    return Err(warp::reject::not_found());
+                }
+                let mut segments = tail.as_str().split('/');
+                let id = match (segments.next(), segments.next()) {
+                    (Some("readonly"), Some(token)) => state.share_key.document_id(token),
+                    (
+                        Some(
+                            "socket" | "share" | "text" | "document" | "blame" | "snapshots"
+                            | "password" | "acl",
+                        ),
+                        Some(id),
+                    ) => Some(id.to_owned()),
+                    _ => None,
+                };
+                let owner = id.and_then(|id| Some(cluster.owner(&id)?.to_owned()));
+                match owner {
+                    Some(owner) => Ok((cluster, owner)),
+                    None => Err(warp::reject::not_found()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Extracts the path and query string of a request, to forward it.
+fn path_and_query() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+    warp::path::full()
+        .and(query)
+        .map(|path: FullPath, query: String| match query.as_str() {
+            "" => path.as_str().to_owned(),
+            query => format!("{}?{}", path.as_str(), query),
+        })
+}
+
+/// Extracts the body of a request to forward, with the same limit as bodies
+/// handled here. Requests without a length are forwarded without a body.
+fn forward_body() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::body::content_length_limit(MAX_BODY_SIZE)
+        .and(warp::body::bytes())
+        .or_else(|rejection: Rejection| async move {
+            match rejection.find::<LengthRequired>() {
+                Some(_) => Ok((Bytes::new(),)),
+                None => Err(rejection),
+            }
+        })
+}
+
+/// Extracts the IP address of the client, which is passed on by the node of
+/// the cluster that forwarded the request, if any.
+fn client_addr(
+    cluster: Option<Arc<Cluster>>,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .map(
+            move |addr: Option<SocketAddr>, headers: HeaderMap| match &cluster {
+                Some(cluster) if cluster.is_forwarded(&headers) => cluster.client_addr(&headers),
+                _ => addr.map(|addr| addr.ip()),
+            },
+        )
+}
+
+/// Rejects a request unless it has the password of the document, if any.
+async fn check_password(
+    state: &ServerState,
+    id: &str,
+    password: Option<PasswordAttempt>,
+) -> Result<(), Rejection> {
+    let hash = match state.documents.get(id) {
+        Some(value) => value.rustpad.password(),
+        None => match &state.database {
+            Some(db) => match db.load(id).await {
+                Ok(document) => document.password,
+                Err(e) if is_not_found(&e) => None,
+                // The document may be protected, so don't let the request through.
+                Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+            },
+            None => None,
+        },
+    };
+    let Some(hash) = hash else {
+        return Ok(());
+    };
+    let Some(PasswordAttempt { password, addr }) = password else {
+        return Err(warp::reject::custom(Unauthorized));
+    };
+    if state.verified_passwords.contains(&password, &hash) {
+        return Ok(());
+    }
+    if let Err(msg) = state.limiter.check_password(addr) {
+        return Err(warp::reject::custom(RateLimited(msg)));
+    }
+    // Hashing is deliberately slow, so keep it off the async workers.
+    let verified = tokio::task::spawn_blocking(move || {
+        let verified = password::verify(&password, &hash);
+        (verified, password, hash)
+    })
+    .await;
+    match verified {
+        Ok((true, password, hash)) => {
+            state.verified_passwords.insert(&password, &hash);
+            Ok(())
+        }
+        _ => Err(warp::reject::custom(Unauthorized)),
+    }
+}
+
+/// Returns the access control list of a document, without loading it into
+/// memory.
+async fn document_acl(state: &ServerState, id: &str) -> anyhow::Result<PersistedAcl> {
+    if let Some(value) = state.documents.get(id) {
+        return Ok(value.rustpad.acl());
+    }
+    match &state.database {
+        Some(db) => db.load_acl(id).await,
+        None => Ok(PersistedAcl::default()),
+    }
+}
+
+/// Rejects a request unless the user has at least a role on the document, and
+/// has its password if any. Returns the role of the user.
+async fn check_access(
+    state: &ServerState,
+    id: &str,
+    password: Option<PasswordAttempt>,
+    user: Option<&User>,
+    role: Role,
+) -> Result<Role, Rejection> {
+    let acl = document_acl(state, id)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    let principal = user.map(|user| user.name.as_str());
+    match acl.role(principal) {
+        Some(actual) if actual >= role => {
+            check_password(state, id, password).await?;
+            Ok(actual)
+        }
+        _ => Err(warp::reject::custom(Forbidden)),
+    }
+}
+
+/// Filter step that passes on the ID of a document if the user has at least a
+/// role on it, and the password matches.
+async fn authorize(
+    id: String,
+    password: Option<PasswordAttempt>,
+    user: Option<User>,
+    role: Role,
+    state: ServerState,
+) -> Result<String, Rejection> {
+    check_access(&state, &id, password, user.as_ref(), role).await?;
+    Ok(id)
+}
+
+/// Refuses new WebSocket connections once the server is shutting down.
+fn check_running(state: &ServerState) -> Result<(), Rejection> {
+    if state.shutting_down.load(Ordering::Relaxed) {
+        return Err(warp::reject::custom(ShuttingDown));
+    }
+    Ok(())
+}
+
+/// Converts rejections from this module into responses.
+async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    if err.find::<Unauthenticated>().is_some() {
+        let reply = warp::reply::with_status("missing or invalid token", StatusCode::UNAUTHORIZED);
+        let reply = warp::reply::with_header(reply, "www-authenticate", "Bearer");
+        return Ok(reply.into_response());
+    }
+    if err.find::<Unauthorized>().is_some() {
+        let msg = "document is protected by a password";
+        return Ok(warp::reply::with_status(msg, StatusCode::UNAUTHORIZED).into_response());
+    }
+    if let Some(RateLimited(msg)) = err.find() {
+        let reply = warp::reply::with_status(msg.clone(), StatusCode::TOO_MANY_REQUESTS);
+        return Ok(reply.into_response());
+    }
+    if err.find::<Forbidden>().is_some() {
+        let msg = "access to the document is denied";
+        return Ok(warp::reply::with_status(msg, StatusCode::FORBIDDEN).into_response());
+    }
+    if err.find::<UnsupportedVersion>().is_some() {
+        let msg = format!(
+            "unsupported protocol version, expected {} to {}",
+            MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
+        return Ok(warp::reply::with_status(msg, StatusCode::BAD_REQUEST).into_response());
+    }
+    if err.find::<UnsupportedEncoding>().is_some() {
+        let msg = "compression requires the msgpack encoding";
+        return Ok(warp::reply::with_status(msg, StatusCode::BAD_REQUEST).into_response());
+    }
+    if err.find::<ShuttingDown>().is_some() {
+        let msg = "server is shutting down";
+        return Ok(warp::reply::with_status(msg, StatusCode::SERVICE_UNAVAILABLE).into_response());
+    }
+    Err(err)
+}
+
+/// Body of a request to set the password of a document.
+#[derive(Deserialize)]
+struct PasswordRequest {
+    /// New password of the document, or `None` to remove protection.
+    password: Option<String>,
+}
+
+/// Handler for the `/api/password/{id}` endpoint.
+async fn password_handler(
+    id: String,
+    password: Option<PasswordAttempt>,
+    user: Option<User>,
+    request: PasswordRequest,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    check_access(&state, &id, password, user.as_ref(), Role::Owner).await?;
+    let hash = match request.password {
+        Some(password) => {
+            let hash = tokio::task::spawn_blocking(move || password::hash(&password));
+            Some(
+                hash.await
+                    .map_err(|e| warp::reject::custom(CustomReject(e.into())))?,
+            )
+        }
+        None => None,
+    };
+    let rustpad = open_document(&state, id, user.as_ref()).await?;
+    rustpad.set_password(hash);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Handler for `GET` requests to the `/api/acl/{id}` endpoint.
+async fn acl_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    match document_acl(&state, &id).await {
+        Ok(acl) => Ok(warp::reply::json(&acl)),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Body of a request to change the role of a principal on a document.
+#[derive(Deserialize)]
+struct GrantRequest {
+    /// Principal whose access is changed.
+    principal: String,
+    /// Role to grant, or `None` to revoke access.
+    role: Option<Role>,
+}
+
+/// Handler for `PUT` requests to the `/api/acl/{id}` endpoint.
+///
+/// Only the owner can change access, and the first principal to do so on a
+/// new document without an owner becomes its owner. Documents that were edited
+/// anonymously stay open to everyone.
+async fn grant_handler(
+    id: String,
+    password: Option<PasswordAttempt>,
+    user: Option<User>,
+    request: GrantRequest,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    check_access(&state, &id, password, user.as_ref(), Role::Owner).await?;
+    let Some(user) = user else {
+        return Err(warp::reject::custom(Forbidden));
+    };
+    if request.role == Some(Role::Owner) || request.principal == user.name {
+        let msg = "ownership of a document cannot be changed";
+        return Ok(warp::reply::with_status(msg, StatusCode::BAD_REQUEST).into_response());
+    }
+    let rustpad = open_document(&state, id, None).await?;
+    if !rustpad.grant(&user.name, &request.principal, request.role) {
+        return Err(warp::reject::custom(Forbidden));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Handler for WebSocket connections to documents owned by another node,
+/// which relays messages to the owner.
+async fn forward_socket_handler(
+    cluster: Arc<Cluster>,
+    owner: String,
+    ws: Ws,
+    path: String,
+    headers: HeaderMap,
+    addr: Option<IpAddr>,
+) -> Result<warp::reply::Response, Rejection> {
+    let upstream = match cluster.connect(&owner, &path, headers, addr).await {
+        Ok(upstream) => upstream,
+        Err(response) => return Ok(response.into_response()),
+    };
+    let reply = ws.on_upgrade(|socket| async move {
+        if let Err(e) = cluster::relay(socket, upstream).await {
+            warn!("relay to {} terminated early: {}", owner, e);
+        }
+    });
+    Ok(reply.into_response())
+}
+
+/// Handler for other requests to documents owned by another node, which
+/// returns the response of the owner.
+async fn forward_handler(
+    cluster: Arc<Cluster>,
+    owner: String,
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    body: Bytes,
+    addr: Option<IpAddr>,
+) -> Result<warp::reply::Response, Rejection> {
+    match cluster
+        .forward(&owner, method, &path, headers, body, addr)
+        .await
+    {
+        Ok(response) => Ok(response.into_response()),
+        Err(e) => {
+            let msg = format!("{:#}", e);
+            Ok(warp::reply::with_status(msg, StatusCode::BAD_GATEWAY).into_response())
+        }
+    }
 }
 
 /// Handler for the `/api/socket/{id}` endpoint.
-async fn socket_handler(id: String, ws: Ws, state: ServerState) -> Result<impl Reply, Rejection> {
+///
+/// Viewers connect as read-only, and cannot share their cursor either.
+async fn socket_handler(
+    id: String,
+    password: Option<PasswordAttempt>,
+    user: Option<User>,
+    ws: Ws,
+    addr: Option<IpAddr>,
+    protocol: Protocol,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    check_running(&state)?;
+    let role = check_access(&state, &id, password, user.as_ref(), Role::Viewer).await?;
+    let rustpad = open_document(&state, id, user.as_ref()).await?;
+    let access = Access {
+        readonly: role < Role::Editor,
+        cursors: role >= Role::Editor,
+        user,
+        addr,
+        limiter: state.limiter,
+        version: protocol.version,
+        encoding: protocol.encoding,
+        revision: protocol.revision,
+        epoch: protocol.epoch,
+    };
+    Ok(ws.on_upgrade(|socket| async move { rustpad.on_connection(socket, access).await }))
+}
+
+/// Handler for the `/api/readonly/{token}` endpoint.
+async fn readonly_socket_handler(
+    token: String,
+    password: Option<PasswordAttempt>,
+    ws: Ws,
+    addr: Option<IpAddr>,
+    user: Option<User>,
+    protocol: Protocol,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    check_running(&state)?;
+    let Some(id) = state.share_key.document_id(&token) else {
+        return Err(warp::reject::not_found());
+    };
+    check_password(&state, &id, password).await?;
+    let rustpad = open_document(&state, id, None).await?;
+    let access = Access {
+        readonly: true,
+        cursors: true,
+        user,
+        addr,
+        limiter: state.limiter,
+        version: protocol.version,
+        encoding: protocol.encoding,
+        revision: protocol.revision,
+        epoch: protocol.epoch,
+    };
+    Ok(ws.on_upgrade(|socket| async move { rustpad.on_connection(socket, access).await }))
+}
+
+/// Handler for the `/api/share/{id}` endpoint.
+async fn share_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let token = state.share_key.token(&id);
+    Ok(warp::reply::json(&serde_json::json!({ "token": token })))
+}
+
+/// Get a document from memory, loading it from storage if needed.
+///
+/// This also marks the document as recently accessed. If a user is given, they
+/// become the owner of the document if it is new.
+///
+/// Documents are loaded without holding a lock on the map, so that other
+/// documents can be opened meanwhile. If the same document is opened
+/// concurrently, the first to finish loading is kept.
+async fn open_document(
+    state: &ServerState,
+    id: String,
+    user: Option<&User>,
+) -> Result<Arc<Rustpad>, Rejection> {
     use dashmap::mapref::entry::Entry;
 
-    let mut entry = match state.documents.entry(id.clone()) {
-        Entry::Occupied(e) => e.into_ref(),
-        Entry::Vacant(e) => {
-            let rustpad = Arc::new(match &state.database {
-                Some(db) => db.load(&id).await.map(Rustpad::from).unwrap_or_default(),
-                None => Rustpad::default(),
-            });
-            if let Some(db) = &state.database {
-                tokio::spawn(persister(id, Arc::clone(&rustpad), db.clone()));
+    loop {
+        let loaded = if state.documents.contains_key(&id) {
+            None
+        } else {
+            Some(match &state.database {
+                Some(db) => load_document(db.as_ref(), &id)
+                    .await
+                    .map_err(|e| warp::reject::custom(CustomReject(e)))?,
+                None => (Rustpad::default(), Persisted::default()),
+            })
+        };
+
+        let mut entry = match (state.documents.entry(id.clone()), loaded) {
+            (Entry::Occupied(e), _) => e.into_ref(),
+            (Entry::Vacant(e), Some((rustpad, persisted))) => {
+                let rustpad = Arc::new(rustpad);
+                rustpad.set_limits(state.limits);
+                rustpad.set_metrics(Arc::clone(&state.metrics));
+                let persister = state.database.as_ref().map(|db| {
+                    rustpad.set_persisted_revision(persisted.revision);
+                    tokio::spawn(persister(
+                        id.clone(),
+                        Arc::clone(&rustpad),
+                        Arc::clone(db),
+                        Arc::clone(&state.metrics),
+                        persisted,
+                    ))
+                });
+                e.insert(Document::new(rustpad, persister))
             }
-            e.insert(Document::new(rustpad))
+            // The document was removed from memory since, so load it again.
+            (Entry::Vacant(_), None) => continue,
+        };
+
+        let value = entry.value_mut();
+        value.last_accessed = Instant::now();
+        if let Some(user) = user {
+            value.rustpad.claim(&user.name);
+        }
+        return Ok(Arc::clone(&value.rustpad));
+    }
+}
+
+/// How much of a document has already been written to storage.
+#[derive(Clone, Default)]
+struct Persisted {
+    /// Number of operations in the stored history.
+    revision: usize,
+    /// Number of stored named snapshots.
+    snapshots: usize,
+    /// Stored hash of the document password.
+    password: Option<String>,
+    /// Stored access control list.
+    acl: PersistedAcl,
+}
+
+/// Load a document from storage, along with how much of it is stored.
+///
+/// Failing to load anything but a document that was never stored fails the
+/// whole load, since the persister would otherwise write over what is stored.
+async fn load_document(db: &dyn DocumentStore, id: &str) -> anyhow::Result<(Rustpad, Persisted)> {
+    let operations = db
+        .load_operations(id)
+        .await
+        .with_context(|| format!("failed to load history of document {}", id))?;
+    let snapshots = db
+        .load_snapshots(id)
+        .await
+        .with_context(|| format!("failed to load snapshots of document {}", id))?;
+    let acl = db
+        .load_acl(id)
+        .await
+        .with_context(|| format!("failed to load access control list of document {}", id))?;
+    let document = match db.load(id).await {
+        Ok(document) => document,
+        Err(e) if !is_not_found(&e) => {
+            return Err(e.context(format!("failed to load document {}", id)));
+        }
+        Err(_) if operations.is_empty() && snapshots.is_empty() => {
+            let rustpad = Rustpad::default();
+            rustpad.set_acl(acl.clone());
+            let persisted = Persisted {
+                acl,
+                ..Default::default()
+            };
+            return Ok((rustpad, persisted));
         }
+        Err(_) => PersistedDocument {
+            text: String::new(),
+            language: None,
+            password: None,
+            authors: Default::default(),
+        },
+    };
+    let persisted = Persisted {
+        revision: operations.len(),
+        snapshots: snapshots.len(),
+        password: document.password.clone(),
+        acl: acl.clone(),
     };
+    let rustpad = Rustpad::restore(document, operations, snapshots);
+    rustpad.set_acl(acl);
+    Ok((rustpad, persisted))
+}
+
+/// Query parameters for reading a past version of a document.
+#[derive(Deserialize)]
+struct TextQuery {
+    /// Revision of the document to read.
+    revision: Option<usize>,
+    /// Time to read the document at, in milliseconds since the Unix epoch.
+    time: Option<u64>,
+}
 
-    let value = entry.value_mut();
-    value.last_accessed = Instant::now();
-    let rustpad = Arc::clone(&value.rustpad);
-    Ok(ws.on_upgrade(|socket| async move { rustpad.on_connection(socket).await }))
+impl TextQuery {
+    /// Returns the text of the document at the requested point, if available.
+    fn text(&self, rustpad: &Rustpad) -> Option<String> {
+        let revision = match (self.revision, self.time) {
+            (Some(revision), _) => revision,
+            (None, Some(time)) => rustpad.revision_at(time)?,
+            (None, None) => rustpad.revision(),
+        };
+        rustpad.text_at(revision)
+    }
 }
 
 /// Handler for the `/api/text/{id}` endpoint.
-async fn text_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
-    Ok(match state.documents.get(&id) {
-        Some(value) => value.rustpad.text(),
-        None => {
-            if let Some(db) = &state.database {
-                db.load(&id)
-                    .await
-                    .map(|document| document.text)
-                    .unwrap_or_default()
+async fn text_handler(
+    id: String,
+    query: TextQuery,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    if query.revision.is_none() && query.time.is_none() {
+        let text = match state.documents.get(&id) {
+            Some(value) => value.rustpad.text(),
+            None => {
+                if let Some(db) = &state.database {
+                    match db.load(&id).await {
+                        Ok(document) => document.text,
+                        Err(e) if is_not_found(&e) => String::new(),
+                        Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+                    }
+                } else {
+                    String::new()
+                }
+            }
+        };
+        return Ok(text.into_response());
+    }
+
+    let rustpad = state
+        .documents
+        .get(&id)
+        .map(|value| Arc::clone(&value.rustpad));
+    let in_memory = rustpad.as_deref().map(|rustpad| query.text(rustpad));
+    let text = match (in_memory, state.database.as_deref()) {
+        (Some(Some(text)), _) => Some(text),
+        // Compacted history may still be available in storage.
+        (_, Some(db)) => {
+            let (rustpad, _) = load_document(db, &id)
+                .await
+                .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+            query.text(&rustpad)
+        }
+        (Some(None), None) => None,
+        (None, None) => query.text(&Rustpad::default()),
+    };
+    Ok(match text {
+        Some(text) => text.into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+/// Maximum size of a request body for writing to a document, in bytes.
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+/// Body of a request to apply an edit to a document.
+#[derive(Deserialize)]
+struct EditRequest {
+    /// Revision of the document that the edit is based on.
+    revision: usize,
+    /// The text operation to apply.
+    operation: OperationSeq,
+}
+
+/// Converts the result of editing a document into a response.
+fn edit_response(result: anyhow::Result<usize>) -> warp::reply::Response {
+    match result {
+        Ok(revision) => {
+            let body = serde_json::json!({ "revision": revision });
+            warp::reply::json(&body).into_response()
+        }
+        Err(e) => {
+            let status = if e.is::<Compacted>() {
+                StatusCode::CONFLICT
+            } else if e.is::<TooLarge>() {
+                StatusCode::PAYLOAD_TOO_LARGE
+            } else if e.is::<Closed>() {
+                StatusCode::SERVICE_UNAVAILABLE
             } else {
-                String::new()
+                StatusCode::BAD_REQUEST
+            };
+            warp::reply::with_status(e.to_string(), status).into_response()
+        }
+    }
+}
+
+/// Handler for `PUT` requests to the `/api/text/{id}` endpoint.
+async fn put_text_handler(
+    id: String,
+    password: Option<PasswordAttempt>,
+    user: Option<User>,
+    body: Bytes,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    check_access(&state, &id, password, user.as_ref(), Role::Editor).await?;
+    let Ok(text) = std::str::from_utf8(&body) else {
+        let msg = "text is not valid UTF-8";
+        return Ok(warp::reply::with_status(msg, StatusCode::BAD_REQUEST).into_response());
+    };
+    let rustpad = open_document(&state, id, user.as_ref()).await?;
+    Ok(edit_response(rustpad.set_text(text, user.as_ref())))
+}
+
+/// Handler for `PATCH` requests to the `/api/text/{id}` endpoint.
+async fn patch_text_handler(
+    id: String,
+    password: Option<PasswordAttempt>,
+    user: Option<User>,
+    edit: EditRequest,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    check_access(&state, &id, password, user.as_ref(), Role::Editor).await?;
+    let rustpad = open_document(&state, id, user.as_ref()).await?;
+    Ok(edit_response(rustpad.edit(
+        edit.revision,
+        edit.operation,
+        user.as_ref(),
+    )))
+}
+
+/// Handler for `DELETE` requests to the `/api/document/{id}` endpoint.
+async fn delete_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let mut found = false;
+    if let Some((_, mut document)) = state.documents.remove(&id) {
+        document.rustpad.delete();
+        found = true;
+        // Wait for a persist in progress, so it cannot write the document back.
+        if let Some(persister) = document.persister.take() {
+            if let Err(e) = persister.await {
+                error!("when persisting deleted document {}: {}", id, e);
             }
         }
+    }
+    if let Some(db) = &state.database {
+        match db.delete(&id).await {
+            Ok(deleted) => found |= deleted,
+            Err(e) => return Err(warp::reject::custom(CustomReject(e))),
+        }
+    }
+    Ok(if found {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
     })
 }
 
+/// Query parameters for listing documents.
+#[derive(Deserialize)]
+struct ListQuery {
+    /// Only include documents whose ID starts with this prefix.
+    prefix: Option<String>,
+    /// Only include documents whose ID contains this substring.
+    id: Option<String>,
+    /// Only include documents whose text contains this substring, ignoring
+    /// the case of ASCII letters.
+    q: Option<String>,
+    /// Number of matching documents to skip.
+    #[serde(default)]
+    offset: usize,
+    /// Maximum number of documents to return.
+    limit: Option<usize>,
+}
+
+/// Number of documents listed per page, unless requested otherwise.
+const DEFAULT_LIST_LIMIT: usize = 50;
+
+/// Maximum number of documents listed per page.
+const MAX_LIST_LIMIT: usize = 1000;
+
+/// Summary of a document, returned when listing documents.
+#[derive(Serialize)]
+struct DocumentInfo {
+    /// Identifier of the document.
+    id: String,
+    /// Size of the text, in bytes.
+    size: usize,
+    /// Language of the document, if set.
+    language: Option<String>,
+    /// Current revision of the document.
+    revision: usize,
+    /// Number of clients currently connected.
+    users: usize,
+    /// Whether the document is protected by a password.
+    protected: bool,
+    /// Time the document was last accessed, in milliseconds since the Unix
+    /// epoch, or `None` if it is not in memory.
+    last_accessed: Option<u64>,
+}
+
+/// A page of documents matching a listing query.
+#[derive(Serialize)]
+struct DocumentList {
+    /// Number of matching documents, across all pages.
+    total: usize,
+    /// Matching documents in this page, sorted by ID.
+    documents: Vec<DocumentInfo>,
+}
+
+/// Lists in-memory and stored documents matching a query, which the user can
+/// view without a password.
+///
+/// Documents in memory are matched here by their latest version, while the
+/// rest are filtered and paged by the storage backend, following the same
+/// rules.
+async fn list_documents(
+    state: &ServerState,
+    query: &ListQuery,
+    user: &User,
+) -> anyhow::Result<DocumentList> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .min(MAX_LIST_LIMIT);
+    let mut filter = DocumentQuery {
+        prefix: query.prefix.clone(),
+        id: query.id.clone(),
+        text: query.q.clone(),
+        principal: Some(user.name.clone()),
+        ..DocumentQuery::default()
+    };
+    let mut in_memory = BTreeMap::new();
+    for entry in state.documents.iter() {
+        let (id, rustpad) = (entry.key(), &entry.rustpad);
+        let text = match filter.text {
+            Some(_) => rustpad.text(),
+            None => String::new(),
+        };
+        if filter.matches(id, &text, rustpad.password().as_deref(), &rustpad.acl()) {
+            let value = (Arc::clone(rustpad), entry.last_accessed);
+            in_memory.insert(id.clone(), value);
+        }
+        filter.exclude.insert(id.clone());
+    }
+
+    // Stored documents are interleaved with those in memory, so their page
+    // starts early enough to cover any in-memory documents before it.
+    let start = query.offset.saturating_sub(in_memory.len());
+    let stored = match &state.database {
+        Some(db) => {
+            let filter = DocumentQuery {
+                offset: start,
+                limit: query.offset + limit - start,
+                ..filter
+            };
+            db.query(&filter).await?
+        }
+        None => DocumentPage::default(),
+    };
+    // In-memory documents before a later page of stored ones come before the
+    // requested page too.
+    let first_stored = stored.documents.first().map(|summary| summary.id.clone());
+    let skipped = match start {
+        0 => 0,
+        _ => in_memory
+            .keys()
+            .take_while(|id| first_stored.as_ref().is_none_or(|first| *id < first))
+            .count(),
+    };
+    let mut merged: Vec<(String, Option<DocumentSummary>)> = in_memory
+        .keys()
+        .skip(skipped)
+        .map(|id| (id.clone(), None))
+        .collect();
+    merged.extend(
+        stored
+            .documents
+            .into_iter()
+            .map(|summary| (summary.id.clone(), Some(summary))),
+    );
+    merged.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let total = in_memory.len() + stored.total;
+    let mut documents = Vec::new();
+    let page = merged.into_iter().skip(query.offset - start - skipped);
+    for (id, summary) in page.take(limit) {
+        let info = match (summary, in_memory.get(&id)) {
+            (Some(summary), _) => DocumentInfo {
+                size: summary.size,
+                language: summary.language,
+                // Documents without a stored history are restored as one operation.
+                revision: summary.revision.max(1),
+                users: 0,
+                protected: false,
+                last_accessed: None,
+                id,
+            },
+            (None, Some((rustpad, last_accessed))) => {
+                let document = rustpad.snapshot();
+                let last_accessed = SystemTime::now() - last_accessed.elapsed();
+                DocumentInfo {
+                    size: document.text.len(),
+                    language: document.language,
+                    revision: rustpad.revision(),
+                    users: rustpad.num_connections(),
+                    protected: document.password.is_some(),
+                    last_accessed: last_accessed
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .ok()
+                        .map(|time| time.as_millis() as u64),
+                    id,
+                }
+            }
+            (None, None) => continue,
+        };
+        documents.push(info);
+    }
+    Ok(DocumentList { total, documents })
+}
+
+/// Handler for the `/api/documents` endpoint.
+///
+/// Knowing the ID of a document is enough to open it unless it is protected, so
+/// only authenticated users can list documents.
+async fn documents_handler(
+    query: ListQuery,
+    user: Option<User>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    let Some(user) = user else {
+        return Err(warp::reject::custom(Forbidden));
+    };
+    match list_documents(&state, &query, &user).await {
+        Ok(list) => Ok(warp::reply::json(&list)),
+        Err(e) => Err(warp::reject::custom(CustomReject(e))),
+    }
+}
+
+/// Handler for the `/api/blame/{id}` endpoint.
+async fn blame_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let rustpad = state
+        .documents
+        .get(&id)
+        .map(|value| Arc::clone(&value.rustpad));
+    let blame = match (rustpad, &state.database) {
+        (Some(rustpad), _) => rustpad.blame(),
+        (None, Some(db)) => load_document(db.as_ref(), &id)
+            .await
+            .map_err(|e| warp::reject::custom(CustomReject(e)))?
+            .0
+            .blame(),
+        (None, None) => Vec::new(),
+    };
+    Ok(warp::reply::json(&blame))
+}
+
+/// Summary of a named snapshot, returned when listing snapshots.
+#[derive(Serialize)]
+struct SnapshotInfo {
+    /// Index of the snapshot, used to fetch or restore it.
+    index: usize,
+    /// Label given to the snapshot by the user.
+    label: String,
+    /// Revision of the document when the snapshot was taken.
+    revision: usize,
+    /// Time the snapshot was created, in milliseconds since the Unix epoch.
+    time: u64,
+}
+
+/// Returns the named snapshots of a document, without loading it into memory.
+async fn document_snapshots(
+    state: &ServerState,
+    id: &str,
+) -> anyhow::Result<Vec<PersistedSnapshot>> {
+    if let Some(value) = state.documents.get(id) {
+        return Ok(value.rustpad.snapshots_since(0));
+    }
+    match &state.database {
+        Some(db) => db.load_snapshots(id).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Handler for the `/api/snapshots/{id}` endpoint.
+async fn snapshots_handler(id: String, state: ServerState) -> Result<impl Reply, Rejection> {
+    let snapshots = document_snapshots(&state, &id)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    let infos: Vec<_> = snapshots
+        .into_iter()
+        .enumerate()
+        .map(|(index, snapshot)| SnapshotInfo {
+            index,
+            label: snapshot.label,
+            revision: snapshot.revision,
+            time: snapshot.time,
+        })
+        .collect();
+    Ok(warp::reply::json(&infos))
+}
+
+/// Handler for the `/api/snapshots/{id}/{index}` endpoint.
+async fn snapshot_handler(
+    id: String,
+    index: usize,
+    password: Option<PasswordAttempt>,
+    user: Option<User>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    check_access(&state, &id, password, user.as_ref(), Role::Viewer).await?;
+    let mut snapshots = document_snapshots(&state, &id)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    if index >= snapshots.len() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    Ok(warp::reply::json(&snapshots.swap_remove(index)).into_response())
+}
+
+/// Handler for the `/api/snapshots/{id}/{index}/restore` endpoint.
+async fn restore_handler(
+    id: String,
+    index: usize,
+    password: Option<PasswordAttempt>,
+    user: Option<User>,
+    state: ServerState,
+) -> Result<impl Reply, Rejection> {
+    check_access(&state, &id, password, user.as_ref(), Role::Editor).await?;
+    // Check that the snapshot exists first, so unknown documents are not opened.
+    let snapshots = document_snapshots(&state, &id)
+        .await
+        .map_err(|e| warp::reject::custom(CustomReject(e)))?;
+    if index >= snapshots.len() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let rustpad = open_document(&state, id, None).await?;
+    match rustpad.restore_snapshot(index, user.as_ref()).transpose() {
+        Some(result) => Ok(edit_response(result)),
+        None => Ok(StatusCode::NOT_FOUND.into_response()),
+    }
+}
+
 /// Handler for the `/api/stats` endpoint.
 async fn stats_handler(start_time: u64, state: ServerState) -> Result<impl Reply, Rejection> {
     let num_documents = state.documents.len();
@@ -187,6 +1566,7 @@ async fn stats_handler(start_time: u64, state: ServerState) -> Result<impl Reply
         start_time,
         num_documents,
         database_size,
+        limits: state.limits,
     }))
 }
 
@@ -204,29 +1584,125 @@ async fn cleaner(state: ServerState, expiry_days: u32) {
         }
         info!("cleaner removing keys: {:?}", keys);
         for key in keys {
-            state.documents.remove(&key);
+            if state.documents.remove(&key).is_some() {
+                state.metrics.documents_expired.inc();
+            }
         }
     }
 }
 
+/// Shuts down the server when requested through its handle.
+///
+/// Every document is removed from memory, which disconnects its clients after
+/// telling them when to reconnect, and persisted one last time.
+async fn shutdown_task(state: ServerState, shutdown: Shutdown) {
+    let retry_after = shutdown.requested().await;
+    state.shutting_down.store(true, Ordering::Relaxed);
+    let keys: Vec<_> = state.documents.iter().map(|e| e.key().clone()).collect();
+    info!("shutting down, storing {} documents", keys.len());
+    let mut persisters = Vec::new();
+    for key in keys {
+        if let Some((_, mut document)) = state.documents.remove(&key) {
+            document.rustpad.shut_down(retry_after.as_secs());
+            persisters.extend(document.persister.take());
+        }
+    }
+    for persister in persisters {
+        if let Err(e) = persister.await {
+            error!("when persisting documents on shutdown: {}", e);
+        }
+    }
+    shutdown.finish();
+}
+
 const PERSIST_INTERVAL: Duration = Duration::from_secs(3);
 const PERSIST_INTERVAL_JITTER: Duration = Duration::from_secs(1);
 
 /// Persists changed documents after a fixed time interval.
-async fn persister(id: String, rustpad: Arc<Rustpad>, db: Database) {
-    let mut last_revision = 0;
+///
+/// Once the document is killed, no more edits are applied to it, and it is
+/// persisted one last time unless it was deleted.
+async fn persister(
+    id: String,
+    rustpad: Arc<Rustpad>,
+    db: Arc<dyn DocumentStore>,
+    metrics: Arc<Metrics>,
+    mut persisted: Persisted,
+) {
     while !rustpad.killed() {
         let interval = PERSIST_INTERVAL
             + rand::thread_rng().gen_range(Duration::ZERO..=PERSIST_INTERVAL_JITTER);
-        time::sleep(interval).await;
-        let revision = rustpad.revision();
-        if revision > last_revision {
-            info!("persisting revision {} for id = {}", revision, id);
-            if let Err(e) = db.store(&id, &rustpad.snapshot()).await {
-                error!("when persisting document {}: {}", id, e);
-            } else {
-                last_revision = revision;
-            }
+        tokio::select! {
+            _ = time::sleep(interval) => {}
+            _ = rustpad.wait_killed() => {}
+        }
+        if rustpad.killed() {
+            break;
+        }
+        persist(&id, &rustpad, &*db, &metrics, &mut persisted).await;
+    }
+    if !rustpad.deleted() {
+        persist(&id, &rustpad, &*db, &metrics, &mut persisted).await;
+    }
+}
+
+/// Stores the changes to a document since it was last persisted.
+///
+/// New operations are appended to the stored history first, followed by a
+/// snapshot of the latest text. Named snapshots and the access control list
+/// are stored separately.
+async fn persist(
+    id: &str,
+    rustpad: &Rustpad,
+    db: &dyn DocumentStore,
+    metrics: &Metrics,
+    persisted: &mut Persisted,
+) {
+    if rustpad.revision() > persisted.revision || rustpad.password() != persisted.password {
+        // Take the snapshot first, so the history is never behind it.
+        let snapshot = rustpad.snapshot();
+        let operations = rustpad.persisted_operations(persisted.revision);
+        let revision = persisted.revision + operations.len();
+        info!("persisting revision {} for id = {}", revision, id);
+        let result = match db
+            .store_operations(id, persisted.revision, &operations)
+            .await
+        {
+            Ok(()) => db.store(id, &snapshot).await,
+            Err(e) => Err(e),
+        };
+        metrics.persisted(result.is_ok());
+        if let Err(e) = result {
+            error!("when persisting document {}: {}", id, e);
+        } else {
+            persisted.revision = revision;
+            persisted.password = snapshot.password;
+            rustpad.set_persisted_revision(revision);
+        }
+    }
+    let snapshots = rustpad.snapshots_since(persisted.snapshots);
+    if !snapshots.is_empty() {
+        info!("persisting {} snapshots for id = {}", snapshots.len(), id);
+        if let Err(e) = db
+            .store_snapshots(id, persisted.snapshots, &snapshots)
+            .await
+        {
+            error!("when persisting snapshots of document {}: {}", id, e);
+            metrics.persisted(false);
+        } else {
+            metrics.persisted(true);
+            persisted.snapshots += snapshots.len();
+        }
+    }
+    let acl = rustpad.acl();
+    if acl != persisted.acl {
+        info!("persisting access control list for id = {}", id);
+        if let Err(e) = db.store_acl(id, &acl).await {
+            error!("when persisting access control list of {}: {}", id, e);
+            metrics.persisted(false);
+        } else {
+            metrics.persisted(true);
+            persisted.acl = acl;
         }
     }
 }
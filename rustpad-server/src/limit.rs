@@ -0,0 +1,224 @@
+//! Rate limits on edits from clients, to protect documents from abuse.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+/// Limits on how quickly clients can edit documents.
+///
+/// Each limit on edits applies to every connection on its own, and separately
+/// to all connections from the same IP address together. Connections without
+/// a known address count as the same address. Limits that are `None` are not
+/// enforced.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimits {
+    /// Maximum number of edits per second from one connection.
+    pub edits_per_second: Option<u32>,
+    /// Maximum number of bytes inserted per minute by one connection.
+    pub inserted_bytes_per_minute: Option<u64>,
+    /// Maximum number of edits per second from one IP address.
+    pub ip_edits_per_second: Option<u32>,
+    /// Maximum number of bytes inserted per minute from one IP address.
+    pub ip_inserted_bytes_per_minute: Option<u64>,
+    /// Maximum number of sockets connected to one document from one IP address.
+    pub ip_sockets_per_document: Option<usize>,
+    /// Maximum number of document passwords checked per minute from one IP
+    /// address.
+    pub ip_password_attempts_per_minute: Option<u32>,
+}
+
+/// Enforces rate limits across all connections to the server.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    /// Budgets of addresses with open connections or budgets that have not
+    /// refilled yet, and how many connections they have.
+    addresses: Mutex<HashMap<Option<IpAddr>, (usize, Budget)>>,
+    /// Password attempts of addresses that have not refilled their budget.
+    passwords: Mutex<HashMap<Option<IpAddr>, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Construct a new rate limiter.
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            addresses: Default::default(),
+            passwords: Default::default(),
+        }
+    }
+
+    /// Returns the limits being enforced.
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Start limiting a new connection from an address.
+    ///
+    /// The budget of the address is kept after its connections close, until it
+    /// refills, so reconnecting does not reset it.
+    pub fn connect(self: &Arc<Self>, addr: Option<IpAddr>) -> ConnectionLimiter {
+        let limits = &self.limits;
+        let mut addresses = self.addresses.lock();
+        addresses.retain(|_, (count, budget)| *count > 0 || !budget.is_full());
+        addresses
+            .entry(addr)
+            .or_insert_with(|| {
+                let budget = Budget::new(
+                    limits.ip_edits_per_second,
+                    limits.ip_inserted_bytes_per_minute,
+                );
+                (0, budget)
+            })
+            .0 += 1;
+        ConnectionLimiter {
+            limiter: Arc::clone(self),
+            addr,
+            budget: Budget::new(limits.edits_per_second, limits.inserted_bytes_per_minute),
+        }
+    }
+
+    /// Records an attempt to check a document password from an address, or
+    /// returns a message if it has made too many.
+    pub fn check_password(&self, addr: Option<IpAddr>) -> Result<(), String> {
+        let Some(limit) = self.limits.ip_password_attempts_per_minute else {
+            return Ok(());
+        };
+        let mut passwords = self.passwords.lock();
+        // Full buckets are no different from new ones, so they can be dropped.
+        passwords.retain(|_, bucket| bucket.available() < bucket.capacity);
+        let bucket = passwords
+            .entry(addr)
+            .or_insert_with(|| Bucket::new(limit as f64, Duration::from_secs(60)));
+        if bucket.available() < 1.0 {
+            return Err("too many password attempts from this address".into());
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Rate limits of a single connection, which also count towards its address.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    limiter: Arc<RateLimiter>,
+    addr: Option<IpAddr>,
+    budget: Budget,
+}
+
+impl ConnectionLimiter {
+    /// Records an edit that inserts some number of bytes, or returns a message
+    /// describing the limit it would exceed.
+    pub fn check_edit(&mut self, inserted: u64) -> Result<(), String> {
+        let mut addresses = self.limiter.addresses.lock();
+        let (_, address) = addresses
+            .get_mut(&self.addr)
+            .expect("address of a connection should have a budget");
+        if let Err(exceeded) = self.budget.check(inserted) {
+            return Err(format!("too many {} from this connection", exceeded));
+        }
+        if let Err(exceeded) = address.check(inserted) {
+            return Err(format!("too many {} from this address", exceeded));
+        }
+        self.budget.take(inserted);
+        address.take(inserted);
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionLimiter {
+    fn drop(&mut self) {
+        let mut addresses = self.limiter.addresses.lock();
+        if let Some((count, budget)) = addresses.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 && budget.is_full() {
+                addresses.remove(&self.addr);
+            }
+        }
+    }
+}
+
+/// Token buckets for the edits and inserted bytes of one client.
+#[derive(Debug)]
+struct Budget {
+    edits: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+impl Budget {
+    fn new(edits_per_second: Option<u32>, bytes_per_minute: Option<u64>) -> Self {
+        Self {
+            edits: edits_per_second.map(|n| Bucket::new(n as f64, Duration::from_secs(1))),
+            bytes: bytes_per_minute.map(|n| Bucket::new(n as f64, Duration::from_secs(60))),
+        }
+    }
+
+    /// Checks that there is room for an edit, returning what would run out.
+    fn check(&mut self, inserted: u64) -> Result<(), &'static str> {
+        if let Some(edits) = &mut self.edits {
+            if edits.available() < 1.0 {
+                return Err("edits");
+            }
+        }
+        if let Some(bytes) = &mut self.bytes {
+            if bytes.available() < inserted as f64 {
+                return Err("inserted bytes");
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether every bucket has refilled, so the budget is no
+    /// different from a new one.
+    fn is_full(&mut self) -> bool {
+        [&mut self.edits, &mut self.bytes]
+            .into_iter()
+            .flatten()
+            .all(|bucket| bucket.available() >= bucket.capacity)
+    }
+
+    /// Uses up the budget for an edit.
+    fn take(&mut self, inserted: u64) {
+        if let Some(edits) = &mut self.edits {
+            edits.tokens -= 1.0;
+        }
+        if let Some(bytes) = &mut self.bytes {
+            bytes.tokens -= inserted as f64;
+        }
+    }
+}
+
+/// A token bucket, which refills continuously up to its capacity.
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    period: Duration,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Construct a full bucket that refills its capacity once per period.
+    fn new(capacity: f64, period: Duration) -> Self {
+        Self {
+            capacity,
+            period,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Refills the bucket, returning the number of tokens available.
+    fn available(&mut self) -> f64 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + self.capacity * elapsed / self.period.as_secs_f64()).min(self.capacity);
+        self.updated = now;
+        self.tokens
+    }
+}
@@ -1,7 +1,7 @@
-use rustpad_server::{server, database::Database, ServerConfig};
+use rustpad_server::{server, auth::{Authenticator, Jwt, StaticTokens, User}, cluster::Cluster, database::Database, limit::RateLimits, shutdown::Shutdown, store::{DocumentStore, FileStore}, ServerConfig};
 use log::{info, warn, error, debug};
-use std::{io::Write, time::Duration};
-use tokio::time;
+use std::{io::Write, sync::Arc, time::Duration};
+use tokio::{sync::oneshot, time};
 
 // Setup self-ping mechanism to prevent Render from spinning down
 async fn setup_self_ping(interval_seconds: u64) {
@@ -70,6 +70,96 @@ fn determine_ping_url() -> String {
     }
 }
 
+// Select a storage backend for persistence, if one is configured
+async fn setup_storage() -> Option<Arc<dyn DocumentStore>> {
+    // A plain directory of files takes precedence when specified
+    if let Ok(dir) = std::env::var("STORAGE_DIR") {
+        return match FileStore::new(&dir).await {
+            Ok(store) => {
+                info!("Storing documents in directory {}", dir);
+                Some(Arc::new(store))
+            },
+            Err(e) => {
+                error!("Failed to open storage directory: {}", e);
+                None
+            }
+        };
+    }
+
+    // Otherwise the database backend is picked from the URI scheme, preferring PostgreSQL
+    match std::env::var("POSTGRES_URI").or_else(|_| std::env::var("SQLITE_URI")) {
+        Ok(uri) => {
+            match Database::new(&uri).await {
+                Ok(db) => {
+                    info!("Database connection successful");
+                    Some(Arc::new(db))
+                },
+                Err(e) => {
+                    error!("Database connection failed: {}", e);
+                    None
+                }
+            }
+        },
+        Err(_) => {
+            warn!("No STORAGE_DIR, POSTGRES_URI or SQLITE_URI found. Running without persistence.");
+            None
+        },
+    }
+}
+
+// Select how API requests are authenticated, if at all
+fn setup_auth() -> Option<Arc<dyn Authenticator>> {
+    // Signed JSON Web Tokens take precedence over a fixed list of tokens
+    if let Ok(secret) = std::env::var("JWT_SECRET") {
+        info!("Authenticating requests with JSON Web Tokens");
+        return Some(Arc::new(Jwt::new(secret.as_bytes())));
+    }
+
+    // Static tokens are given as comma-separated `token=name` pairs
+    let tokens = std::env::var("AUTH_TOKENS").ok()?;
+    let tokens: Vec<_> = tokens
+        .split(',')
+        .map(|pair| {
+            let (token, name) = pair.split_once('=').expect("Unable to parse AUTH_TOKENS");
+            (token.trim().to_string(), User { name: name.trim().to_string(), hue: None })
+        })
+        .collect();
+    info!("Authenticating requests with {} static tokens", tokens.len());
+    Some(Arc::new(StaticTokens::new(tokens)))
+}
+
+// Join a cluster of servers that share documents, if configured
+fn setup_cluster() -> Option<Cluster> {
+    // Nodes are given as comma-separated base URLs, which must include this node
+    let nodes = std::env::var("CLUSTER_NODES").ok()?;
+    let nodes: Vec<String> = nodes.split(',').map(|node| node.trim().to_string()).collect();
+    let node = std::env::var("CLUSTER_NODE").expect("CLUSTER_NODE is required with CLUSTER_NODES");
+    let secret = std::env::var("CLUSTER_SECRET").expect("CLUSTER_SECRET is required with CLUSTER_NODES");
+    info!("Running as node {} of a cluster of {} nodes", node, nodes.len());
+    Some(Cluster::new(&node, &nodes, &secret))
+}
+
+// Read an optional limit from an environment variable
+fn parse_limit<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    Some(value.parse().unwrap_or_else(|_| panic!("Unable to parse {}", name)))
+}
+
+// Wait for a signal asking the server to stop
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+
 #[tokio::main]
 async fn main() {
     // Set up environment variables
@@ -114,31 +204,55 @@ async fn main() {
         }
     }
 
+    // Graceful shutdown on SIGTERM or SIGINT
+    let shutdown = Shutdown::default();
+    let shutdown_timeout: u64 = parse_limit("SHUTDOWN_TIMEOUT").unwrap_or(10);
+    let retry_after: u64 = parse_limit("SHUTDOWN_RETRY_AFTER").unwrap_or(5);
+
+    let defaults = ServerConfig::default();
     let config = ServerConfig {
         expiry_days: std::env::var("EXPIRY_DAYS")
             .unwrap_or_else(|_| String::from("1"))
             .parse()
             .expect("Unable to parse EXPIRY_DAYS"),
-        database: match std::env::var("POSTGRES_URI") {
-            Ok(uri) => {
-                match Database::new(&uri).await {
-                    Ok(db) => {
-                        info!("PostgreSQL connection successful");
-                        Some(db)
-                    },
-                    Err(e) => {
-                        error!("PostgreSQL connection failed: {}", e);
-                        None
-                    }
-                }
-            },
-            Err(_) => {
-                warn!("No POSTGRES_URI found. Running without persistence.");
-                None
-            },
+        database: setup_storage().await,
+        max_history: parse_limit("MAX_HISTORY").unwrap_or(defaults.max_history),
+        max_document_size: parse_limit("MAX_DOCUMENT_SIZE").unwrap_or(defaults.max_document_size),
+        max_users: parse_limit("MAX_USERS"),
+        share_secret: std::env::var("SHARE_SECRET").ok(),
+        auth: setup_auth(),
+        principal_header: std::env::var("PRINCIPAL_HEADER").ok(),
+        rate_limits: RateLimits {
+            edits_per_second: parse_limit("EDITS_PER_SECOND"),
+            inserted_bytes_per_minute: parse_limit("INSERTED_BYTES_PER_MINUTE"),
+            ip_edits_per_second: parse_limit("IP_EDITS_PER_SECOND"),
+            ip_inserted_bytes_per_minute: parse_limit("IP_INSERTED_BYTES_PER_MINUTE"),
+            ip_sockets_per_document: parse_limit("IP_SOCKETS_PER_DOCUMENT"),
+            ip_password_attempts_per_minute: parse_limit("IP_PASSWORD_ATTEMPTS_PER_MINUTE"),
         },
+        cluster: setup_cluster(),
+        shutdown: Some(shutdown.clone()),
     };
 
+    // Stop accepting connections once a signal arrives
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let (_, serving) = warp::serve(server(config))
+        .bind_with_graceful_shutdown(([0, 0, 0, 0], port), async {
+            stop_rx.await.ok();
+        });
+    let serving = tokio::spawn(serving);
+
     info!("Server ready");
-    warp::serve(server(config)).run(([0, 0, 0, 0], port)).await;
+    wait_for_signal().await;
+
+    // Tell clients when to reconnect and store every document, within the deadline
+    info!("Shutting down, waiting up to {} seconds", shutdown_timeout);
+    stop_tx.send(()).ok();
+    let finished = time::timeout(Duration::from_secs(shutdown_timeout), async {
+        shutdown.shut_down(Duration::from_secs(retry_after)).await;
+        serving.await.ok();
+    });
+    if finished.await.is_err() {
+        warn!("Shutdown did not finish within {} seconds", shutdown_timeout);
+    }
 }
@@ -0,0 +1,255 @@
+//! Metrics about documents, connections and storage, exported to Prometheus.
+
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use prometheus::{
+    core::Collector, exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+use crate::database::{PersistedAcl, PersistedDocument, PersistedOperation, PersistedSnapshot};
+use crate::store::{DocumentPage, DocumentQuery, DocumentStore};
+
+/// Metrics of a server, shared by all of its documents.
+///
+/// Counters only ever increase, so rates such as edits per second are left to
+/// the queries that read them.
+pub struct Metrics {
+    registry: Registry,
+    /// Number of documents in memory.
+    pub documents: IntGauge,
+    /// Number of documents removed from memory after a period of inactivity.
+    pub documents_expired: IntCounter,
+    /// Number of open WebSocket connections.
+    pub sockets: IntGauge,
+    /// Number of edits applied to documents.
+    pub edits: IntCounter,
+    /// Time taken to apply an edit, including transforming it.
+    pub apply_edit_seconds: Histogram,
+    /// Number of times an edit was transformed against a concurrent one.
+    pub transforms: IntCounter,
+    /// Number of rejected edits, by reason.
+    pub rejected_edits: IntCounterVec,
+    /// Number of times a connection fell behind on metadata updates.
+    pub broadcast_lagged: IntCounter,
+    /// Number of attempts to persist a document, by result.
+    pub persists: IntCounterVec,
+    /// Time taken by storage operations, by operation.
+    pub database_seconds: HistogramVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::new();
+        let edit_buckets = exponential_buckets(1e-5, 4.0, 9).expect("valid buckets");
+        Self {
+            documents: register(
+                &registry,
+                IntGauge::new("rustpad_documents", "Documents in memory"),
+            ),
+            documents_expired: register(
+                &registry,
+                IntCounter::new(
+                    "rustpad_documents_expired_total",
+                    "Documents removed from memory after inactivity",
+                ),
+            ),
+            sockets: register(
+                &registry,
+                IntGauge::new("rustpad_sockets", "Open WebSocket connections"),
+            ),
+            edits: register(
+                &registry,
+                IntCounter::new("rustpad_edits_total", "Edits applied to documents"),
+            ),
+            apply_edit_seconds: register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new("rustpad_apply_edit_seconds", "Time taken to apply an edit")
+                        .buckets(edit_buckets),
+                ),
+            ),
+            transforms: register(
+                &registry,
+                IntCounter::new(
+                    "rustpad_transforms_total",
+                    "Edits transformed against concurrent edits",
+                ),
+            ),
+            rejected_edits: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("rustpad_rejected_edits_total", "Rejected edits"),
+                    &["reason"],
+                ),
+            ),
+            broadcast_lagged: register(
+                &registry,
+                IntCounter::new(
+                    "rustpad_broadcast_lagged_total",
+                    "Connections that fell behind on metadata updates",
+                ),
+            ),
+            persists: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("rustpad_persists_total", "Attempts to persist a document"),
+                    &["result"],
+                ),
+            ),
+            database_seconds: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "rustpad_database_seconds",
+                        "Time taken by storage operations",
+                    ),
+                    &["operation"],
+                ),
+            ),
+            registry,
+        }
+    }
+}
+
+/// Registers a newly created metric, returning it.
+fn register<M: Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: prometheus::Result<M>,
+) -> M {
+    let metric = metric.expect("metric should be valid");
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric should be unique");
+    metric
+}
+
+impl Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    /// Counts an edit that was rejected for a reason.
+    pub fn reject_edit(&self, reason: &str) {
+        self.rejected_edits.with_label_values(&[reason]).inc();
+    }
+
+    /// Counts an attempt to persist a document.
+    pub fn persisted(&self, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        self.persists.with_label_values(&[result]).inc();
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("failed to encode metrics");
+        String::from_utf8(buffer).expect("metrics should be valid UTF-8")
+    }
+
+    /// Runs a storage operation, recording how long it takes.
+    async fn time<T>(&self, operation: &str, future: impl Future<Output = T>) -> T {
+        let _timer = self
+            .database_seconds
+            .with_label_values(&[operation])
+            .start_timer();
+        future.await
+    }
+}
+
+/// A storage backend that records the latency of every operation.
+#[derive(Debug)]
+pub struct TimedStore {
+    inner: Arc<dyn DocumentStore>,
+    metrics: Arc<Metrics>,
+}
+
+impl TimedStore {
+    /// Wraps a storage backend, recording into the given metrics.
+    pub fn new(inner: Arc<dyn DocumentStore>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+#[async_trait]
+impl DocumentStore for TimedStore {
+    async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
+        let future = self.inner.load(document_id);
+        self.metrics.time("load", future).await
+    }
+
+    async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        let future = self.inner.store(document_id, document);
+        self.metrics.time("store", future).await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        self.metrics.time("count", self.inner.count()).await
+    }
+
+    async fn load_operations(&self, document_id: &str) -> Result<Vec<PersistedOperation>> {
+        let future = self.inner.load_operations(document_id);
+        self.metrics.time("load_operations", future).await
+    }
+
+    async fn count_operations(&self, document_id: &str) -> Result<usize> {
+        let future = self.inner.count_operations(document_id);
+        self.metrics.time("count_operations", future).await
+    }
+
+    async fn store_operations(
+        &self,
+        document_id: &str,
+        start: usize,
+        operations: &[PersistedOperation],
+    ) -> Result<()> {
+        let future = self.inner.store_operations(document_id, start, operations);
+        self.metrics.time("store_operations", future).await
+    }
+
+    async fn load_snapshots(&self, document_id: &str) -> Result<Vec<PersistedSnapshot>> {
+        let future = self.inner.load_snapshots(document_id);
+        self.metrics.time("load_snapshots", future).await
+    }
+
+    async fn store_snapshots(
+        &self,
+        document_id: &str,
+        start: usize,
+        snapshots: &[PersistedSnapshot],
+    ) -> Result<()> {
+        let future = self.inner.store_snapshots(document_id, start, snapshots);
+        self.metrics.time("store_snapshots", future).await
+    }
+
+    async fn load_acl(&self, document_id: &str) -> Result<PersistedAcl> {
+        let future = self.inner.load_acl(document_id);
+        self.metrics.time("load_acl", future).await
+    }
+
+    async fn store_acl(&self, document_id: &str, acl: &PersistedAcl) -> Result<()> {
+        let future = self.inner.store_acl(document_id, acl);
+        self.metrics.time("store_acl", future).await
+    }
+
+    async fn delete(&self, document_id: &str) -> Result<bool> {
+        let future = self.inner.delete(document_id);
+        self.metrics.time("delete", future).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.metrics.time("list", self.inner.list()).await
+    }
+
+    async fn query(&self, query: &DocumentQuery) -> Result<DocumentPage> {
+        self.metrics.time("query", self.inner.query(query)).await
+    }
+}
@@ -21,3 +21,25 @@ pub fn transform_index(operation: &OperationSeq, position: u32) -> u32 {
     }
     new_index as u32
 }
+
+/// Return an operation that changes one string into another.
+///
+/// Only the common prefix and suffix are retained, which is enough to keep
+/// small edits small without computing a full diff.
+pub fn diff(old: &str, new: &str) -> OperationSeq {
+    let old: Vec<char> = old.chars().collect();
+    let new: Vec<char> = new.chars().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut operation = OperationSeq::default();
+    operation.retain(prefix as u64);
+    operation.delete((old.len() - prefix - suffix) as u64);
+    operation.insert(&new[prefix..new.len() - suffix].iter().collect::<String>());
+    operation.retain(suffix as u64);
+    operation
+}
@@ -0,0 +1,104 @@
+//! Hashing and verification of document passwords.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use rand::RngCore;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use tokio::time::Instant;
+
+/// Number of PBKDF2 iterations used for new password hashes.
+const ITERATIONS: u32 = 100_000;
+
+/// How long a verified password is remembered.
+const VERIFIED_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Hash a password with a random salt, for storage.
+///
+/// The result has the form `pbkdf2-sha256${iterations}${salt}${hash}`, with the
+/// salt and hash in hex, so the parameters can change without breaking hashes
+/// that were already stored.
+pub fn hash(password: &str) -> String {
+    let mut salt = [0; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let hash = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), &salt, ITERATIONS);
+    format!(
+        "pbkdf2-sha256${}${}${}",
+        ITERATIONS,
+        hex::encode(salt),
+        hex::encode(hash)
+    )
+}
+
+/// Check a password against a hash produced by [`hash`].
+pub fn verify(password: &str, hash: &str) -> bool {
+    let mut parts = hash.split('$');
+    let (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(expected), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return false;
+    };
+    let (Ok(iterations), Ok(salt), Ok(expected)) =
+        (iterations.parse(), hex::decode(salt), hex::decode(expected))
+    else {
+        return false;
+    };
+    let actual = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), &salt, iterations);
+    actual.ct_eq(&expected[..]).into()
+}
+
+/// Passwords that were recently verified against a hash, so that clients
+/// sending the password with every request only pay for hashing it once.
+///
+/// Entries are keyed by a MAC of the stored hash and the password under a
+/// random key, so they change along with the password of a document and are
+/// useless outside of this process.
+pub struct VerifiedPasswords {
+    mac: Hmac<Sha256>,
+    entries: Mutex<HashMap<[u8; 32], Instant>>,
+}
+
+impl Default for VerifiedPasswords {
+    fn default() -> Self {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self {
+            mac: Hmac::new_from_slice(&key).expect("HMAC accepts keys of any length"),
+            entries: Default::default(),
+        }
+    }
+}
+
+impl VerifiedPasswords {
+    /// Returns whether a password was recently verified against a hash.
+    pub fn contains(&self, password: &str, hash: &str) -> bool {
+        let key = self.key(password, hash);
+        let entries = self.entries.lock();
+        entries
+            .get(&key)
+            .is_some_and(|verified| verified.elapsed() < VERIFIED_TTL)
+    }
+
+    /// Remembers that a password matches a hash, forgetting expired entries.
+    pub fn insert(&self, password: &str, hash: &str) {
+        let key = self.key(password, hash);
+        let mut entries = self.entries.lock();
+        entries.retain(|_, verified| verified.elapsed() < VERIFIED_TTL);
+        entries.insert(key, Instant::now());
+    }
+
+    fn key(&self, password: &str, hash: &str) -> [u8; 32] {
+        let mut mac = self.mac.clone();
+        mac.update(hash.as_bytes());
+        mac.update(&[0]);
+        mac.update(password.as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+}
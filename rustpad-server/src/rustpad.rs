@@ -1,18 +1,38 @@
 //! Eventually consistent server-side logic for Rustpad.
 
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Result};
 use futures::prelude::*;
 use log::{info, warn};
-use operational_transform::OperationSeq;
-use parking_lot::{RwLock, RwLockUpgradableReadGuard};
+use operational_transform::{OTError, Operation, OperationSeq};
+use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
+use schemars::{gen::SchemaGenerator, JsonSchema};
 use serde::{Deserialize, Serialize};
-use tokio::sync::{broadcast, Notify};
+use serde_json::{json, Value};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    Notify,
+};
 use warp::ws::{Message, WebSocket};
 
-use crate::{database::PersistedDocument, ot::transform_index};
+use crate::{
+    auth::User,
+    blame::Attribution,
+    database::{
+        PersistedAcl, PersistedAuthor, PersistedDocument, PersistedOperation, PersistedSnapshot,
+        Role,
+    },
+    encoding::Encoding,
+    limit::{ConnectionLimiter, RateLimiter},
+    metrics::Metrics,
+    ot::{diff, transform_index},
+};
 
 /// The main object representing a collaborative session.
 pub struct Rustpad {
@@ -20,49 +40,199 @@ pub struct Rustpad {
     state: RwLock<State>,
     /// Incremented to obtain unique user IDs.
     count: AtomicU64,
+    /// Number of currently open connections.
+    connections: AtomicUsize,
     /// Used to notify clients of new text operations.
     notify: Notify,
     /// Used to inform all clients of metadata updates.
     update: broadcast::Sender<ServerMsg>,
     /// Set to true when the document is destroyed.
     killed: AtomicBool,
+    /// Set to true when the document is destroyed because it was deleted.
+    deleted: AtomicBool,
+    /// Seconds before clients should reconnect, when the document is destroyed
+    /// because the server is shutting down.
+    retry_after: Mutex<Option<u64>>,
+    /// Random ID of this copy of the document, which changes whenever it is
+    /// created or loaded. Revisions are only comparable within an epoch, since
+    /// edits after the last persist are lost when the server restarts, and a
+    /// deleted document starts over from revision 0.
+    epoch: String,
 }
 
 /// Shared state involving multiple users, protected by a lock.
 #[derive(Default)]
 struct State {
+    /// Revision of the checkpoint, which `operations` follow.
+    checkpoint_revision: usize,
+    /// Text of the document at the checkpoint revision.
+    checkpoint_text: String,
+    /// Time of the last operation folded into the checkpoint.
+    checkpoint_time: u64,
+    /// Operations in the history after the checkpoint.
     operations: Vec<UserOperation>,
+    /// Limits on the size of the document and its number of users.
+    limits: DocumentLimits,
+    /// Revision up to which the history has been persisted, if enabled.
+    persisted_revision: Option<usize>,
     text: String,
     language: Option<String>,
+    /// Hash of the password required to access the document, if any.
+    password: Option<String>,
+    /// Owner of the document and roles granted to other principals.
+    acl: PersistedAcl,
     users: HashMap<u64, UserInfo>,
     cursors: HashMap<u64, CursorData>,
+    /// Number of open sockets from each address, or from unknown addresses.
+    sockets: HashMap<Option<IpAddr>, usize>,
+    snapshots: Vec<PersistedSnapshot>,
+    /// Which user inserted each part of the current text.
+    attribution: Attribution,
+    /// Latest information of every user whose edit was applied, kept after
+    /// they leave.
+    authors: HashMap<u64, UserInfo>,
+    /// Latest edit from each client session whose edits are still in history.
+    sessions: HashMap<String, Session>,
+    /// Metrics of the server, shared with its other documents, if set.
+    metrics: Option<Arc<Metrics>>,
+}
+
+/// The latest edit applied from a client session, to recognize resubmissions.
+#[derive(Clone, Copy, Debug)]
+struct Session {
+    /// Sequence number of the edit.
+    seq: u64,
+    /// Revision after the edit was applied.
+    revision: usize,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 struct UserOperation {
     id: u64,
+    #[schemars(with = "Vec<Component>")]
     operation: OperationSeq,
+    /// Time the edit was applied, in milliseconds since the Unix epoch.
+    time: u64,
+    /// Client session that sent the edit and its sequence number, which are
+    /// persisted but never sent to other clients.
+    #[serde(skip)]
+    #[schemars(skip)]
+    session: Option<(String, u64)>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 struct UserInfo {
     name: String,
     hue: u32,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl From<UserInfo> for PersistedAuthor {
+    fn from(info: UserInfo) -> Self {
+        Self {
+            name: info.name,
+            hue: info.hue,
+        }
+    }
+}
+
+impl From<&User> for UserInfo {
+    /// Information of an authenticated user who edits without a socket, with
+    /// a hue derived from their name if their token does not assign one.
+    fn from(user: &User) -> Self {
+        let hue = user.hue.unwrap_or_else(|| {
+            let hash = user
+                .name
+                .bytes()
+                .fold(0u32, |h, b| h.wrapping_mul(31) ^ b as u32);
+            hash % 360
+        });
+        Self {
+            name: user.name.clone(),
+            hue,
+        }
+    }
+}
+
+/// Converts persisted authors into the information shown when blaming.
+fn into_authors(authors: BTreeMap<u64, PersistedAuthor>) -> HashMap<u64, UserInfo> {
+    authors
+        .into_iter()
+        .map(|(id, author)| {
+            let info = UserInfo {
+                name: author.name,
+                hue: author.hue,
+            };
+            (id, info)
+        })
+        .collect()
+}
+
+/// A range of the current text, attributed to the user who inserted it.
+#[derive(Clone, Debug, Serialize)]
+pub struct BlameRange {
+    /// Start of the range, in Unicode code points.
+    start: u64,
+    /// End of the range, in Unicode code points.
+    end: u64,
+    /// ID of the author, or `None` if the origin of the text is unknown.
+    id: Option<u64>,
+    /// Information about the author, if they shared it.
+    info: Option<UserInfo>,
+}
+
+/// What a connection is allowed to do, and who is behind it.
+#[derive(Clone, Debug, Default)]
+pub struct Access {
+    /// Whether the client can only view the document, but not change it.
+    pub readonly: bool,
+    /// Whether the client can share its cursor with others.
+    pub cursors: bool,
+    /// The authenticated user, whose name overrides what the client sends.
+    pub user: Option<User>,
+    /// IP address of the client, if known.
+    pub addr: Option<IpAddr>,
+    /// Rate limits shared by all connections to the server.
+    pub limiter: Arc<RateLimiter>,
+    /// Version of the protocol that the client speaks.
+    pub version: u32,
+    /// How messages are encoded on the connection.
+    pub encoding: Encoding,
+    /// Revision the client already has, when it is reconnecting.
+    pub revision: Option<usize>,
+    /// Epoch of the document that the revision belongs to.
+    pub epoch: Option<String>,
+}
+
+/// Identity of a connection, sent as just the ID to clients that can edit.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum Identity {
+    Editor(u64),
+    ReadOnly { id: u64, readonly: bool },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 struct CursorData {
     cursors: Vec<u32>,
     selections: Vec<(u32, u32)>,
 }
 
 /// A message received from the client over WebSocket.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 enum ClientMsg {
     /// Represents a sequence of local edits from the user.
     Edit {
         revision: usize,
+        #[schemars(with = "Vec<Component>")]
         operation: OperationSeq,
+        /// Sequence number chosen by the client, echoed back in the `Ack`.
+        #[serde(default)]
+        seq: Option<u64>,
+        /// Token chosen by the client to identify itself across reconnects.
+        /// Edits with a sequence number that was already applied from the
+        /// same session are acknowledged again instead of being reapplied.
+        #[serde(default)]
+        session: Option<String>,
     },
     /// Sets the language of the editor.
     SetLanguage(String),
@@ -70,30 +240,365 @@ enum ClientMsg {
     ClientInfo(UserInfo),
     /// Sets the user's cursor and selection positions.
     CursorData(CursorData),
+    /// Saves the current text as a named snapshot.
+    CreateSnapshot { label: String },
+}
+
+impl ClientMsg {
+    /// Parses a message, returning `None` for frames without messages.
+    fn parse(message: &Message, encoding: Encoding) -> Result<Option<Self>, ClientError> {
+        encoding.decode(message).map_err(|e| {
+            let message = format!("failed to deserialize message: {}", e);
+            ClientError::new(ErrorCode::MalformedMessage, message)
+        })
+    }
+
+    /// Returns the number of bytes inserted by this message.
+    fn inserted_bytes(&self) -> u64 {
+        let Self::Edit { operation, .. } = self else {
+            return 0;
+        };
+        let inserted = operation.ops().iter().map(|op| match op {
+            Operation::Insert(s) => s.len() as u64,
+            _ => 0,
+        });
+        inserted.sum()
+    }
+
+    /// Returns whether this message changes the document.
+    fn is_write(&self) -> bool {
+        match self {
+            Self::Edit { .. } | Self::SetLanguage(_) | Self::CreateSnapshot { .. } => true,
+            Self::ClientInfo(_) | Self::CursorData(_) => false,
+        }
+    }
 }
 
 /// A message sent to the client over WebSocket.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 enum ServerMsg {
     /// Informs the client of their unique socket ID.
-    Identity(u64),
+    Identity(Identity),
+    /// Informs the client of the epoch of the document, which it passes back
+    /// along with its revision when reconnecting. Since protocol version 5.
+    Epoch(String),
+    /// Sends the text at a revision, when older history has been compacted.
+    /// Since protocol version 2.
+    Checkpoint { revision: usize, text: String },
+    /// Sends the latest text to a joining client, in place of the history
+    /// before its revision. Since protocol version 3.
+    Snapshot { revision: usize, text: String },
+    /// Tells the client that its revision is too old to continue from. Since
+    /// protocol version 2.
+    Resync,
     /// Broadcasts text operations to all clients.
     History {
         start: usize,
         operations: Vec<UserOperation>,
     },
+    /// Tells the client that its edit was applied as the operation before
+    /// `revision`, once it has been sent the history up to that point. Since
+    /// protocol version 2.
+    Ack { revision: usize, seq: Option<u64> },
     /// Broadcasts the current language, last writer wins.
     Language(String),
     /// Broadcasts a user's information, or `None` on disconnect.
     UserInfo { id: u64, info: Option<UserInfo> },
     /// Broadcasts a user's cursor position.
     UserCursor { id: u64, data: CursorData },
+    /// Tells the client that the document was deleted, before disconnecting.
+    /// Since protocol version 2.
+    Deleted,
+    /// Tells the client that the server is shutting down, before disconnecting,
+    /// and how many seconds to wait before reconnecting. Since protocol
+    /// version 4.
+    ServerShutdown { retry_after: u64 },
+    /// Informs the client of the limits of the document, after its identity.
+    /// Since protocol version 2.
+    Limits(DocumentLimits),
+    /// Reports an error caused by the client, which closes the connection if
+    /// it is fatal. Since protocol version 2.
+    Error {
+        code: ErrorCode,
+        message: String,
+        fatal: bool,
+    },
+}
+
+/// Stable, machine-readable reason for an error sent to the client.
+///
+/// Errors are recoverable when the message that caused them can be ignored,
+/// and the connection stays open. Rejected edits are fatal, since the client
+/// would otherwise wait on them forever, so it needs to reconnect and resync.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+enum ErrorCode {
+    /// A message could not be parsed. This is recoverable.
+    MalformedMessage,
+    /// The connection is not allowed to send a message. This is recoverable.
+    Forbidden,
+    /// An edit was based on a revision the server does not have yet.
+    InvalidRevision,
+    /// An edit does not apply to the text at its revision.
+    InvalidOperation,
+    /// An edit would make the document larger than allowed.
+    TooLarge,
+    /// Too many users are connected to the document.
+    DocumentFull,
+    /// The client sent edits faster than allowed.
+    RateLimited,
+    /// Too many sockets are connected to the document from the same address.
+    TooManyConnections,
+    /// The document already has as many snapshots as allowed. This is
+    /// recoverable.
+    TooManySnapshots,
+}
+
+impl ErrorCode {
+    /// Returns the reason for the error as a metric label.
+    fn label(self) -> &'static str {
+        match self {
+            Self::MalformedMessage => "malformed_message",
+            Self::Forbidden => "forbidden",
+            Self::InvalidRevision => "invalid_revision",
+            Self::InvalidOperation => "invalid_operation",
+            Self::TooLarge => "too_large",
+            Self::DocumentFull => "document_full",
+            Self::RateLimited => "rate_limited",
+            Self::TooManyConnections => "too_many_connections",
+            Self::TooManySnapshots => "too_many_snapshots",
+        }
+    }
+
+    /// Returns whether the connection is closed after this error.
+    fn is_fatal(self) -> bool {
+        !matches!(
+            self,
+            Self::MalformedMessage | Self::Forbidden | Self::TooManySnapshots
+        )
+    }
+}
+
+/// An error caused by a message from a client, which is reported back to it.
+#[derive(Debug)]
+struct ClientError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl ClientError {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<&ClientError> for ServerMsg {
+    fn from(error: &ClientError) -> Self {
+        ServerMsg::Error {
+            code: error.code,
+            message: error.message.clone(),
+            fatal: error.code.is_fatal(),
+        }
+    }
+}
+
+/// Latest version of the WebSocket protocol.
+///
+/// Version 2 adds the `Limits`, `Ack`, `Error`, `Checkpoint`, `Resync` and
+/// `Deleted` messages, which are not sent to clients of version 1. Those
+/// clients are disconnected instead once the history they need is compacted.
+/// Version 3 sends joining clients a `Snapshot` of the text instead of the
+/// whole history, version 4 adds `ServerShutdown`, and version 5 adds `Epoch`,
+/// which reconnecting clients need to resume from their revision. Clients that
+/// do not ask for a version get 1.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Oldest version of the WebSocket protocol that is still supported.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+impl ServerMsg {
+    /// Returns the first protocol version with this message.
+    fn version(&self) -> u32 {
+        match self {
+            Self::Limits(_)
+            | Self::Ack { .. }
+            | Self::Error { .. }
+            | Self::Checkpoint { .. }
+            | Self::Resync
+            | Self::Deleted => 2,
+            Self::Snapshot { .. } => 3,
+            Self::ServerShutdown { .. } => 4,
+            Self::Epoch(_) => 5,
+            _ => 1,
+        }
+    }
+}
+
+/// A component of a text operation, as it is serialized in messages.
+///
+/// Positive integers retain that many characters, negative integers delete
+/// them, and strings are inserted. Lengths are in Unicode code points.
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum Component {
+    Length(i64),
+    Insert(String),
 }
 
-impl From<ServerMsg> for Message {
-    fn from(msg: ServerMsg) -> Self {
-        let serialized = serde_json::to_string(&msg).expect("failed serialize");
-        Message::text(serialized)
+/// Returns a JSON Schema describing every message of the WebSocket protocol.
+pub fn protocol_schema() -> Value {
+    let mut gen = SchemaGenerator::default();
+    let client = gen.subschema_for::<ClientMsg>();
+    let server = gen.subschema_for::<ServerMsg>();
+    json!({
+        "$schema": gen.settings().meta_schema,
+        "title": "Rustpad WebSocket protocol",
+        "description": format!("Messages of protocol version {}.", PROTOCOL_VERSION),
+        "anyOf": [client, server],
+        "definitions": gen.definitions(),
+    })
+}
+
+/// Default number of recent operations kept in memory for each document.
+pub const DEFAULT_MAX_HISTORY: usize = 1000;
+
+/// Maximum length of a session token sent by a client.
+const MAX_SESSION_LEN: usize = 64;
+
+/// Maximum number of named snapshots of each document.
+const MAX_SNAPSHOTS: usize = 100;
+
+/// Default maximum size of a document, in Unicode code points.
+pub const DEFAULT_MAX_DOCUMENT_SIZE: usize = 256 * 1024;
+
+/// Limits on the size of a document and how many users it can have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct DocumentLimits {
+    /// Maximum length of the text, in Unicode code points.
+    pub max_document_size: usize,
+    /// Number of recent operations kept in memory, before older history is
+    /// compacted into a checkpoint.
+    pub max_history: usize,
+    /// Maximum number of connected users, or `None` for no limit.
+    pub max_users: Option<usize>,
+}
+
+impl Default for DocumentLimits {
+    fn default() -> Self {
+        Self {
+            max_document_size: DEFAULT_MAX_DOCUMENT_SIZE,
+            max_history: DEFAULT_MAX_HISTORY,
+            max_users: None,
+        }
+    }
+}
+
+/// Error returned when an edit is based on a revision before the checkpoint.
+#[derive(Debug)]
+pub struct Compacted {
+    revision: usize,
+    checkpoint: usize,
+}
+
+impl fmt::Display for Compacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "revision {} predates the checkpoint at revision {}",
+            self.revision, self.checkpoint
+        )
+    }
+}
+
+impl std::error::Error for Compacted {}
+
+/// Error returned when an edit would make a document larger than its limit.
+#[derive(Debug)]
+pub struct TooLarge {
+    size: usize,
+    max: usize,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "document size {} is greater than the maximum of {}",
+            self.size, self.max
+        )
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// Error returned when an edit arrives after the document was killed, since it
+/// would not be persisted anymore.
+#[derive(Debug)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "document is closed")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+impl State {
+    /// Returns the current revision.
+    fn revision(&self) -> usize {
+        self.checkpoint_revision + self.operations.len()
+    }
+
+    /// Returns the operations since a revision, or `None` if compacted.
+    fn operations_since(&self, start: usize) -> Option<&[UserOperation]> {
+        let index = start.checked_sub(self.checkpoint_revision)?;
+        Some(&self.operations[index.min(self.operations.len())..])
+    }
+
+    /// Folds old operations into the checkpoint, keeping a bounded tail.
+    ///
+    /// This only happens once the history reaches twice its maximum length, so
+    /// the cost is amortized. Operations that have not been persisted yet are
+    /// never folded.
+    fn compact(&mut self) {
+        let max_history = self.limits.max_history;
+        if self.operations.len() < 2 * max_history.max(1) {
+            return;
+        }
+        let mut count = self.operations.len() - max_history;
+        if let Some(persisted) = self.persisted_revision {
+            count = count.min(persisted.saturating_sub(self.checkpoint_revision));
+        }
+        if count == 0 {
+            return;
+        }
+        self.checkpoint_time = self.operations[count - 1].time;
+        let mut folded = self.operations.drain(..count).map(|op| op.operation);
+        let first = folded.next().expect("at least one operation is folded");
+        let composed = folded
+            .try_fold(first, |acc, op| acc.compose(&op))
+            .expect("history operations should compose");
+        self.checkpoint_text = composed
+            .apply(&self.checkpoint_text)
+            .expect("history should apply to the checkpoint");
+        self.checkpoint_revision += count;
+
+        // Resubmitted edits from older sessions can no longer be transformed.
+        let checkpoint = self.checkpoint_revision;
+        self.sessions
+            .retain(|_, session| session.revision > checkpoint);
     }
 }
 
@@ -103,9 +608,13 @@ impl Default for Rustpad {
         Self {
             state: Default::default(),
             count: Default::default(),
+            connections: Default::default(),
             notify: Default::default(),
             update: tx,
             killed: AtomicBool::new(false),
+            deleted: AtomicBool::new(false),
+            retry_after: Mutex::new(None),
+            epoch: format!("{:016x}", rand::random::<u64>()),
         }
     }
 }
@@ -120,9 +629,14 @@ impl From<PersistedDocument> for Rustpad {
             let mut state = rustpad.state.write();
             state.text = document.text;
             state.language = document.language;
+            state.password = document.password;
+            state.authors = into_authors(document.authors);
+            state.attribution.apply(&operation, None);
             state.operations.push(UserOperation {
                 id: u64::MAX,
                 operation,
+                time: 0,
+                session: None,
             })
         }
         rustpad
@@ -130,13 +644,126 @@ impl From<PersistedDocument> for Rustpad {
 }
 
 impl Rustpad {
+    /// Restore a document from its persisted text, operation history and
+    /// named snapshots.
+    ///
+    /// If the history is missing, this restores the latest text as a single
+    /// operation. If the history cannot be replayed, the latest text becomes a
+    /// checkpoint at the end of the history instead, so that the revisions in
+    /// memory still match those in storage.
+    pub fn restore(
+        document: PersistedDocument,
+        operations: Vec<PersistedOperation>,
+        snapshots: Vec<PersistedSnapshot>,
+    ) -> Self {
+        let rustpad = Self::replay(document, operations);
+        rustpad.state.write().snapshots = snapshots;
+        rustpad
+    }
+
+    fn replay(document: PersistedDocument, operations: Vec<PersistedOperation>) -> Self {
+        if operations.is_empty() {
+            return Self::from(document);
+        }
+        let mut text = String::new();
+        let mut attribution = Attribution::default();
+        for (revision, op) in operations.iter().enumerate() {
+            match op.operation.apply(&text) {
+                Ok(new_text) => {
+                    text = new_text;
+                    let author = Some(op.id).filter(|&id| id != u64::MAX);
+                    attribution.apply(&op.operation, author);
+                }
+                Err(e) => {
+                    warn!("failed to replay history at revision {}: {}", revision, e);
+                    return Self::checkpoint(document, operations.len());
+                }
+            }
+        }
+        if text != document.text {
+            // The history is appended before the text snapshot is written, so it
+            // may be ahead of the text if the last snapshot failed.
+            warn!("replayed history does not match the persisted text");
+        }
+
+        let rustpad = Self::default();
+        let next_id = operations
+            .iter()
+            .map(|op| op.id)
+            .filter(|&id| id != u64::MAX)
+            .max()
+            .map_or(0, |id| id + 1);
+        rustpad.count.store(next_id, Ordering::Relaxed);
+        {
+            let mut state = rustpad.state.write();
+            state.text = text;
+            state.language = document.language;
+            state.password = document.password;
+            state.authors = into_authors(document.authors);
+            state.attribution = attribution;
+            state.operations = operations
+                .into_iter()
+                .map(|op| UserOperation {
+                    id: op.id,
+                    operation: op.operation,
+                    time: op.time,
+                    session: op.session.zip(op.seq),
+                })
+                .collect();
+            // Edits in the history are recognized if their clients resubmit them.
+            let state = &mut *state;
+            for (revision, op) in (1..).zip(&state.operations) {
+                if let Some((session, seq)) = &op.session {
+                    let applied = Session {
+                        seq: *seq,
+                        revision,
+                    };
+                    state.sessions.insert(session.clone(), applied);
+                }
+            }
+        }
+        rustpad
+    }
+
+    /// Restores the latest text as a checkpoint at a revision, without any
+    /// history before it.
+    fn checkpoint(document: PersistedDocument, revision: usize) -> Self {
+        let mut operation = OperationSeq::default();
+        operation.insert(&document.text);
+
+        let rustpad = Self::default();
+        {
+            let mut state = rustpad.state.write();
+            state.checkpoint_revision = revision;
+            state.checkpoint_text = document.text.clone();
+            state.text = document.text;
+            state.language = document.language;
+            state.password = document.password;
+            state.authors = into_authors(document.authors);
+            state.attribution.apply(&operation, None);
+        }
+        rustpad
+    }
+
     /// Handle a connection from a WebSocket.
-    pub async fn on_connection(&self, socket: WebSocket) {
+    ///
+    /// Read-only connections receive all updates, and may share their user
+    /// information and cursors, but cannot change the document.
+    pub async fn on_connection(&self, socket: WebSocket, access: Access) {
         let id = self.count.fetch_add(1, Ordering::Relaxed);
         info!("connection! id = {}", id);
-        if let Err(e) = self.handle_connection(id, socket).await {
+        let metrics = self.metrics();
+        if let Some(metrics) = &metrics {
+            metrics.sockets.inc();
+        }
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.handle_connection(id, socket, &access).await {
             warn!("connection terminated early: {}", e);
         }
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+        if let Some(metrics) = &metrics {
+            metrics.sockets.dec();
+        }
         info!("disconnection, id = {}", id);
         self.state.write().users.remove(&id);
         self.state.write().cursors.remove(&id);
@@ -151,24 +778,269 @@ impl Rustpad {
         state.text.clone()
     }
 
+    /// Returns the number of currently connected clients.
+    pub fn num_connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
     /// Returns a snapshot of the current document for persistence.
     pub fn snapshot(&self) -> PersistedDocument {
         let state = self.state.read();
         PersistedDocument {
             text: state.text.clone(),
             language: state.language.clone(),
+            password: state.password.clone(),
+            authors: state
+                .authors
+                .iter()
+                .map(|(&id, info)| (id, info.clone().into()))
+                .collect(),
+        }
+    }
+
+    /// Returns the hash of the password required to access the document.
+    pub fn password(&self) -> Option<String> {
+        let state = self.state.read();
+        state.password.clone()
+    }
+
+    /// Sets or clears the hash of the password required to access the document.
+    pub fn set_password(&self, password: Option<String>) {
+        self.state.write().password = password;
+    }
+
+    /// Returns the access control list of the document.
+    pub fn acl(&self) -> PersistedAcl {
+        let state = self.state.read();
+        state.acl.clone()
+    }
+
+    /// Replaces the access control list of the document.
+    pub fn set_acl(&self, acl: PersistedAcl) {
+        self.state.write().acl = acl;
+    }
+
+    /// Makes a principal the owner of the document, if it has no owner and has
+    /// never been edited.
+    pub fn claim(&self, principal: &str) {
+        let mut state = self.state.write();
+        if state.acl.owner.is_none() && state.revision() == 0 {
+            state.acl.owner = Some(principal.into());
         }
     }
 
+    /// Grants a role other than owner to a principal, or revokes their access
+    /// with `None`.
+    ///
+    /// A document without an owner is claimed by the principal making the
+    /// change, as long as it has never been edited. Returns `false` without
+    /// changes if they are not the owner.
+    pub fn grant(&self, caller: &str, principal: &str, role: Option<Role>) -> bool {
+        let mut state = self.state.write();
+        let revision = state.revision();
+        let acl = &mut state.acl;
+        match acl.owner.as_deref() {
+            Some(owner) if owner != caller => return false,
+            None if revision > 0 => return false,
+            _ => {}
+        }
+        acl.owner = Some(caller.into());
+        match role {
+            Some(role) => acl.grants.insert(principal.into(), role),
+            None => acl.grants.remove(principal),
+        };
+        true
+    }
+
+    /// Returns the limits of the document.
+    pub fn limits(&self) -> DocumentLimits {
+        self.state.read().limits
+    }
+
+    /// Sets the limits of the document, compacting older history into a
+    /// checkpoint if needed.
+    ///
+    /// Lowering the limits does not truncate the text or disconnect users, but
+    /// applies to later edits and connections.
+    pub fn set_limits(&self, limits: DocumentLimits) {
+        let mut state = self.state.write();
+        state.limits = limits;
+        state.compact();
+    }
+
+    /// Returns the metrics that the document records into, if set.
+    pub fn metrics(&self) -> Option<Arc<Metrics>> {
+        self.state.read().metrics.clone()
+    }
+
+    /// Sets the metrics that the document records into, which are usually
+    /// shared by every document of a server.
+    pub fn set_metrics(&self, metrics: Arc<Metrics>) {
+        self.state.write().metrics = Some(metrics);
+    }
+
+    /// Marks the history up to a revision as persisted.
+    ///
+    /// Once this has been called, operations that have not been persisted are
+    /// kept in memory even if they are old enough to be compacted.
+    pub fn set_persisted_revision(&self, revision: usize) {
+        let mut state = self.state.write();
+        state.persisted_revision = Some(revision);
+    }
+
+    /// Returns the operations in the history since a revision, for persistence.
+    ///
+    /// Operations that have been compacted are no longer available, so this
+    /// only returns those after the checkpoint.
+    pub fn persisted_operations(&self, start: usize) -> Vec<PersistedOperation> {
+        let state = self.state.read();
+        let start = start.max(state.checkpoint_revision);
+        state
+            .operations_since(start)
+            .unwrap_or_default()
+            .iter()
+            .map(|op| PersistedOperation {
+                id: op.id,
+                operation: op.operation.clone(),
+                time: op.time,
+                session: op.session.as_ref().map(|(session, _)| session.clone()),
+                seq: op.session.as_ref().map(|&(_, seq)| seq),
+            })
+            .collect()
+    }
+
+    /// Returns the named snapshots of this document, starting at an index.
+    pub fn snapshots_since(&self, start: usize) -> Vec<PersistedSnapshot> {
+        let state = self.state.read();
+        state.snapshots[start.min(state.snapshots.len())..].to_vec()
+    }
+
+    /// Restores the text of a named snapshot, returning the new revision, or
+    /// `None` if there is no snapshot with that index.
+    ///
+    /// The text is replaced as with [`Rustpad::set_text`], and the language of
+    /// the snapshot is restored too.
+    pub fn restore_snapshot(&self, index: usize, user: Option<&User>) -> Result<Option<usize>> {
+        let (text, language) = {
+            let state = self.state.read();
+            let Some(snapshot) = state.snapshots.get(index) else {
+                return Ok(None);
+            };
+            (snapshot.text.clone(), snapshot.language.clone())
+        };
+        let revision = self.set_text(&text, user)?;
+        if let Some(language) = language {
+            self.set_language(language);
+        }
+        Ok(Some(revision))
+    }
+
+    /// Replaces the whole text of the document, returning the new revision.
+    ///
+    /// This is applied as a normal edit from a new user, so any concurrent
+    /// edits are transformed against it and connected clients stay in sync.
+    /// If the text is unchanged, no edit is made.
+    pub fn set_text(&self, text: &str, user: Option<&User>) -> Result<usize> {
+        let (revision, operation) = {
+            let state = self.state.read();
+            (state.revision(), diff(&state.text, text))
+        };
+        if operation.is_noop() {
+            return Ok(revision);
+        }
+        self.edit(revision, operation, user)
+    }
+
+    /// Applies an edit from a new user, returning the revision after it.
+    ///
+    /// The edit is transformed against any history since its base revision,
+    /// then broadcast to connected clients. If the user is authenticated, the
+    /// inserted text is attributed to them.
+    pub fn edit(
+        &self,
+        revision: usize,
+        operation: OperationSeq,
+        user: Option<&User>,
+    ) -> Result<usize> {
+        let id = self.count.fetch_add(1, Ordering::Relaxed);
+        let revision = self.apply_edit(id, revision, operation, None)?;
+        if let Some(user) = user {
+            self.state.write().authors.insert(id, user.into());
+        }
+        self.notify.notify_waiters();
+        Ok(revision)
+    }
+
+    /// Returns the text at a past revision.
+    ///
+    /// Returns `None` if the revision is in the future, or if it precedes the
+    /// checkpoint and its history has been compacted.
+    pub fn text_at(&self, revision: usize) -> Option<String> {
+        let state = self.state.read();
+        if revision > state.revision() {
+            return None;
+        }
+        if revision == state.revision() {
+            return Some(state.text.clone());
+        }
+        let count = revision.checked_sub(state.checkpoint_revision)?;
+        let mut text = state.checkpoint_text.clone();
+        for op in &state.operations[..count] {
+            text = op
+                .operation
+                .apply(&text)
+                .expect("history should apply to the checkpoint");
+        }
+        Some(text)
+    }
+
+    /// Returns the revision as of a time, in milliseconds since the Unix epoch.
+    ///
+    /// Returns `None` if the time precedes the checkpoint and its history has
+    /// been compacted.
+    pub fn revision_at(&self, time: u64) -> Option<usize> {
+        let state = self.state.read();
+        if state.checkpoint_revision > 0 && time < state.checkpoint_time {
+            return None;
+        }
+        let count = state
+            .operations
+            .iter()
+            .position(|op| op.time > time)
+            .unwrap_or(state.operations.len());
+        Some(state.checkpoint_revision + count)
+    }
+
+    /// Returns the authorship of every range of the current text.
+    pub fn blame(&self) -> Vec<BlameRange> {
+        let state = self.state.read();
+        state
+            .attribution
+            .ranges()
+            .map(|(start, end, id)| BlameRange {
+                start,
+                end,
+                id,
+                info: id.and_then(|id| state.authors.get(&id).cloned()),
+            })
+            .collect()
+    }
+
     /// Returns the current revision.
     pub fn revision(&self) -> usize {
         let state = self.state.read();
-        state.operations.len()
+        state.revision()
     }
 
     /// Kill this object immediately, dropping all current connections.
+    ///
+    /// No edits are applied afterwards. The flag is set under the state lock,
+    /// so that edits in progress are finished before the final persist.
     pub fn kill(&self) {
-        self.killed.store(true, Ordering::Relaxed);
+        {
+            let _state = self.state.write();
+            self.killed.store(true, Ordering::Relaxed);
+        }
         self.notify.notify_waiters();
     }
 
@@ -177,10 +1049,59 @@ impl Rustpad {
         self.killed.load(Ordering::Relaxed)
     }
 
-    async fn handle_connection(&self, id: u64, mut socket: WebSocket) -> Result<()> {
+    /// Waits until this Rustpad object has been killed.
+    pub async fn wait_killed(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.killed() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Kill this object because the document was deleted, telling all current
+    /// connections before dropping them.
+    pub fn delete(&self) {
+        self.deleted.store(true, Ordering::Relaxed);
+        self.kill();
+    }
+
+    /// Returns if this Rustpad object has been deleted.
+    pub fn deleted(&self) -> bool {
+        self.deleted.load(Ordering::Relaxed)
+    }
+
+    /// Kill this object because the server is shutting down, telling all
+    /// current connections how many seconds to wait before reconnecting.
+    pub fn shut_down(&self, retry_after: u64) {
+        *self.retry_after.lock() = Some(retry_after);
+        self.kill();
+    }
+
+    async fn handle_connection(
+        &self,
+        id: u64,
+        mut socket: WebSocket,
+        access: &Access,
+    ) -> Result<()> {
+        let max_users = self.limits().max_users;
+        if max_users.is_some_and(|max| self.num_connections() > max) {
+            let message = "too many users are connected to the document";
+            let error = ClientError::new(ErrorCode::DocumentFull, message);
+            send(&mut socket, access, (&error).into()).await?;
+            return Err(error.into());
+        }
+        let Some(_socket_guard) = self.register_socket(access) else {
+            let message = "too many connections from this address";
+            let error = ClientError::new(ErrorCode::TooManyConnections, message);
+            send(&mut socket, access, (&error).into()).await?;
+            return Err(error.into());
+        };
+        let mut limiter = access.limiter.connect(access.addr);
         let mut update_rx = self.update.subscribe();
 
-        let mut revision: usize = self.send_initial(id, &mut socket).await?;
+        let mut revision: usize = self.send_initial(id, &mut socket, access).await?;
 
         loop {
             // In order to avoid the "lost wakeup" problem, we first request a
@@ -188,22 +1109,44 @@ impl Rustpad {
             // This is the same approach that `tokio::sync::watch` takes.
             let notified = self.notify.notified();
             if self.killed() {
+                let retry_after = *self.retry_after.lock();
+                if self.deleted() {
+                    send(&mut socket, access, ServerMsg::Deleted).await?;
+                } else if let Some(retry_after) = retry_after {
+                    let msg = ServerMsg::ServerShutdown { retry_after };
+                    send(&mut socket, access, msg).await?;
+                }
                 break;
             }
             if self.revision() > revision {
-                revision = self.send_history(revision, &mut socket).await?
+                revision = self
+                    .send_history(revision, None, &mut socket, access)
+                    .await?
             }
 
             tokio::select! {
                 _ = notified => {}
                 update = update_rx.recv() => {
-                    socket.send(update?.into()).await?;
+                    if let (Err(RecvError::Lagged(_)), Some(metrics)) = (&update, self.metrics()) {
+                        metrics.broadcast_lagged.inc();
+                    }
+                    send(&mut socket, access, update?).await?;
                 }
                 result = socket.next() => {
                     match result {
                         None => break,
                         Some(message) => {
-                            self.handle_message(id, message?).await?;
+                            let result = self
+                                .handle_message(
+                                    id,
+                                    message?,
+                                    &mut socket,
+                                    &mut revision,
+                                    access,
+                                    &mut limiter,
+                                )
+                                .await;
+                            report_error(result, &mut socket, access).await?;
                         }
                     }
                 }
@@ -213,16 +1156,62 @@ impl Rustpad {
         Ok(())
     }
 
-    async fn send_initial(&self, id: u64, socket: &mut WebSocket) -> Result<usize> {
-        socket.send(ServerMsg::Identity(id).into()).await?;
+    async fn send_initial(
+        &self,
+        id: u64,
+        socket: &mut WebSocket,
+        access: &Access,
+    ) -> Result<usize> {
+        let identity = if access.readonly {
+            Identity::ReadOnly { id, readonly: true }
+        } else {
+            Identity::Editor(id)
+        };
+        send(socket, access, ServerMsg::Identity(identity)).await?;
+        send(socket, access, ServerMsg::Epoch(self.epoch.clone())).await?;
         let mut messages = Vec::new();
+        let compacted;
         let revision = {
             let state = self.state.read();
-            if !state.operations.is_empty() {
-                messages.push(ServerMsg::History {
-                    start: 0,
-                    operations: state.operations.clone(),
-                });
+            compacted = state.checkpoint_revision > 0 && access.version < 2;
+            messages.push(ServerMsg::Limits(state.limits));
+            if access.version >= 3 {
+                // Reconnecting clients only need the history they missed, if
+                // it is still around and their revision is from the same
+                // epoch, and everyone else starts from the text.
+                let same_epoch = access.epoch.as_deref() == Some(self.epoch.as_str());
+                let missed = access
+                    .revision
+                    .filter(|&revision| revision == 0 || same_epoch)
+                    .filter(|&revision| revision <= state.revision())
+                    .and_then(|revision| Some((revision, state.operations_since(revision)?)));
+                match missed {
+                    Some((_, [])) => {}
+                    Some((start, operations)) => messages.push(ServerMsg::History {
+                        start,
+                        operations: operations.to_owned(),
+                    }),
+                    None if state.revision() > 0 || access.revision.is_some() => {
+                        messages.push(ServerMsg::Snapshot {
+                            revision: state.revision(),
+                            text: state.text.clone(),
+                        })
+                    }
+                    None => {}
+                }
+            } else {
+                if state.checkpoint_revision > 0 {
+                    messages.push(ServerMsg::Checkpoint {
+                        revision: state.checkpoint_revision,
+                        text: state.checkpoint_text.clone(),
+                    });
+                }
+                if !state.operations.is_empty() {
+                    messages.push(ServerMsg::History {
+                        start: state.checkpoint_revision,
+                        operations: state.operations.clone(),
+                    });
+                }
             }
             if let Some(language) = &state.language {
                 messages.push(ServerMsg::Language(language.clone()));
@@ -239,52 +1228,152 @@ impl Rustpad {
                     data: data.clone(),
                 });
             }
-            state.operations.len()
+            state.revision()
         };
+        if compacted {
+            return Err(refuse_compacted(socket).await);
+        }
         for msg in messages {
-            socket.send(msg.into()).await?;
+            send(socket, access, msg).await?;
         }
         Ok(revision)
     }
 
-    async fn send_history(&self, start: usize, socket: &mut WebSocket) -> Result<usize> {
+    /// Sends the history since a revision, up to another revision if given,
+    /// returning the revision the client has been sent.
+    async fn send_history(
+        &self,
+        start: usize,
+        end: Option<usize>,
+        socket: &mut WebSocket,
+        access: &Access,
+    ) -> Result<usize> {
         let operations = {
             let state = self.state.read();
-            let len = state.operations.len();
-            if start < len {
-                state.operations[start..].to_owned()
-            } else {
-                Vec::new()
+            state.operations_since(start).map(|ops| {
+                let count = end.map_or(ops.len(), |end| end.saturating_sub(start));
+                ops[..count.min(ops.len())].to_owned()
+            })
+        };
+        let Some(operations) = operations else {
+            // This connection fell too far behind, and the history it needs
+            // has already been compacted.
+            if access.version < 2 {
+                return Err(refuse_compacted(socket).await);
             }
+            send(socket, access, ServerMsg::Resync).await?;
+            bail!("history since revision {} was compacted", start);
         };
         let num_ops = operations.len();
         if num_ops > 0 {
             let msg = ServerMsg::History { start, operations };
-            socket.send(msg.into()).await?;
+            send(socket, access, msg).await?;
         }
         Ok(start + num_ops)
     }
 
-    async fn handle_message(&self, id: u64, message: Message) -> Result<()> {
-        let msg: ClientMsg = match message.to_str() {
-            Ok(text) => serde_json::from_str(text).context("failed to deserialize message")?,
-            Err(()) => return Ok(()), // Ignore non-text messages
+    /// Counts a new socket from the address of a connection, returning `None`
+    /// if it has too many already. The socket is counted until the returned
+    /// guard is dropped.
+    fn register_socket(&self, access: &Access) -> Option<SocketGuard<'_>> {
+        let max = access.limiter.limits().ip_sockets_per_document;
+        let mut state = self.state.write();
+        let count = state.sockets.get(&access.addr).copied().unwrap_or(0);
+        if max.is_some_and(|max| count >= max) {
+            return None;
+        }
+        state.sockets.insert(access.addr, count + 1);
+        Some(SocketGuard {
+            rustpad: self,
+            addr: access.addr,
+        })
+    }
+
+    async fn handle_message(
+        &self,
+        id: u64,
+        message: Message,
+        socket: &mut WebSocket,
+        sent: &mut usize,
+        access: &Access,
+        limiter: &mut ConnectionLimiter,
+    ) -> Result<()> {
+        let Some(msg) = ClientMsg::parse(&message, access.encoding)? else {
+            return Ok(()); // Ignore frames without messages
         };
+        if access.readonly && msg.is_write() {
+            if matches!(msg, ClientMsg::Edit { .. }) {
+                self.reject_edit(ErrorCode::Forbidden.label());
+            }
+            let message = "read-only connection cannot change the document";
+            return Err(ClientError::new(ErrorCode::Forbidden, message).into());
+        }
+        if !access.cursors && matches!(msg, ClientMsg::CursorData(_)) {
+            let message = "connection cannot share its cursor";
+            return Err(ClientError::new(ErrorCode::Forbidden, message).into());
+        }
+        if let ClientMsg::Edit { session, .. } = &msg {
+            if session.as_ref().is_some_and(|s| s.len() > MAX_SESSION_LEN) {
+                let message = format!("session token is longer than {} bytes", MAX_SESSION_LEN);
+                self.reject_edit(ErrorCode::MalformedMessage.label());
+                return Err(ClientError::new(ErrorCode::MalformedMessage, message).into());
+            }
+            if let Err(message) = limiter.check_edit(msg.inserted_bytes()) {
+                self.reject_edit(ErrorCode::RateLimited.label());
+                return Err(ClientError::new(ErrorCode::RateLimited, message).into());
+            }
+        }
         match msg {
             ClientMsg::Edit {
                 revision,
                 operation,
+                seq,
+                session,
             } => {
-                self.apply_edit(id, revision, operation)
-                    .context("invalid edit operation")?;
+                let session = session.as_deref().zip(seq);
+                let acked = match self.apply_edit(id, revision, operation, session) {
+                    Ok(acked) => acked,
+                    Err(e) => {
+                        if e.is::<Compacted>() {
+                            if access.version < 2 {
+                                return Err(refuse_compacted(socket).await);
+                            }
+                            send(socket, access, ServerMsg::Resync).await?;
+                        }
+                        if let Some(too_large) = e.downcast_ref::<TooLarge>() {
+                            let message = too_large.to_string();
+                            return Err(ClientError::new(ErrorCode::TooLarge, message).into());
+                        }
+                        return Err(e.context("invalid edit operation"));
+                    }
+                };
                 self.notify.notify_waiters();
+                // Send the history up to the edit first, so that the client
+                // knows which concurrent edits were applied before it.
+                *sent = self
+                    .send_history(*sent, Some(acked), socket, access)
+                    .await?;
+                let msg = ServerMsg::Ack {
+                    revision: acked,
+                    seq,
+                };
+                send(socket, access, msg).await?;
             }
             ClientMsg::SetLanguage(language) => {
-                self.state.write().language = Some(language.clone());
-                self.update.send(ServerMsg::Language(language)).ok();
+                self.set_language(language);
             }
-            ClientMsg::ClientInfo(info) => {
-                self.state.write().users.insert(id, info.clone());
+            ClientMsg::ClientInfo(mut info) => {
+                if let Some(user) = &access.user {
+                    info.name = user.name.clone();
+                    info.hue = user.hue.unwrap_or(info.hue);
+                }
+                {
+                    let mut state = self.state.write();
+                    state.users.insert(id, info.clone());
+                    if state.authors.contains_key(&id) {
+                        state.authors.insert(id, info.clone());
+                    }
+                }
                 let msg = ServerMsg::UserInfo {
                     id,
                     info: Some(info),
@@ -296,11 +1385,71 @@ impl Rustpad {
                 let msg = ServerMsg::UserCursor { id, data };
                 self.update.send(msg).ok();
             }
+            ClientMsg::CreateSnapshot { label } => {
+                let mut state = self.state.write();
+                if state.snapshots.len() >= MAX_SNAPSHOTS {
+                    let message = format!("documents can have at most {} snapshots", MAX_SNAPSHOTS);
+                    return Err(ClientError::new(ErrorCode::TooManySnapshots, message).into());
+                }
+                let snapshot = PersistedSnapshot {
+                    label,
+                    revision: state.revision(),
+                    id,
+                    time: unix_millis(),
+                    text: state.text.clone(),
+                    language: state.language.clone(),
+                };
+                state.snapshots.push(snapshot);
+            }
         }
         Ok(())
     }
 
-    fn apply_edit(&self, id: u64, revision: usize, mut operation: OperationSeq) -> Result<()> {
+    fn set_language(&self, language: String) {
+        self.state.write().language = Some(language.clone());
+        self.update.send(ServerMsg::Language(language)).ok();
+    }
+
+    /// Applies an edit based on a revision, returning the revision after it.
+    ///
+    /// If the edit comes from a client session with a sequence number, and
+    /// that session has already applied an edit with the same or a later
+    /// sequence number, it is not applied again. Instead, this returns the
+    /// revision after the latest edit from the session.
+    fn apply_edit(
+        &self,
+        id: u64,
+        revision: usize,
+        operation: OperationSeq,
+        session: Option<(&str, u64)>,
+    ) -> Result<usize> {
+        let start = Instant::now();
+        let result = self.try_apply_edit(id, revision, operation, session);
+        if let Some(metrics) = self.metrics() {
+            metrics
+                .apply_edit_seconds
+                .observe(start.elapsed().as_secs_f64());
+        }
+        if let Err(e) = &result {
+            self.reject_edit(rejection_reason(e));
+        }
+        result
+    }
+
+    /// Counts an edit that was rejected for a reason, if metrics are set.
+    fn reject_edit(&self, reason: &str) {
+        if let Some(metrics) = self.metrics() {
+            metrics.reject_edit(reason);
+        }
+    }
+
+    fn try_apply_edit(
+        &self,
+        id: u64,
+        revision: usize,
+        mut operation: OperationSeq,
+        session: Option<(&str, u64)>,
+    ) -> Result<usize> {
         info!(
             "edit: id = {}, revision = {}, base_len = {}, target_len = {}",
             id,
@@ -309,20 +1458,42 @@ impl Rustpad {
             operation.target_len()
         );
         let state = self.state.upgradable_read();
-        let len = state.operations.len();
+        if self.killed() {
+            return Err(Closed.into());
+        }
+        if let Some((session, seq)) = session {
+            if let Some(applied) = state.sessions.get(session).filter(|s| s.seq >= seq) {
+                info!("edit from session was already applied: id = {}", id);
+                return Ok(applied.revision);
+            }
+        }
+        let len = state.revision();
         if revision > len {
-            bail!("got revision {}, but current is {}", revision, len);
+            let message = format!("got revision {}, but current is {}", revision, len);
+            return Err(ClientError::new(ErrorCode::InvalidRevision, message).into());
+        }
+        let history = state.operations_since(revision).ok_or_else(|| {
+            anyhow!(Compacted {
+                revision,
+                checkpoint: state.checkpoint_revision,
+            })
+        })?;
+        let invalid = |e: OTError| ClientError::new(ErrorCode::InvalidOperation, e.to_string());
+        if let Some(metrics) = &state.metrics {
+            metrics.transforms.inc_by(history.len() as u64);
         }
-        for history_op in &state.operations[revision..] {
-            operation = operation.transform(&history_op.operation)?.0;
+        for history_op in history {
+            operation = operation
+                .transform(&history_op.operation)
+                .map_err(invalid)?
+                .0;
         }
-        if operation.target_len() > 256 * 1024 {
-            bail!(
-                "target length {} is greater than 256 KiB maximum",
-                operation.target_len()
-            );
+        // Edits that do not grow the text are allowed, even over the limit.
+        let (size, max) = (operation.target_len(), state.limits.max_document_size);
+        if size > max && size > operation.base_len() {
+            return Err(TooLarge { size, max }.into());
         }
-        let new_text = operation.apply(&state.text)?;
+        let new_text = operation.apply(&state.text).map_err(invalid)?;
         let mut state = RwLockUpgradableReadGuard::upgrade(state);
         for (_, data) in state.cursors.iter_mut() {
             for cursor in data.cursors.iter_mut() {
@@ -333,8 +1504,106 @@ impl Rustpad {
                 *end = transform_index(&operation, *end);
             }
         }
-        state.operations.push(UserOperation { id, operation });
+        state.attribution.apply(&operation, Some(id));
+        if let Some(info) = state.users.get(&id).cloned() {
+            state.authors.insert(id, info);
+        }
+        let time = unix_millis();
+        state.operations.push(UserOperation {
+            id,
+            operation,
+            time,
+            session: session.map(|(session, seq)| (session.into(), seq)),
+        });
         state.text = new_text;
-        Ok(())
+        let revision = state.revision();
+        if let Some((session, seq)) = session {
+            state
+                .sessions
+                .insert(session.into(), Session { seq, revision });
+        }
+        state.compact();
+        if let Some(metrics) = &state.metrics {
+            metrics.edits.inc();
+        }
+        Ok(revision)
     }
 }
+
+/// Keeps a socket counted towards the limit for its address while alive.
+struct SocketGuard<'a> {
+    rustpad: &'a Rustpad,
+    addr: Option<IpAddr>,
+}
+
+impl Drop for SocketGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.rustpad.state.write();
+        if let Some(count) = state.sockets.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                state.sockets.remove(&self.addr);
+            }
+        }
+    }
+}
+
+/// Sends a message to a client in its encoding, unless its protocol version
+/// predates the message.
+async fn send(socket: &mut WebSocket, access: &Access, msg: ServerMsg) -> Result<()> {
+    if msg.version() <= access.version {
+        socket.send(access.encoding.encode(&msg)).await?;
+    }
+    Ok(())
+}
+
+/// Closes the connection of a client whose protocol version predates
+/// checkpoints, since it can't follow the history once it was compacted.
+/// Returns the error that ends the connection.
+async fn refuse_compacted(socket: &mut WebSocket) -> anyhow::Error {
+    let reason = "document history was compacted, which needs protocol version 2 or later";
+    if let Err(e) = socket.send(Message::close_with(1008u16, reason)).await {
+        return e.into();
+    }
+    anyhow!(reason)
+}
+
+/// Reports an error caused by the client to it, passing on the error if it
+/// is fatal or was not caused by the client.
+async fn report_error(result: Result<()>, socket: &mut WebSocket, access: &Access) -> Result<()> {
+    let Err(e) = result else {
+        return Ok(());
+    };
+    let Some(error) = e.downcast_ref::<ClientError>() else {
+        return Err(e);
+    };
+    send(socket, access, error.into()).await?;
+    if error.code.is_fatal() {
+        return Err(e);
+    }
+    warn!("ignoring message from client: {}", error);
+    Ok(())
+}
+
+/// Returns the reason that an edit was rejected, as a metric label.
+fn rejection_reason(error: &anyhow::Error) -> &'static str {
+    if error.is::<Compacted>() {
+        "compacted"
+    } else if error.is::<TooLarge>() {
+        ErrorCode::TooLarge.label()
+    } else if error.is::<Closed>() {
+        "closed"
+    } else if let Some(error) = error.downcast_ref::<ClientError>() {
+        error.code.label()
+    } else {
+        "other"
+    }
+}
+
+/// Returns the current system time in milliseconds since the Unix epoch.
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("SystemTime returned before UNIX_EPOCH")
+        .as_millis() as u64
+}
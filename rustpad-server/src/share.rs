@@ -0,0 +1,68 @@
+//! Read-only share tokens, derived from document IDs with a server secret.
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// Length of the nonce at the start of a token, in bytes.
+const NONCE_LEN: usize = 24;
+
+/// Derives read-only share tokens for documents.
+///
+/// A token is the document ID encrypted with XChaCha20-Poly1305 under a key
+/// derived from the server secret. This grants access to view the document
+/// without revealing the ID needed to edit it, and the ID can be recovered
+/// from the token without looking up every stored document.
+#[derive(Clone)]
+pub struct ShareKey {
+    cipher: XChaCha20Poly1305,
+}
+
+impl ShareKey {
+    /// Construct a key from a server secret.
+    pub fn new(secret: &[u8]) -> Self {
+        let key = Sha256::new()
+            .chain_update(b"rustpad share key\0")
+            .chain_update(secret)
+            .finalize();
+        Self {
+            cipher: XChaCha20Poly1305::new(&key),
+        }
+    }
+
+    /// Construct a key from a random secret, so tokens last until restart.
+    pub fn random() -> Self {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self::new(&secret)
+    }
+
+    /// Returns a new read-only token for a document.
+    pub fn token(&self, document_id: &str) -> String {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let encrypted = self
+            .cipher
+            .encrypt(&nonce, document_id.as_bytes())
+            .expect("document IDs should fit in a single message");
+        let mut token = nonce.to_vec();
+        token.extend(encrypted);
+        hex::encode(token)
+    }
+
+    /// Returns the document that a token was derived from, if it is valid.
+    pub fn document_id(&self, token: &str) -> Option<String> {
+        let token = hex::decode(token).ok()?;
+        if token.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, encrypted) = token.split_at(NONCE_LEN);
+        let document_id = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), encrypted)
+            .ok()?;
+        String::from_utf8(document_id).ok()
+    }
+}
@@ -0,0 +1,56 @@
+//! Graceful shutdown of a server, triggered from outside of it.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// Handle for shutting down a server gracefully.
+///
+/// Shutting down tells every connected client when to reconnect before closing
+/// its socket, and stores every document with changes that were not persisted
+/// yet. The handle is passed to the server in its configuration.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    /// Set to the delay before clients should reconnect, to start shutting down.
+    requested: Arc<watch::Sender<Option<Duration>>>,
+    /// Set once every document has been stored.
+    finished: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            requested: Arc::new(watch::channel(None).0),
+            finished: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Start shutting down the server, and wait until it has finished.
+    ///
+    /// This never finishes if no server was configured with the handle, so
+    /// callers should give up after a deadline.
+    pub async fn shut_down(&self, retry_after: Duration) {
+        let mut finished = self.finished.subscribe();
+        self.requested.send_replace(Some(retry_after));
+        finished.wait_for(|&finished| finished).await.ok();
+    }
+
+    /// Waits until shutting down is requested, returning how long clients
+    /// should wait before reconnecting.
+    pub async fn requested(&self) -> Duration {
+        let mut requested = self.requested.subscribe();
+        let retry_after = requested
+            .wait_for(Option::is_some)
+            .await
+            .expect("sender is kept alive by this handle");
+        retry_after.expect("shutdown was requested")
+    }
+
+    /// Marks the server as finished shutting down.
+    pub fn finish(&self) {
+        self.finished.send_replace(true);
+    }
+}
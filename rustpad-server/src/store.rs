@@ -0,0 +1,680 @@
+//! Pluggable storage backends for persisting documents.
+//!
+//! The server only talks to storage through the [`DocumentStore`] trait, so
+//! embedders of [`server`](crate::server) can provide their own backend. Three
+//! implementations are included: the SQL [`Database`](crate::database::Database),
+//! an in-memory [`MemoryStore`], and a plain-directory [`FileStore`].
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex as AsyncMutex};
+
+use crate::database::{
+    PersistedAcl, PersistedAuthor, PersistedDocument, PersistedOperation, PersistedSnapshot,
+};
+
+/// A backend capable of persisting documents by their identifier.
+#[async_trait]
+pub trait DocumentStore: Debug + Send + Sync {
+    /// Load a document, returning a [`NotFound`] error if it does not exist.
+    ///
+    /// Any other error means the document could not be read, and callers must
+    /// not treat it as missing.
+    async fn load(&self, document_id: &str) -> Result<PersistedDocument>;
+
+    /// Store a document, replacing any existing version.
+    async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()>;
+
+    /// Count the number of stored documents.
+    async fn count(&self) -> Result<usize>;
+
+    /// Load the operation history of a document, starting at revision 0.
+    ///
+    /// Returns an empty history if none was stored, in which case the document
+    /// is restored from its latest text alone.
+    async fn load_operations(&self, document_id: &str) -> Result<Vec<PersistedOperation>>;
+
+    /// Count the operations in the history of a document, which is the revision
+    /// it is restored at unless no history was stored.
+    ///
+    /// By default this loads the whole history. Backends should override it
+    /// when they can count operations more cheaply.
+    async fn count_operations(&self, document_id: &str) -> Result<usize> {
+        Ok(self.load_operations(document_id).await?.len())
+    }
+
+    /// Append operations to the history of a document, starting at revision
+    /// `start`. Revisions that were already stored are left unchanged.
+    async fn store_operations(
+        &self,
+        document_id: &str,
+        start: usize,
+        operations: &[PersistedOperation],
+    ) -> Result<()>;
+
+    /// Load the named snapshots of a document, in order of creation.
+    async fn load_snapshots(&self, document_id: &str) -> Result<Vec<PersistedSnapshot>>;
+
+    /// Append named snapshots to a document, starting at index `start`.
+    /// Snapshots that were already stored are left unchanged.
+    async fn store_snapshots(
+        &self,
+        document_id: &str,
+        start: usize,
+        snapshots: &[PersistedSnapshot],
+    ) -> Result<()>;
+
+    /// Load the access control list of a document, which is empty if none was
+    /// stored.
+    async fn load_acl(&self, document_id: &str) -> Result<PersistedAcl>;
+
+    /// Store the access control list of a document, replacing any existing one.
+    async fn store_acl(&self, document_id: &str, acl: &PersistedAcl) -> Result<()>;
+
+    /// Delete a document along with its history, snapshots and access control
+    /// list, returning whether it existed.
+    async fn delete(&self, document_id: &str) -> Result<bool>;
+
+    /// List the identifiers of all stored documents, in sorted order.
+    async fn list(&self) -> Result<Vec<String>>;
+
+    /// Find the stored documents matching a query, returning a page of them
+    /// in sorted order along with the total number of matches.
+    ///
+    /// By default this loads every document that is not excluded by its ID.
+    /// Backends should override it to filter and page documents natively,
+    /// following the rules of [`DocumentQuery::matches`].
+    async fn query(&self, query: &DocumentQuery) -> Result<DocumentPage> {
+        let mut ids = Vec::new();
+        for id in self.list().await? {
+            if !query.matches_id(&id) {
+                continue;
+            }
+            let document = self.load(&id).await?;
+            let acl = self.load_acl(&id).await?;
+            if query.matches(&id, &document.text, document.password.as_deref(), &acl) {
+                ids.push((id, document));
+            }
+        }
+        let total = ids.len();
+        let mut documents = Vec::new();
+        for (id, document) in ids.into_iter().skip(query.offset).take(query.limit) {
+            documents.push(DocumentSummary {
+                revision: self.count_operations(&id).await?,
+                size: document.text.len(),
+                language: document.language,
+                id,
+            });
+        }
+        Ok(DocumentPage { total, documents })
+    }
+}
+
+/// A query for listing documents, which only matches documents without a
+/// password that a principal can view.
+#[derive(Clone, Debug, Default)]
+pub struct DocumentQuery {
+    /// Only match documents whose ID starts with this prefix.
+    pub prefix: Option<String>,
+    /// Only match documents whose ID contains this substring.
+    pub id: Option<String>,
+    /// Only match documents whose text contains this substring, ignoring the
+    /// case of ASCII letters.
+    pub text: Option<String>,
+    /// Principal who must be able to view the documents.
+    pub principal: Option<String>,
+    /// Documents never to match, such as those whose latest version is only
+    /// known elsewhere.
+    pub exclude: BTreeSet<String>,
+    /// Number of matching documents to skip.
+    pub offset: usize,
+    /// Maximum number of documents to return.
+    pub limit: usize,
+}
+
+impl DocumentQuery {
+    /// Returns whether a document ID passes the filters on IDs.
+    pub fn matches_id(&self, id: &str) -> bool {
+        !self.exclude.contains(id)
+            && self
+                .prefix
+                .as_ref()
+                .is_none_or(|p| id.starts_with(p.as_str()))
+            && self
+                .id
+                .as_ref()
+                .is_none_or(|part| id.contains(part.as_str()))
+    }
+
+    /// Returns whether a document matches the query.
+    pub fn matches(
+        &self,
+        id: &str,
+        text: &str,
+        password: Option<&str>,
+        acl: &PersistedAcl,
+    ) -> bool {
+        self.matches_id(id)
+            && password.is_none()
+            && acl.role(self.principal.as_deref()).is_some()
+            && self
+                .text
+                .as_ref()
+                .is_none_or(|q| text.to_ascii_lowercase().contains(&q.to_ascii_lowercase()))
+    }
+}
+
+/// Summary of a stored document, returned when querying documents.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DocumentSummary {
+    /// Identifier of the document.
+    pub id: String,
+    /// Size of the text, in bytes.
+    pub size: usize,
+    /// Language of the document, if set.
+    pub language: Option<String>,
+    /// Number of operations in the stored history.
+    pub revision: usize,
+}
+
+/// A page of stored documents matching a query.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DocumentPage {
+    /// Number of matching documents, across all pages.
+    pub total: usize,
+    /// Matching documents in this page, sorted by ID.
+    pub documents: Vec<DocumentSummary>,
+}
+
+/// Error returned when loading a document that was never stored.
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "document {} not found", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// Returns whether an error from [`DocumentStore::load`] means the document
+/// does not exist.
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    err.is::<NotFound>()
+}
+
+/// A store that keeps documents in process memory, mostly useful for tests.
+#[derive(Default, Debug)]
+pub struct MemoryStore {
+    documents: Mutex<BTreeMap<String, PersistedDocument>>,
+    operations: Mutex<BTreeMap<String, Vec<PersistedOperation>>>,
+    snapshots: Mutex<BTreeMap<String, Vec<PersistedSnapshot>>>,
+    acls: Mutex<BTreeMap<String, PersistedAcl>>,
+}
+
+impl MemoryStore {
+    /// Construct a new, empty in-memory store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl DocumentStore for MemoryStore {
+    async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
+        self.documents
+            .lock()
+            .get(document_id)
+            .cloned()
+            .ok_or_else(|| NotFound(document_id.into()).into())
+    }
+
+    async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        self.documents
+            .lock()
+            .insert(document_id.into(), document.clone());
+        Ok(())
+    }
+
+    async fn count(&self) -> Result<usize> {
+        Ok(self.documents.lock().len())
+    }
+
+    async fn load_operations(&self, document_id: &str) -> Result<Vec<PersistedOperation>> {
+        let operations = self.operations.lock();
+        Ok(operations.get(document_id).cloned().unwrap_or_default())
+    }
+
+    async fn count_operations(&self, document_id: &str) -> Result<usize> {
+        let operations = self.operations.lock();
+        Ok(operations.get(document_id).map_or(0, Vec::len))
+    }
+
+    async fn store_operations(
+        &self,
+        document_id: &str,
+        start: usize,
+        operations: &[PersistedOperation],
+    ) -> Result<()> {
+        let mut map = self.operations.lock();
+        let log = map.entry(document_id.into()).or_default();
+        if start > log.len() {
+            bail!("got revision {}, but history has {}", start, log.len());
+        }
+        let skip = log.len() - start;
+        log.extend(operations.iter().skip(skip).cloned());
+        Ok(())
+    }
+
+    async fn load_snapshots(&self, document_id: &str) -> Result<Vec<PersistedSnapshot>> {
+        let snapshots = self.snapshots.lock();
+        Ok(snapshots.get(document_id).cloned().unwrap_or_default())
+    }
+
+    async fn store_snapshots(
+        &self,
+        document_id: &str,
+        start: usize,
+        snapshots: &[PersistedSnapshot],
+    ) -> Result<()> {
+        let mut map = self.snapshots.lock();
+        let stored = map.entry(document_id.into()).or_default();
+        if start > stored.len() {
+            bail!(
+                "got snapshot {}, but only {} are stored",
+                start,
+                stored.len()
+            );
+        }
+        let skip = stored.len() - start;
+        stored.extend(snapshots.iter().skip(skip).cloned());
+        Ok(())
+    }
+
+    async fn load_acl(&self, document_id: &str) -> Result<PersistedAcl> {
+        let acls = self.acls.lock();
+        Ok(acls.get(document_id).cloned().unwrap_or_default())
+    }
+
+    async fn store_acl(&self, document_id: &str, acl: &PersistedAcl) -> Result<()> {
+        self.acls.lock().insert(document_id.into(), acl.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, document_id: &str) -> Result<bool> {
+        self.operations.lock().remove(document_id);
+        self.snapshots.lock().remove(document_id);
+        self.acls.lock().remove(document_id);
+        Ok(self.documents.lock().remove(document_id).is_some())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        Ok(self.documents.lock().keys().cloned().collect())
+    }
+
+    async fn query(&self, query: &DocumentQuery) -> Result<DocumentPage> {
+        let documents = self.documents.lock();
+        let acls = self.acls.lock();
+        let operations = self.operations.lock();
+        let no_acl = PersistedAcl::default();
+        let matching: Vec<_> = documents
+            .iter()
+            .filter(|(id, document)| {
+                let acl = acls.get(*id).unwrap_or(&no_acl);
+                query.matches(id, &document.text, document.password.as_deref(), acl)
+            })
+            .collect();
+        let page = matching.iter().skip(query.offset).take(query.limit);
+        Ok(DocumentPage {
+            total: matching.len(),
+            documents: page
+                .map(|(id, document)| DocumentSummary {
+                    id: id.to_string(),
+                    size: document.text.len(),
+                    language: document.language.clone(),
+                    revision: operations.get(*id).map_or(0, Vec::len),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Metadata stored in a sidecar file next to each document's text.
+#[derive(Serialize, Deserialize)]
+struct Metadata {
+    language: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    authors: BTreeMap<u64, PersistedAuthor>,
+}
+
+/// A store that writes each document to a plain directory.
+///
+/// Document `id` is saved as `{id}.txt` holding the text, along with an
+/// `{id}.json` sidecar holding metadata and an `{id}.ops` log holding the
+/// history, one JSON operation per line. Named snapshots are kept together in
+/// an `{id}.snapshots` JSON array, and the access control list in an `{id}.acl`
+/// JSON file. Characters outside of `[A-Za-z0-9_-]` are
+/// percent-encoded in file names.
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    root: PathBuf,
+    /// Number of operations in the log of each document, once it is known.
+    /// The lock also keeps appends to the same log from interleaving.
+    logs: Arc<DashMap<String, Arc<AsyncMutex<Option<usize>>>>>,
+}
+
+impl FileStore {
+    /// Open a store in a directory, creating it if it does not exist.
+    pub async fn new(root: impl AsRef<Path>) -> Result<Self> {
+        let root = root.as_ref().to_owned();
+        fs::create_dir_all(&root)
+            .await
+            .with_context(|| format!("failed to create directory {}", root.display()))?;
+        Ok(Self {
+            root,
+            logs: Default::default(),
+        })
+    }
+
+    fn path(&self, document_id: &str, extension: &str) -> PathBuf {
+        self.root
+            .join(format!("{}.{}", encode_id(document_id), extension))
+    }
+
+    /// Read the raw operation log of a document, which may not exist yet.
+    async fn read_log(&self, document_id: &str) -> Result<String> {
+        match fs::read_to_string(self.path(document_id, "ops")).await {
+            Ok(log) => Ok(log),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl DocumentStore for FileStore {
+    async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
+        let not_found = |e: std::io::Error| match e.kind() {
+            ErrorKind::NotFound => anyhow::Error::new(NotFound(document_id.into())),
+            _ => e.into(),
+        };
+        let text = fs::read_to_string(self.path(document_id, "txt"))
+            .await
+            .map_err(not_found)?;
+        let metadata = fs::read(self.path(document_id, "json"))
+            .await
+            .map_err(not_found)?;
+        let metadata: Metadata = serde_json::from_slice(&metadata)?;
+        Ok(PersistedDocument {
+            text,
+            language: metadata.language,
+            password: metadata.password,
+            authors: metadata.authors,
+        })
+    }
+
+    async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        let metadata = serde_json::to_vec(&Metadata {
+            language: document.language.clone(),
+            password: document.password.clone(),
+            authors: document.authors.clone(),
+        })?;
+        // The sidecar is written last, since `load` and `list` rely on it.
+        write_atomic(&self.path(document_id, "txt"), document.text.as_bytes()).await?;
+        write_atomic(&self.path(document_id, "json"), &metadata).await?;
+        Ok(())
+    }
+
+    async fn count(&self) -> Result<usize> {
+        Ok(self.list().await?.len())
+    }
+
+    async fn load_operations(&self, document_id: &str) -> Result<Vec<PersistedOperation>> {
+        Ok(parse_log(&self.read_log(document_id).await?))
+    }
+
+    async fn count_operations(&self, document_id: &str) -> Result<usize> {
+        // Logs that were appended to since startup have a known length.
+        if let Some(length) = self.logs.get(document_id).map(|e| Arc::clone(e.value())) {
+            if let Some(stored) = *length.lock().await {
+                return Ok(stored);
+            }
+        }
+        Ok(parse_log(&self.read_log(document_id).await?).len())
+    }
+
+    async fn store_operations(
+        &self,
+        document_id: &str,
+        start: usize,
+        operations: &[PersistedOperation],
+    ) -> Result<()> {
+        let length = Arc::clone(&self.logs.entry(document_id.into()).or_default());
+        let mut length = length.lock().await;
+        let mut lines = String::new();
+        let stored = match *length {
+            Some(stored) => stored,
+            None => {
+                // The log is only parsed the first time, after which its
+                // length is tracked as operations are appended.
+                let log = self.read_log(document_id).await?;
+                if !log.is_empty() && !log.ends_with('\n') {
+                    // Terminate a line left over from an interrupted write.
+                    lines.push('\n');
+                }
+                parse_log(&log).len()
+            }
+        };
+        if start > stored {
+            bail!("got revision {}, but history has {}", start, stored);
+        }
+        let appended = operations.len().saturating_sub(stored - start);
+        for (revision, operation) in (start..).zip(operations).skip(stored - start) {
+            let entry = LogEntry {
+                revision,
+                operation: operation.clone(),
+            };
+            lines.push_str(&serde_json::to_string(&entry)?);
+            lines.push('\n');
+        }
+        // If the append fails, the log is parsed again on the next write.
+        *length = None;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(document_id, "ops"))
+            .await?;
+        file.write_all(lines.as_bytes()).await?;
+        file.sync_data().await?;
+        *length = Some(stored + appended);
+        Ok(())
+    }
+
+    async fn load_snapshots(&self, document_id: &str) -> Result<Vec<PersistedSnapshot>> {
+        match fs::read(self.path(document_id, "snapshots")).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn store_snapshots(
+        &self,
+        document_id: &str,
+        start: usize,
+        snapshots: &[PersistedSnapshot],
+    ) -> Result<()> {
+        let mut stored = self.load_snapshots(document_id).await?;
+        if start > stored.len() {
+            bail!(
+                "got snapshot {}, but only {} are stored",
+                start,
+                stored.len()
+            );
+        }
+        let skip = stored.len() - start;
+        stored.extend(snapshots.iter().skip(skip).cloned());
+        let data = serde_json::to_vec(&stored)?;
+        write_atomic(&self.path(document_id, "snapshots"), &data).await
+    }
+
+    async fn load_acl(&self, document_id: &str) -> Result<PersistedAcl> {
+        match fs::read(self.path(document_id, "acl")).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(PersistedAcl::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn store_acl(&self, document_id: &str, acl: &PersistedAcl) -> Result<()> {
+        let data = serde_json::to_vec(acl)?;
+        write_atomic(&self.path(document_id, "acl"), &data).await
+    }
+
+    async fn delete(&self, document_id: &str) -> Result<bool> {
+        // Hold the log lock, so a concurrent append cannot interleave.
+        let length = Arc::clone(&self.logs.entry(document_id.into()).or_default());
+        let mut length = length.lock().await;
+        *length = None;
+        let existed = remove_if_exists(&self.path(document_id, "json")).await?;
+        remove_if_exists(&self.path(document_id, "txt")).await?;
+        remove_if_exists(&self.path(document_id, "ops")).await?;
+        remove_if_exists(&self.path(document_id, "snapshots")).await?;
+        remove_if_exists(&self.path(document_id, "acl")).await?;
+        self.logs.remove(document_id);
+        Ok(existed)
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        let mut entries = fs::read_dir(&self.root).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if let Some(id) = name.strip_suffix(".json").and_then(decode_id) {
+                ids.push(id);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    async fn query(&self, query: &DocumentQuery) -> Result<DocumentPage> {
+        // Only the small sidecar files are read, unless the text is searched.
+        let mut matching = Vec::new();
+        for id in self.list().await? {
+            if !query.matches_id(&id) {
+                continue;
+            }
+            let metadata = fs::read(self.path(&id, "json")).await?;
+            let metadata: Metadata = serde_json::from_slice(&metadata)?;
+            let acl = self.load_acl(&id).await?;
+            let text = match &query.text {
+                Some(_) => fs::read_to_string(self.path(&id, "txt")).await?,
+                None => String::new(),
+            };
+            if query.matches(&id, &text, metadata.password.as_deref(), &acl) {
+                matching.push((id, metadata.language));
+            }
+        }
+        let total = matching.len();
+        let mut documents = Vec::new();
+        for (id, language) in matching.into_iter().skip(query.offset).take(query.limit) {
+            documents.push(DocumentSummary {
+                size: fs::metadata(self.path(&id, "txt")).await?.len() as usize,
+                revision: self.count_operations(&id).await?,
+                language,
+                id,
+            });
+        }
+        Ok(DocumentPage { total, documents })
+    }
+}
+
+/// A line in the operation log of a [`FileStore`].
+#[derive(Serialize, Deserialize)]
+struct LogEntry {
+    revision: usize,
+    #[serde(flatten)]
+    operation: PersistedOperation,
+}
+
+/// Parse the contiguous prefix of operations in a [`FileStore`] log.
+fn parse_log(log: &str) -> Vec<PersistedOperation> {
+    let mut operations = Vec::new();
+    for line in log.lines() {
+        // Lines left partially written by an interrupted append are skipped.
+        if let Ok(entry) = serde_json::from_str::<LogEntry>(line) {
+            if entry.revision == operations.len() {
+                operations.push(entry.operation);
+            }
+        }
+    }
+    operations
+}
+
+/// Write a file by renaming a temporary file, so readers never see partial data.
+///
+/// The temporary file has a unique name, so concurrent writes to any files
+/// never share one.
+async fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let name = path.file_name().context("path should name a file")?;
+    let tmp = path.with_file_name(format!(
+        "{}.{:016x}.tmp",
+        name.to_string_lossy(),
+        rand::random::<u64>()
+    ));
+    fs::write(&tmp, contents).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Remove a file, returning whether it existed.
+async fn remove_if_exists(path: &Path) -> Result<bool> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Percent-encode a document ID into a string that is safe as a file name.
+fn encode_id(id: &str) -> String {
+    let mut encoded = String::with_capacity(id.len());
+    for byte in id.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Reverse the encoding of [`encode_id`], returning `None` if malformed.
+fn decode_id(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut iter = encoded.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
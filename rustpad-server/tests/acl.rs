@@ -0,0 +1,285 @@
+//! Tests for per-document access control lists.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{
+    auth::{StaticTokens, User},
+    database::PersistedAcl,
+    server,
+    store::{DocumentStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::{json, Value};
+use tokio::time;
+use warp::{filters::BoxedFilter, test::RequestBuilder, Reply};
+
+pub mod common;
+
+/// Configuration accepting the tokens `a`, `b` and `c` for Alice, Bob and
+/// Carol, or a principal from the `x-forwarded-user` header.
+fn config() -> ServerConfig {
+    let user = |token: &str, name: &str| {
+        let user = User {
+            name: name.into(),
+            hue: None,
+        };
+        (token.to_owned(), user)
+    };
+    let tokens = [user("a", "alice"), user("b", "bob"), user("c", "carol")];
+    ServerConfig {
+        auth: Some(Arc::new(StaticTokens::new(tokens))),
+        principal_header: Some("x-forwarded-user".into()),
+        ..ServerConfig::default()
+    }
+}
+
+/// Start a request to the API with a bearer token.
+fn request(method: &str, path: &str, token: &str) -> RequestBuilder {
+    warp::test::request()
+        .method(method)
+        .path(&format!("/api/{}", path))
+        .header("authorization", format!("Bearer {}", token))
+}
+
+/// Grant a role to a principal, or revoke it, returning the status.
+async fn grant(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    token: &str,
+    principal: &str,
+    role: Option<&str>,
+) -> u16 {
+    let body = json!({ "principal": principal, "role": role });
+    let resp = request("PUT", "acl/doc", token)
+        .json(&body)
+        .reply(filter)
+        .await;
+    resp.status().as_u16()
+}
+
+/// Send a request with a bearer token, returning the status.
+async fn status(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    method: &str,
+    path: &str,
+    token: &str,
+) -> u16 {
+    let resp = request(method, path, token).reply(filter).await;
+    resp.status().as_u16()
+}
+
+#[tokio::test]
+async fn test_roles() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(config());
+
+    // Documents are owned by the principal who creates them.
+    let resp = request("PUT", "text/doc", "a")
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(status(&filter, "GET", "text/doc", "b").await, 403);
+    assert_eq!(status(&filter, "GET", "acl/doc", "b").await, 403);
+    let resp = request("GET", "acl/doc", "a").reply(&filter).await;
+    let acl: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(acl, json!({ "owner": "alice", "grants": {} }));
+
+    assert_eq!(grant(&filter, "a", "bob", Some("viewer")).await, 204);
+    assert_eq!(grant(&filter, "a", "carol", Some("editor")).await, 204);
+    let resp = request("GET", "acl/doc", "a").reply(&filter).await;
+    let acl: Value = serde_json::from_slice(resp.body())?;
+    let grants = json!({ "bob": "viewer", "carol": "editor" });
+    assert_eq!(acl, json!({ "owner": "alice", "grants": grants }));
+
+    // Viewers can read, editors can also write, and only owners manage access.
+    assert_eq!(status(&filter, "GET", "text/doc", "b").await, 200);
+    assert_eq!(status(&filter, "GET", "blame/doc", "b").await, 200);
+    let resp = request("PUT", "text/doc", "b")
+        .body("x")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+    assert_eq!(status(&filter, "GET", "share/doc", "b").await, 403);
+    let resp = request("PUT", "text/doc", "c")
+        .body("y")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(status(&filter, "DELETE", "document/doc", "c").await, 403);
+    assert_eq!(grant(&filter, "c", "bob", Some("editor")).await, 403);
+
+    // Ownership cannot be granted or given up.
+    assert_eq!(grant(&filter, "a", "bob", Some("owner")).await, 400);
+    assert_eq!(grant(&filter, "a", "alice", None).await, 400);
+
+    assert_eq!(grant(&filter, "a", "bob", None).await, 204);
+    assert_eq!(status(&filter, "GET", "text/doc", "b").await, 403);
+
+    // A trusted proxy can identify principals with a header instead.
+    let resp = warp::test::request()
+        .path("/api/text/doc")
+        .header("x-forwarded-user", "carol")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .path("/api/text/doc")
+        .header("x-forwarded-user", "mallory")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+
+    assert_eq!(status(&filter, "DELETE", "document/doc", "a").await, 204);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_viewer_socket() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(config());
+
+    let mut alice = connect(&filter, "doc?access_token=a").await?;
+    assert_eq!(alice.recv().await?, json!({ "Identity": 0 }));
+    alice.recv_limits().await?;
+    let mut operation = OperationSeq::default();
+    operation.insert("hi");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    alice.send(&msg).await;
+    alice.recv().await?;
+
+    assert!(connect(&filter, "doc?access_token=b").await.is_err());
+    assert_eq!(grant(&filter, "a", "bob", Some("viewer")).await, 204);
+
+    let mut bob = connect(&filter, "doc?access_token=b").await?;
+    let identity = json!({ "id": 1, "readonly": true });
+    assert_eq!(bob.recv().await?, json!({ "Identity": identity }));
+    bob.recv_limits().await?;
+    bob.recv().await?;
+    let cursor = json!({ "CursorData": { "cursors": [1], "selections": [] } });
+    for msg in [cursor, json!({ "SetLanguage": "rust" }), msg] {
+        bob.send(&msg).await;
+        assert_eq!(bob.recv().await?["Error"]["code"], "Forbidden");
+    }
+
+    expect_text(&filter, "doc?access_token=a", "hi").await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unowned() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        principal_header: Some("x-forwarded-user".into()),
+        ..ServerConfig::default()
+    });
+
+    // Anonymous documents are open to everyone, until someone claims them.
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/acl/doc")
+        .json(&json!({ "principal": "bob", "role": "viewer" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/acl/doc")
+        .header("x-forwarded-user", "alice")
+        .json(&json!({ "principal": "bob", "role": "viewer" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/doc")
+        .header("x-forwarded-user", "alice")
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = warp::test::request()
+        .path("/api/text/doc")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+    let resp = warp::test::request()
+        .path("/api/text/doc")
+        .header("x-forwarded-user", "bob")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "hello");
+
+    // Documents that were already edited anonymously can't be claimed.
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/open")
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/acl/open")
+        .header("x-forwarded-user", "carol")
+        .json(&json!({ "principal": "dave", "role": "viewer" }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+    let resp = warp::test::request()
+        .path("/api/text/open")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_acl_persisted() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(MemoryStore::new());
+    let config = || ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..config()
+    };
+    let filter = server(config());
+    let resp = request("PUT", "text/doc", "a")
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(grant(&filter, "a", "bob", Some("viewer")).await, 204);
+
+    // Let the persister start before skipping ahead.
+    time::sleep(Duration::from_millis(50)).await;
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+
+    let filter = server(config());
+    assert_eq!(status(&filter, "GET", "text/doc", "b").await, 200);
+    assert_eq!(status(&filter, "GET", "text/doc", "c").await, 403);
+
+    // Listing and content search skip documents that the user cannot view.
+    for path in ["documents", "documents?q=hello"] {
+        let resp = request("GET", path, "c").reply(&filter).await;
+        let body: Value = serde_json::from_slice(resp.body())?;
+        assert_eq!(body["total"], 0);
+    }
+    let resp = request("GET", "documents?q=hello", "b")
+        .reply(&filter)
+        .await;
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["total"], 1);
+
+    assert_eq!(status(&filter, "DELETE", "document/doc", "a").await, 204);
+    assert_eq!(store.load_acl("doc").await?, PersistedAcl::default());
+    Ok(())
+}
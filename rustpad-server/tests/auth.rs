@@ -0,0 +1,138 @@
+//! Tests for authentication with bearer tokens.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use common::*;
+use hmac::{Hmac, Mac};
+use rustpad_server::{
+    auth::{Authenticator, Jwt, StaticTokens, User},
+    server, ServerConfig,
+};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+pub mod common;
+
+/// Sign a JSON Web Token with HS256, or with another algorithm in the header.
+fn sign(secret: &[u8], alg: &str, claims: &Value) -> String {
+    let header = URL_SAFE_NO_PAD.encode(json!({ "alg": alg, "typ": "JWT" }).to_string());
+    let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+    let message = format!("{}.{}", header, payload);
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("valid key");
+    mac.update(message.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    format!("{}.{}", message, signature)
+}
+
+#[test]
+fn test_jwt() {
+    let jwt = Jwt::new(b"secret");
+    let user = |name: &str, hue| {
+        Some(User {
+            name: name.into(),
+            hue,
+        })
+    };
+
+    let token = sign(b"secret", "HS256", &json!({ "sub": "alice" }));
+    assert_eq!(jwt.authenticate(&token), user("alice", None));
+    let token = sign(
+        b"secret",
+        "HS256",
+        &json!({ "sub": "a", "name": "Alice", "hue": 9 }),
+    );
+    assert_eq!(jwt.authenticate(&token), user("Alice", Some(9)));
+
+    let token = sign(b"secret", "HS256", &json!({ "name": "Old", "exp": 1 }));
+    assert_eq!(jwt.authenticate(&token), None);
+    let token = sign(
+        b"secret",
+        "HS256",
+        &json!({ "name": "New", "nbf": u64::MAX }),
+    );
+    assert_eq!(jwt.authenticate(&token), None);
+    let token = sign(b"secret", "HS256", &json!({ "hue": 9 }));
+    assert_eq!(jwt.authenticate(&token), None);
+    let token = sign(b"other", "HS256", &json!({ "name": "Eve" }));
+    assert_eq!(jwt.authenticate(&token), None);
+    let token = sign(b"secret", "none", &json!({ "name": "Eve" }));
+    assert_eq!(jwt.authenticate(&token), None);
+    assert_eq!(jwt.authenticate("not.a.token"), None);
+}
+
+#[tokio::test]
+async fn test_static_tokens() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let alice = User {
+        name: "Alice".into(),
+        hue: None,
+    };
+    let filter = server(ServerConfig {
+        auth: Some(Arc::new(StaticTokens::new([("t0ken".into(), alice)]))),
+        ..ServerConfig::default()
+    });
+
+    let resp = warp::test::request()
+        .path("/api/text/a")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers()["www-authenticate"], "Bearer");
+    let resp = warp::test::request()
+        .path("/api/stats")
+        .header("authorization", "Bearer wrong")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 401);
+    let resp = warp::test::request()
+        .path("/api/text/a")
+        .header("authorization", "Bearer t0ken")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let resp = warp::test::request()
+        .path("/api/text/a?access_token=t0ken")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    // Names come from the token, but clients still pick their own hue.
+    assert!(connect(&filter, "a").await.is_err());
+    let mut client = connect(&filter, "a?access_token=t0ken").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    let info = json!({ "name": "Mallory", "hue": 5 });
+    client.send(&json!({ "ClientInfo": info })).await;
+    let info = json!({ "name": "Alice", "hue": 5 });
+    assert_eq!(
+        client.recv().await?,
+        json!({ "UserInfo": { "id": 0, "info": info } })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_jwt_socket() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        auth: Some(Arc::new(Jwt::new(b"secret"))),
+        ..ServerConfig::default()
+    });
+
+    let token = sign(b"secret", "HS256", &json!({ "name": "Bob", "hue": 96 }));
+    let mut client = connect(&filter, &format!("b?access_token={}", token)).await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    let info = json!({ "name": "Mallory", "hue": 5 });
+    client.send(&json!({ "ClientInfo": info })).await;
+    let info = json!({ "name": "Bob", "hue": 96 });
+    assert_eq!(
+        client.recv().await?,
+        json!({ "UserInfo": { "id": 0, "info": info } })
+    );
+
+    Ok(())
+}
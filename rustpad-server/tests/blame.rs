@@ -0,0 +1,171 @@
+//! Tests for attributing text to the users who wrote it.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{
+    database::PersistedDocument,
+    server,
+    store::{DocumentStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::{json, Value};
+use tokio::time;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Fetch the blame route, returning the parsed JSON body.
+async fn blame(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str) -> Result<Value> {
+    let resp = warp::test::request()
+        .path(&format!("/api/blame/{}", id))
+        .reply(filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    Ok(serde_json::from_slice(resp.body())?)
+}
+
+#[tokio::test]
+async fn test_blame() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+    assert_eq!(blame(&filter, "blame").await?, json!([]));
+
+    let alice = json!({ "name": "Alice", "hue": 42 });
+    let mut client = connect(&filter, "blame").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    client.send(&json!({ "ClientInfo": alice })).await;
+    client.recv().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello world");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+
+    let mut client2 = connect(&filter, "blame").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_limits().await?;
+    for _ in 0..2 {
+        client2.recv().await?;
+    }
+
+    // Bob replaces "world" with "🦀 crab", so lengths are in code points.
+    let mut operation = OperationSeq::default();
+    operation.retain(6);
+    operation.delete(5);
+    operation.insert("🦀 crab");
+    let msg = json!({ "Edit": { "revision": 1, "operation": operation } });
+    client2.send(&msg).await;
+    client2.recv().await?;
+    client2.recv_ack().await?;
+    expect_text(&filter, "blame", "hello 🦀 crab").await;
+
+    // Users stay attributed after they leave.
+    drop(client);
+    let msg = client2.recv().await?;
+    assert_eq!(msg, json!({ "UserInfo": { "id": 0, "info": null } }));
+    let mut operation = OperationSeq::default();
+    operation.retain(2);
+    operation.delete(2);
+    operation.retain(8);
+    let msg = json!({ "Edit": { "revision": 2, "operation": operation } });
+    client2.send(&msg).await;
+    client2.recv().await?;
+    client2.recv_ack().await?;
+    expect_text(&filter, "blame", "heo 🦀 crab").await;
+
+    assert_eq!(
+        blame(&filter, "blame").await?,
+        json!([
+            { "start": 0, "end": 4, "id": 0, "info": alice },
+            { "start": 4, "end": 10, "id": 1, "info": null },
+        ])
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_blame_persisted() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(MemoryStore::new());
+    let document = PersistedDocument {
+        text: "restored".into(),
+        language: None,
+        password: None,
+        authors: Default::default(),
+    };
+    store.store("persisted", &document).await?;
+    let filter = server(ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..ServerConfig::default()
+    });
+
+    // Text without a stored history has no known author.
+    assert_eq!(
+        blame(&filter, "persisted").await?,
+        json!([{ "start": 0, "end": 8, "id": null, "info": null }])
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_authors_persisted() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(MemoryStore::new());
+    let config = || ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..ServerConfig::default()
+    };
+    let filter = server(config());
+
+    let alice = json!({ "name": "Alice", "hue": 42 });
+    let mut client = connect(&filter, "authors").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    client.send(&json!({ "ClientInfo": alice })).await;
+    client.recv().await?;
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+
+    // Users who never edit are not recorded as authors.
+    let mut viewer = connect(&filter, "authors").await?;
+    assert_eq!(viewer.recv().await?, json!({ "Identity": 1 }));
+    viewer.recv_limits().await?;
+    let bob = json!({ "name": "Bob", "hue": 7 });
+    viewer.send(&json!({ "ClientInfo": bob })).await;
+    client.recv().await?;
+
+    // Authors change their information after editing.
+    let alice = json!({ "name": "Alice B.", "hue": 42 });
+    client.send(&json!({ "ClientInfo": alice })).await;
+    client.recv().await?;
+
+    // Let the document be persisted.
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+    let document = store.load("authors").await?;
+    let authors: Vec<_> = document.authors.values().map(|a| &a.name).collect();
+    assert_eq!(authors, ["Alice B."]);
+
+    // Authorship survives a restart.
+    let filter = server(config());
+    assert_eq!(
+        blame(&filter, "authors").await?,
+        json!([{ "start": 0, "end": 5, "id": 0, "info": alice }])
+    );
+
+    Ok(())
+}
@@ -24,6 +24,7 @@ async fn test_cleanup() -> Result<()> {
     let mut client = connect(&filter, "old").await?;
     let msg = client.recv().await?;
     assert_eq!(msg, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("hello");
@@ -0,0 +1,155 @@
+//! Tests for clusters of servers, which forward requests to document owners.
+
+use anyhow::{bail, Context, Result};
+use futures::prelude::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{cluster::Cluster, server, ServerConfig, PROTOCOL_VERSION};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// Secret shared by the nodes of test clusters.
+const SECRET: &str = "cluster-secret";
+
+/// Start a server for each listener as a node of a cluster, which may also
+/// include nodes that are not running. Returns the URLs of the started nodes.
+async fn start_cluster(listeners: Vec<TcpListener>, stopped: &[String]) -> Result<Vec<String>> {
+    let mut nodes = Vec::new();
+    for listener in &listeners {
+        nodes.push(format!("http://{}", listener.local_addr()?));
+    }
+    let all_nodes = [&nodes[..], stopped].concat();
+    for (listener, node) in listeners.into_iter().zip(&nodes) {
+        let filter = server(ServerConfig {
+            cluster: Some(Cluster::new(node, &all_nodes, SECRET)),
+            ..ServerConfig::default()
+        });
+        tokio::spawn(warp::serve(filter).run_incoming(TcpListenerStream::new(listener)));
+    }
+    Ok(nodes)
+}
+
+/// Find a document ID owned by a node, as seen from another node.
+fn owned_by(cluster: &Cluster, owner: Option<&str>) -> String {
+    (0..)
+        .map(|i| format!("doc{}", i))
+        .find(|id| cluster.owner(id) == owner)
+        .expect("some document should be owned by every node")
+}
+
+/// Receive a JSON message from a WebSocket.
+async fn recv(
+    socket: &mut (impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin),
+) -> Result<Value> {
+    match socket.next().await.context("socket closed")?? {
+        Message::Text(text) => Ok(serde_json::from_str(&text)?),
+        message => bail!("unexpected message {:?}", message),
+    }
+}
+
+#[tokio::test]
+async fn test_forward_to_owner() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let listeners = vec![
+        TcpListener::bind("127.0.0.1:0").await?,
+        TcpListener::bind("127.0.0.1:0").await?,
+    ];
+    let nodes = start_cluster(listeners, &[]).await?;
+    let cluster = Cluster::new(&nodes[0], &nodes, SECRET);
+    let id = owned_by(&cluster, Some(&nodes[1]));
+
+    // Connect to the document through the node that does not own it.
+    let url = format!(
+        "{}/api/socket/{}?version={}",
+        nodes[0].replace("http://", "ws://"),
+        id,
+        PROTOCOL_VERSION
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await?;
+    assert_eq!(recv(&mut socket).await?, json!({ "Identity": 0 }));
+    assert!(recv(&mut socket).await?.get("Epoch").is_some());
+    assert!(recv(&mut socket).await?.get("Limits").is_some());
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation, "seq": 1 } });
+    socket.send(Message::Text(msg.to_string())).await?;
+    assert!(recv(&mut socket).await?.get("History").is_some());
+    assert_eq!(
+        recv(&mut socket).await?,
+        json!({ "Ack": { "revision": 1, "seq": 1 } })
+    );
+
+    // Both nodes serve the text of the owner, which only it keeps in memory.
+    let client = reqwest::Client::new();
+    for node in &nodes {
+        let url = format!("{}/api/text/{}", node, id);
+        assert_eq!(client.get(url).send().await?.text().await?, "hello");
+    }
+    let stats: Value = client
+        .get(format!("{}/api/stats", nodes[0]))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(stats["num_documents"], 0);
+
+    // Request bodies are forwarded, and edits reach the relayed socket.
+    let url = format!("{}/api/text/{}", nodes[0], id);
+    let resp = client.put(url).body("hello world").send().await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.json::<Value>().await?, json!({ "revision": 2 }));
+    let msg = recv(&mut socket).await?;
+    assert_eq!(msg["History"]["start"], 1);
+
+    // Documents owned by the node that receives a request are handled there.
+    let local = owned_by(&cluster, None);
+    let url = format!("{}/api/text/{}", nodes[0], local);
+    client.put(url).body("local").send().await?;
+    let stats: Value = client
+        .get(format!("{}/api/stats", nodes[0]))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(stats["num_documents"], 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forward_errors() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let stopped = vec![format!("http://{}", listener.local_addr()?)];
+    drop(listener);
+    let listeners = vec![
+        TcpListener::bind("127.0.0.1:0").await?,
+        TcpListener::bind("127.0.0.1:0").await?,
+    ];
+    let nodes = start_cluster(listeners, &stopped).await?;
+    let cluster = Cluster::new(&nodes[0], &[&nodes[..], &stopped[..]].concat(), SECRET);
+
+    // Connections refused by the owner are refused with the same response.
+    let id = owned_by(&cluster, Some(&nodes[1]));
+    let url = format!(
+        "{}/api/socket/{}?version=99",
+        nodes[0].replace("http://", "ws://"),
+        id
+    );
+    match tokio_tungstenite::connect_async(url).await {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 400),
+        result => panic!("expected an HTTP error, got {:?}", result.map(|_| ())),
+    }
+
+    // Owners that cannot be reached are reported as a bad gateway.
+    let id = owned_by(&cluster, Some(&stopped[0]));
+    let client = reqwest::Client::new();
+    let resp = client
+        .get(format!("{}/api/text/{}", nodes[0], id))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 502);
+
+    Ok(())
+}
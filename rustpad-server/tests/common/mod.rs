@@ -1,6 +1,10 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use anyhow::{anyhow, Result};
+use rustpad_server::PROTOCOL_VERSION;
 use serde_json::Value;
-use warp::{filters::BoxedFilter, test::WsClient, Reply};
+use tempfile::NamedTempFile;
+use warp::{filters::BoxedFilter, test::WsClient, ws::Message, Reply};
 
 /// A test WebSocket client that sends and receives JSON messages.
 pub struct JsonSocket(WsClient);
@@ -10,7 +14,57 @@ impl JsonSocket {
         self.0.send_text(msg.to_string()).await
     }
 
+    /// Receive a message, checking and then removing operation timestamps
+    /// from history so that it can be compared with a literal.
     pub async fn recv(&mut self) -> Result<Value> {
+        let mut msg = self.recv_raw().await?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        if let Some(operations) = msg.pointer_mut("/History/operations") {
+            for op in operations.as_array_mut().into_iter().flatten() {
+                let time = op.get("time").and_then(Value::as_u64);
+                match time {
+                    Some(time) if time <= now => {}
+                    _ => return Err(anyhow!("invalid operation timestamp in {}", op)),
+                }
+                if let Some(op) = op.as_object_mut() {
+                    op.remove("time");
+                }
+            }
+        }
+        Ok(msg)
+    }
+
+    /// Receive the epoch of the document, which follows the identity message.
+    pub async fn recv_epoch(&mut self) -> Result<String> {
+        let msg = self.recv().await?;
+        msg.get("Epoch")
+            .and_then(Value::as_str)
+            .map(String::from)
+            .ok_or_else(|| anyhow!("expected epoch, got {}", msg))
+    }
+
+    /// Receive the limits of the document, which follow the identity message
+    /// and the epoch, if any.
+    pub async fn recv_limits(&mut self) -> Result<Value> {
+        let mut msg = self.recv().await?;
+        if msg.get("Epoch").is_some() {
+            msg = self.recv().await?;
+        }
+        msg.get("Limits")
+            .cloned()
+            .ok_or_else(|| anyhow!("expected limits, got {}", msg))
+    }
+
+    /// Receive the acknowledgement of an edit, returning its revision.
+    pub async fn recv_ack(&mut self) -> Result<u64> {
+        let msg = self.recv().await?;
+        msg.pointer("/Ack/revision")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| anyhow!("expected acknowledgement, got {}", msg))
+    }
+
+    /// Receive a message exactly as it was sent by the server.
+    pub async fn recv_raw(&mut self) -> Result<Value> {
         let msg = self.0.recv().await?;
         let msg = msg.to_str().map_err(|_| anyhow!("non-string message"))?;
         Ok(serde_json::from_str(msg)?)
@@ -19,20 +73,48 @@ impl JsonSocket {
     pub async fn recv_closed(&mut self) -> Result<()> {
         self.0.recv_closed().await.map_err(|e| e.into())
     }
+
+    /// Close the connection and wait for the server to close it as well.
+    pub async fn close(&mut self) -> Result<()> {
+        self.0.send(Message::close()).await;
+        self.recv_closed().await
+    }
 }
 
 /// Connect a new test client WebSocket.
 pub async fn connect(
     filter: &BoxedFilter<(impl Reply + 'static,)>,
     id: &str,
+) -> Result<JsonSocket> {
+    connect_path(filter, &versioned(format!("/api/socket/{}", id))).await
+}
+
+/// Connect a new read-only test client WebSocket, with a share token.
+pub async fn connect_readonly(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    token: &str,
+) -> Result<JsonSocket> {
+    connect_path(filter, &versioned(format!("/api/readonly/{}", token))).await
+}
+
+/// Connect a new test client WebSocket to a path, as is.
+pub async fn connect_path(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    path: &str,
 ) -> Result<JsonSocket> {
     let client = warp::test::ws()
-        .path(&format!("/api/socket/{}", id))
+        .path(path)
         .handshake(filter.clone())
         .await?;
     Ok(JsonSocket(client))
 }
 
+/// Ask for the latest protocol version in a socket path.
+fn versioned(path: String) -> String {
+    let separator = if path.contains('?') { '&' } else { '?' };
+    format!("{}{}version={}", path, separator, PROTOCOL_VERSION)
+}
+
 /// Check the text route.
 pub async fn expect_text(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str, text: &str) {
     let resp = warp::test::request()
@@ -42,3 +124,15 @@ pub async fn expect_text(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str
     assert_eq!(resp.status(), 200);
     assert_eq!(resp.body(), text);
 }
+
+/// Returns a connection URI for a new, temporary SQLite database.
+pub fn temp_sqlite_uri() -> Result<String> {
+    Ok(format!(
+        "sqlite://{}",
+        NamedTempFile::new()?
+            .into_temp_path()
+            .as_os_str()
+            .to_str()
+            .expect("failed to get name of tempfile as &str")
+    ))
+}
@@ -0,0 +1,123 @@
+//! Tests for compaction of document history into checkpoints.
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{server, ServerConfig};
+use serde_json::json;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_compaction() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_history: 2,
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "compact").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let epoch = client.recv_epoch().await?;
+    client.recv_limits().await?;
+
+    for (revision, letter) in ["a", "b", "c", "d"].into_iter().enumerate() {
+        let mut operation = OperationSeq::default();
+        operation.retain(revision as u64);
+        operation.insert(letter);
+        let msg = json!({ "Edit": { "revision": revision, "operation": operation } });
+        client.send(&msg).await;
+        client.recv().await?;
+        client.recv_ack().await?;
+    }
+    expect_text(&filter, "compact", "abcd").await;
+
+    // New clients receive a snapshot of the latest text.
+    let mut client2 = connect(&filter, "compact").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_limits().await?;
+    assert_eq!(
+        client2.recv().await?,
+        json!({ "Snapshot": { "revision": 4, "text": "abcd" } })
+    );
+    client2.close().await?;
+
+    // Reconnecting clients only receive the history they missed, or a
+    // snapshot if it was compacted.
+    let path = format!("compact?revision=3&epoch={}", epoch);
+    let mut client2 = connect(&filter, &path).await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 2 }));
+    client2.recv_limits().await?;
+    assert_eq!(
+        client2.recv().await?,
+        json!({
+            "History": {
+                "start": 3,
+                "operations": [{ "id": 0, "operation": [3, "d"] }]
+            }
+        })
+    );
+    client2.close().await?;
+    let path = format!("compact?revision=1&epoch={}", epoch);
+    let mut client2 = connect(&filter, &path).await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 3 }));
+    client2.recv_limits().await?;
+    assert!(client2.recv().await?.get("Snapshot").is_some());
+    client2.close().await?;
+    for id in 1..=3 {
+        let msg = client.recv().await?;
+        assert_eq!(msg, json!({ "UserInfo": { "id": id, "info": null } }));
+    }
+
+    // Clients of older protocol versions receive the checkpoint, followed by
+    // the recent history.
+    let mut client2 = connect_path(&filter, "/api/socket/compact?version=2").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 4 }));
+    client2.recv_limits().await?;
+    assert_eq!(
+        client2.recv().await?,
+        json!({ "Checkpoint": { "revision": 2, "text": "ab" } })
+    );
+    assert_eq!(
+        client2.recv().await?,
+        json!({
+            "History": {
+                "start": 2,
+                "operations": [
+                    { "id": 0, "operation": [2, "c"] },
+                    { "id": 0, "operation": [3, "d"] }
+                ]
+            }
+        })
+    );
+
+    // Edits based on a compacted revision can't be transformed.
+    let mut operation = OperationSeq::default();
+    operation.retain(1);
+    operation.insert("!");
+    let msg = json!({ "Edit": { "revision": 1, "operation": operation } });
+    client2.send(&msg).await;
+    assert_eq!(client2.recv().await?, json!("Resync"));
+    client2.recv_closed().await?;
+    let msg = client.recv().await?;
+    assert_eq!(msg, json!({ "UserInfo": { "id": 4, "info": null } }));
+
+    // Clients of version 1 don't know about checkpoints, so they are refused.
+    let mut client2 = connect_path(&filter, "/api/socket/compact?version=1").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 5 }));
+    client2.recv_closed().await?;
+    let msg = client.recv().await?;
+    assert_eq!(msg, json!({ "UserInfo": { "id": 5, "info": null } }));
+
+    // Edits based on recent revisions are still accepted.
+    let mut operation = OperationSeq::default();
+    operation.retain(2);
+    operation.insert("!");
+    let msg = json!({ "Edit": { "revision": 2, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+    expect_text(&filter, "compact", "ab!cd").await;
+
+    Ok(())
+}
@@ -0,0 +1,196 @@
+//! Tests for deleting documents.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{
+    database::{PersistedAcl, PersistedDocument, PersistedOperation, PersistedSnapshot},
+    server,
+    store::{DocumentStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::json;
+use tokio::{sync::Notify, time};
+
+pub mod common;
+
+#[tokio::test]
+async fn test_delete() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "doomed").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.insert("goodbye");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path("/api/document/doomed")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+
+    // Connected clients are told before they are disconnected.
+    assert_eq!(client.recv().await?, json!("Deleted"));
+    client.recv_closed().await?;
+    expect_text(&filter, "doomed", "").await;
+
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path("/api/document/doomed")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_persisted() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(MemoryStore::new());
+    let document = PersistedDocument {
+        text: "stored".into(),
+        language: None,
+        password: None,
+        authors: Default::default(),
+    };
+    store.store("stored", &document).await?;
+    let filter = server(ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..ServerConfig::default()
+    });
+    expect_text(&filter, "stored", "stored").await;
+
+    // Documents that are only in storage can be deleted too.
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path("/api/document/stored")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+    assert!(store.load("stored").await.is_err());
+    expect_text(&filter, "stored", "").await;
+
+    Ok(())
+}
+
+/// A store that takes a while to write history, signalling when it starts.
+#[derive(Debug, Default)]
+struct SlowStore {
+    inner: MemoryStore,
+    writing: Notify,
+}
+
+#[async_trait]
+impl DocumentStore for SlowStore {
+    async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
+        self.inner.load(document_id).await
+    }
+
+    async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        self.inner.store(document_id, document).await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        self.inner.count().await
+    }
+
+    async fn load_operations(&self, document_id: &str) -> Result<Vec<PersistedOperation>> {
+        self.inner.load_operations(document_id).await
+    }
+
+    async fn store_operations(
+        &self,
+        document_id: &str,
+        start: usize,
+        operations: &[PersistedOperation],
+    ) -> Result<()> {
+        self.writing.notify_one();
+        time::sleep(Duration::from_millis(200)).await;
+        self.inner
+            .store_operations(document_id, start, operations)
+            .await
+    }
+
+    async fn load_snapshots(&self, document_id: &str) -> Result<Vec<PersistedSnapshot>> {
+        self.inner.load_snapshots(document_id).await
+    }
+
+    async fn store_snapshots(
+        &self,
+        document_id: &str,
+        start: usize,
+        snapshots: &[PersistedSnapshot],
+    ) -> Result<()> {
+        self.inner
+            .store_snapshots(document_id, start, snapshots)
+            .await
+    }
+
+    async fn load_acl(&self, document_id: &str) -> Result<PersistedAcl> {
+        self.inner.load_acl(document_id).await
+    }
+
+    async fn store_acl(&self, document_id: &str, acl: &PersistedAcl) -> Result<()> {
+        self.inner.store_acl(document_id, acl).await
+    }
+
+    async fn delete(&self, document_id: &str) -> Result<bool> {
+        self.inner.delete(document_id).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.inner.list().await
+    }
+}
+
+#[tokio::test]
+async fn test_delete_while_persisting() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(SlowStore::default());
+    let filter = server(ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "doomed").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    let mut operation = OperationSeq::default();
+    operation.insert("goodbye");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+
+    // Delete the document while its history is being written.
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    store.writing.notified().await;
+    let resp = warp::test::request()
+        .method("DELETE")
+        .path("/api/document/doomed")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 204);
+
+    // The persist in progress does not bring the document back.
+    time::sleep(Duration::from_millis(500)).await;
+    assert!(store.load("doomed").await.is_err());
+    assert!(store.load_operations("doomed").await?.is_empty());
+    expect_text(&filter, "doomed", "").await;
+
+    Ok(())
+}
@@ -0,0 +1,173 @@
+//! Tests for listing and searching documents.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use common::*;
+use rustpad_server::{
+    database::PersistedDocument,
+    server,
+    store::{DocumentStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// List documents with a query string, returning the IDs and total count.
+async fn list(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    query: &str,
+) -> Result<(Vec<String>, u64)> {
+    let resp = warp::test::request()
+        .path(&format!("/api/documents?{}", query))
+        .header("x-forwarded-user", "alice")
+        .reply(filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    let ids = body["documents"]
+        .as_array()
+        .expect("documents should be an array")
+        .iter()
+        .map(|info| info["id"].as_str().unwrap_or_default().to_owned())
+        .collect();
+    Ok((ids, body["total"].as_u64().unwrap_or_default()))
+}
+
+#[tokio::test]
+async fn test_list_documents() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(MemoryStore::new());
+    for (id, text) in [("notes-a", "Meeting Notes"), ("notes-b", "todo list")] {
+        let document = PersistedDocument {
+            text: text.into(),
+            language: Some("markdown".into()),
+            password: None,
+            authors: Default::default(),
+        };
+        store.store(id, &document).await?;
+    }
+    let filter = server(ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        principal_header: Some("x-forwarded-user".into()),
+        ..ServerConfig::default()
+    });
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/live")
+        .body("fresh notes")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let mut client = connect(&filter, "live").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+    client.recv_limits().await?;
+    client.recv().await?;
+
+    // Only authenticated users can list documents.
+    let resp = warp::test::request()
+        .path("/api/documents")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 403);
+
+    let resp = warp::test::request()
+        .path("/api/documents")
+        .header("x-forwarded-user", "alice")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["total"], 3);
+    let live = &body["documents"][0];
+    assert_eq!(live["id"], "live");
+    assert_eq!(live["size"], 11);
+    assert_eq!(live["revision"], 1);
+    assert_eq!(live["users"], 1);
+    assert!(live["last_accessed"].as_u64().is_some());
+    assert_eq!(
+        body["documents"][1],
+        json!({
+            "id": "notes-a",
+            "size": 13,
+            "language": "markdown",
+            "revision": 1,
+            "users": 0,
+            "protected": false,
+            "last_accessed": null
+        })
+    );
+
+    let ids = |ids: &[&str]| ids.iter().map(|&id| id.to_owned()).collect::<Vec<_>>();
+    assert_eq!(
+        list(&filter, "prefix=notes").await?,
+        (ids(&["notes-a", "notes-b"]), 2)
+    );
+    assert_eq!(list(&filter, "id=-b").await?, (ids(&["notes-b"]), 1));
+    assert_eq!(
+        list(&filter, "q=NOTES").await?,
+        (ids(&["live", "notes-a"]), 2)
+    );
+    assert_eq!(
+        list(&filter, "q=notes&prefix=n").await?,
+        (ids(&["notes-a"]), 1)
+    );
+    assert_eq!(
+        list(&filter, "limit=1&offset=1").await?,
+        (ids(&["notes-a"]), 3)
+    );
+    assert_eq!(list(&filter, "offset=5").await?, (ids(&[]), 3));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_pages() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(MemoryStore::new());
+    for id in ["a", "c", "e", "g"] {
+        let document = PersistedDocument {
+            text: format!("stored {} text", id),
+            language: None,
+            password: None,
+            authors: Default::default(),
+        };
+        store.store(id, &document).await?;
+    }
+    let filter = server(ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        principal_header: Some("x-forwarded-user".into()),
+        ..ServerConfig::default()
+    });
+    // Documents in memory, some of which are also stored.
+    for id in ["b", "c", "d", "f"] {
+        let resp = warp::test::request()
+            .method("PUT")
+            .path(&format!("/api/text/{}", id))
+            .body(format!("memory {} TEXT", id))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    // Pages interleave documents in memory with stored ones.
+    let all: Vec<String> = "abcdefg".chars().map(String::from).collect();
+    for offset in 0..9 {
+        for limit in 1..4 {
+            let expected: Vec<_> = all.iter().skip(offset).take(limit).cloned().collect();
+            let query = format!("offset={}&limit={}", offset, limit);
+            assert_eq!(list(&filter, &query).await?, (expected, 7), "{}", query);
+        }
+    }
+
+    // Text is matched the same way in memory and in storage.
+    let ids = |ids: &[&str]| ids.iter().map(|&id| id.to_owned()).collect::<Vec<_>>();
+    assert_eq!(list(&filter, "q=d%20text").await?, (ids(&["d"]), 1));
+    assert_eq!(list(&filter, "q=e%20TEXT").await?, (ids(&["e"]), 1));
+    assert_eq!(list(&filter, "q=c%20text").await?, (ids(&["c"]), 1));
+
+    Ok(())
+}
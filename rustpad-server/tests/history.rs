@@ -0,0 +1,165 @@
+//! Tests for reading documents at past revisions and times.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{
+    server,
+    store::{DocumentStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::json;
+use tokio::time;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Fetch the text route with a query string, returning the status and body.
+async fn text_query(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+    query: &str,
+) -> (u16, String) {
+    let resp = warp::test::request()
+        .path(&format!("/api/text/{}?{}", id, query))
+        .reply(filter)
+        .await;
+    let body = String::from_utf8_lossy(resp.body()).into_owned();
+    (resp.status().as_u16(), body)
+}
+
+#[tokio::test]
+async fn test_text_at_revision() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "travel").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+
+    let mut times = Vec::new();
+    for (revision, letter) in ["a", "b", "c"].into_iter().enumerate() {
+        let mut operation = OperationSeq::default();
+        operation.retain(revision as u64);
+        operation.insert(letter);
+        let msg = json!({ "Edit": { "revision": revision, "operation": operation } });
+        client.send(&msg).await;
+        let msg = client.recv_raw().await?;
+        let time = msg["History"]["operations"][0]["time"].as_u64();
+        times.push(time.expect("history should include timestamps"));
+        client.recv_ack().await?;
+        time::sleep(Duration::from_millis(5)).await;
+    }
+    expect_text(&filter, "travel", "abc").await;
+
+    assert_eq!(
+        text_query(&filter, "travel", "revision=0").await,
+        (200, "".into())
+    );
+    assert_eq!(
+        text_query(&filter, "travel", "revision=2").await,
+        (200, "ab".into())
+    );
+    assert_eq!(
+        text_query(&filter, "travel", "revision=3").await,
+        (200, "abc".into())
+    );
+    assert_eq!(text_query(&filter, "travel", "revision=4").await.0, 404);
+    assert_eq!(text_query(&filter, "travel", "revision=x").await.0, 400);
+
+    let query = format!("time={}", times[0] - 1);
+    assert_eq!(
+        text_query(&filter, "travel", &query).await,
+        (200, "".into())
+    );
+    let query = format!("time={}", times[1]);
+    assert_eq!(
+        text_query(&filter, "travel", &query).await,
+        (200, "ab".into())
+    );
+    let query = format!("time={}", u64::MAX);
+    assert_eq!(
+        text_query(&filter, "travel", &query).await,
+        (200, "abc".into())
+    );
+
+    Ok(())
+}
+
+/// Append a letter to the end of the document, waiting for the broadcast.
+async fn append(client: &mut JsonSocket, revision: usize, letter: &str) -> Result<()> {
+    let mut operation = OperationSeq::default();
+    operation.retain(revision as u64);
+    operation.insert(letter);
+    let msg = json!({ "Edit": { "revision": revision, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_text_at_compacted_revision() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_history: 1,
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "compacted").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    for (revision, letter) in ["a", "b", "c"].into_iter().enumerate() {
+        append(&mut client, revision, letter).await?;
+    }
+    expect_text(&filter, "compacted", "abc").await;
+
+    // Without storage, compacted history is gone for good.
+    assert_eq!(text_query(&filter, "compacted", "revision=1").await.0, 404);
+    assert_eq!(text_query(&filter, "compacted", "time=0").await.0, 404);
+    assert_eq!(
+        text_query(&filter, "compacted", "revision=2").await,
+        (200, "ab".into())
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_text_at_persisted_revision() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(MemoryStore::new());
+    let filter = server(ServerConfig {
+        max_history: 1,
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "persisted").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    append(&mut client, 0, "a").await?;
+    append(&mut client, 1, "b").await?;
+
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(store.load_operations("persisted").await?.len(), 2);
+
+    // Compacted history is replayed from the stored operations.
+    append(&mut client, 2, "c").await?;
+    expect_text(&filter, "persisted", "abc").await;
+    assert_eq!(
+        text_query(&filter, "persisted", "revision=1").await,
+        (200, "a".into())
+    );
+    assert_eq!(
+        text_query(&filter, "persisted", "time=0").await,
+        (200, "".into())
+    );
+
+    Ok(())
+}
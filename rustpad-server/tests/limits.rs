@@ -0,0 +1,249 @@
+//! Tests for rate limits on WebSocket clients and limits on documents.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{limit::RateLimits, server, ServerConfig};
+use serde_json::{json, Value};
+use tokio::time;
+
+pub mod common;
+
+/// An edit appending text to a document of some length.
+fn append(revision: usize, len: u64, text: &str) -> Value {
+    let mut operation = OperationSeq::default();
+    operation.retain(len);
+    operation.insert(text);
+    json!({ "Edit": { "revision": revision, "operation": operation } })
+}
+
+#[tokio::test]
+async fn test_edit_rate() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        rate_limits: RateLimits {
+            edits_per_second: Some(2),
+            ..RateLimits::default()
+        },
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    client.send(&append(0, 0, "a")).await;
+    client.recv().await?;
+    assert_eq!(client.recv_ack().await?, 1);
+    client.send(&append(1, 1, "b")).await;
+    client.recv().await?;
+    assert_eq!(client.recv_ack().await?, 2);
+    client.send(&append(2, 2, "c")).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "RateLimited");
+    client.recv_closed().await?;
+
+    // Other connections have their own budget.
+    let mut client = connect(&filter, "foobar").await?;
+    client.recv().await?;
+    client.recv_limits().await?;
+    client.recv().await?;
+    client.send(&append(2, 2, "c")).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+    expect_text(&filter, "foobar", "abc").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_inserted_bytes() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        rate_limits: RateLimits {
+            inserted_bytes_per_minute: Some(10),
+            ..RateLimits::default()
+        },
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    client.send(&append(0, 0, "hello")).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+    client.send(&append(1, 5, "world!")).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "RateLimited");
+    client.recv_closed().await?;
+    expect_text(&filter, "foobar", "hello").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_address_limits() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        rate_limits: RateLimits {
+            ip_edits_per_second: Some(2),
+            ip_sockets_per_document: Some(2),
+            ..RateLimits::default()
+        },
+        ..ServerConfig::default()
+    });
+
+    // Test clients have no address, so they are all limited together.
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    let mut client2 = connect(&filter, "foobar").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_limits().await?;
+
+    let mut client3 = connect(&filter, "foobar").await?;
+    let msg = client3.recv().await?;
+    assert_eq!(msg["Error"]["code"], "TooManyConnections");
+    client3.recv_closed().await?;
+    let left = json!({ "UserInfo": { "id": 2, "info": null } });
+    assert_eq!(client.recv().await?, left);
+    assert_eq!(client2.recv().await?, left);
+    let mut other = connect(&filter, "other").await?;
+    assert_eq!(other.recv().await?, json!({ "Identity": 0 }));
+    other.recv_limits().await?;
+
+    client.send(&append(0, 0, "a")).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+    client2.recv().await?;
+    client2.send(&append(1, 1, "b")).await;
+    client.recv().await?;
+    client2.recv().await?;
+    client2.recv_ack().await?;
+    client.send(&append(2, 2, "c")).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "RateLimited");
+    client.recv_closed().await?;
+
+    // Sockets no longer count towards the limit once they are closed.
+    assert_eq!(client2.recv().await?["UserInfo"]["info"], Value::Null);
+    let mut client3 = connect(&filter, "foobar").await?;
+    assert_eq!(client3.recv().await?, json!({ "Identity": 3 }));
+    client3.recv_limits().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_address_reconnect() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        rate_limits: RateLimits {
+            ip_inserted_bytes_per_minute: Some(5),
+            ..RateLimits::default()
+        },
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    client.send(&append(0, 0, "hello")).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+    client.send(&append(1, 5, "!")).await;
+    assert_eq!(client.recv().await?["Error"]["code"], "RateLimited");
+    client.recv_closed().await?;
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The address keeps its budget after all of its sockets are closed.
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+    client.recv_limits().await?;
+    client.recv().await?;
+    client.send(&append(1, 5, "!")).await;
+    assert_eq!(client.recv().await?["Error"]["code"], "RateLimited");
+    client.recv_closed().await?;
+    expect_text(&filter, "foobar", "hello").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_size() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_document_size: 5,
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let limits = json!({ "max_document_size": 5, "max_history": 1000, "max_users": null });
+    assert_eq!(client.recv_limits().await?, limits);
+    client.send(&append(0, 0, "hello")).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+    client.send(&append(1, 5, "!")).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "TooLarge");
+    client.recv_closed().await?;
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/foobar")
+        .body("hello world")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 413);
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/foobar")
+        .body("hey")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    expect_text(&filter, "foobar", "hey").await;
+
+    let resp = warp::test::request()
+        .path("/api/stats")
+        .reply(&filter)
+        .await;
+    let stats: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(stats["limits"], limits);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_users() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        max_users: Some(1),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    assert_eq!(client.recv_limits().await?["max_users"], 1);
+
+    let mut client2 = connect(&filter, "foobar").await?;
+    let msg = client2.recv().await?;
+    assert_eq!(msg["Error"]["code"], "DocumentFull");
+    assert_eq!(msg["Error"]["fatal"], true);
+    client2.recv_closed().await?;
+    let left = json!({ "UserInfo": { "id": 1, "info": null } });
+    assert_eq!(client.recv().await?, left);
+
+    // Other documents have their own users.
+    let mut other = connect(&filter, "other").await?;
+    assert_eq!(other.recv().await?, json!({ "Identity": 0 }));
+
+    client.close().await?;
+    let mut client3 = connect(&filter, "foobar").await?;
+    assert_eq!(client3.recv().await?, json!({ "Identity": 2 }));
+
+    Ok(())
+}
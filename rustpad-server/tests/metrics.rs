@@ -0,0 +1,94 @@
+//! Tests for the Prometheus metrics endpoint.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{
+    server,
+    store::{DocumentStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::json;
+use tokio::time;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Fetch the metrics of a server, in the Prometheus text format.
+async fn metrics(filter: &BoxedFilter<(impl Reply + 'static,)>) -> String {
+    let resp = warp::test::request().path("/metrics").reply(filter).await;
+    assert_eq!(resp.status(), 200);
+    String::from_utf8(resp.body().to_vec()).expect("metrics should be UTF-8")
+}
+
+/// Assert that the metrics contain a sample with the given value.
+fn assert_sample(metrics: &str, sample: &str) {
+    assert!(
+        metrics.lines().any(|line| line == sample),
+        "expected {:?} in metrics:\n{}",
+        sample,
+        metrics
+    );
+}
+
+#[tokio::test]
+async fn test_metrics() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store: Arc<dyn DocumentStore> = Arc::new(MemoryStore::new());
+    let filter = server(ServerConfig {
+        database: Some(store),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "measured").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+
+    // This edit is concurrent with the first one, so it is transformed.
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+
+    let text = metrics(&filter).await;
+    assert_sample(&text, "rustpad_documents 1");
+    assert_sample(&text, "rustpad_sockets 1");
+    assert_sample(&text, "rustpad_edits_total 2");
+    assert_sample(&text, "rustpad_transforms_total 1");
+    assert_sample(&text, "rustpad_apply_edit_seconds_count 2");
+    assert!(text.contains("rustpad_database_seconds_count{operation=\"load\"}"));
+
+    // Rejected edits are counted by reason.
+    let msg = json!({ "Edit": { "revision": 10, "operation": operation } });
+    client.send(&msg).await;
+    assert_eq!(client.recv().await?["Error"]["code"], "InvalidRevision");
+    client.recv_closed().await?;
+    let text = metrics(&filter).await;
+    assert_sample(&text, "rustpad_edits_total 2");
+    assert_sample(
+        &text,
+        "rustpad_rejected_edits_total{reason=\"invalid_revision\"} 1",
+    );
+
+    // The persister counts how often it stores the document.
+    time::pause();
+    time::advance(Duration::from_secs(5)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+    let text = metrics(&filter).await;
+    assert_sample(&text, "rustpad_persists_total{result=\"success\"} 1");
+    assert_sample(
+        &text,
+        "rustpad_database_seconds_count{operation=\"store\"} 1",
+    );
+
+    Ok(())
+}
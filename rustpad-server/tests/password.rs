@@ -0,0 +1,306 @@
+//! Tests for password-protected documents.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use common::*;
+use rustpad_server::{
+    database::{PersistedAcl, PersistedDocument, PersistedOperation, PersistedSnapshot},
+    limit::RateLimits,
+    server,
+    store::{DocumentStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::json;
+use tokio::time;
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Set the password of a document, authenticating with the current one.
+async fn set_password(
+    filter: &BoxedFilter<(impl Reply + 'static,)>,
+    id: &str,
+    current: Option<&str>,
+    password: Option<&str>,
+) -> u16 {
+    let mut request = warp::test::request()
+        .method("PUT")
+        .path(&format!("/api/password/{}", id))
+        .json(&json!({ "password": password }));
+    if let Some(current) = current {
+        request = request.header("x-document-password", current);
+    }
+    request.reply(filter).await.status().as_u16()
+}
+
+/// Fetch the text of a document with a query string, returning the status.
+async fn text_status(filter: &BoxedFilter<(impl Reply + 'static,)>, path: &str) -> u16 {
+    let resp = warp::test::request().path(path).reply(filter).await;
+    resp.status().as_u16()
+}
+
+#[tokio::test]
+async fn test_password() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/secret")
+        .body("top secret")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        set_password(&filter, "secret", None, Some("hunter2")).await,
+        204
+    );
+
+    // Reading requires the password, from either a header or the query string.
+    assert_eq!(text_status(&filter, "/api/text/secret").await, 401);
+    assert_eq!(
+        text_status(&filter, "/api/text/secret?password=nope").await,
+        401
+    );
+    assert_eq!(
+        text_status(&filter, "/api/text/secret?password=hunter2").await,
+        200
+    );
+    let resp = warp::test::request()
+        .path("/api/text/secret?revision=0")
+        .header("x-document-password", "hunter2")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), "");
+    assert_eq!(text_status(&filter, "/api/blame/secret").await, 401);
+    assert_eq!(text_status(&filter, "/api/snapshots/secret/0").await, 401);
+
+    // Sockets are only upgraded with the password.
+    assert!(connect(&filter, "secret").await.is_err());
+    assert!(connect(&filter, "secret?password=nope").await.is_err());
+    let mut client = connect(&filter, "secret?password=hunter2").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+    client.recv_limits().await?;
+
+    // Changing the password requires the current one.
+    assert_eq!(set_password(&filter, "secret", None, None).await, 401);
+    assert_eq!(
+        set_password(&filter, "secret", Some("hunter2"), None).await,
+        204
+    );
+    expect_text(&filter, "secret", "top secret").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_password_persisted() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(MemoryStore::new());
+    let config = || ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        principal_header: Some("x-forwarded-user".into()),
+        ..ServerConfig::default()
+    };
+    let filter = server(config());
+    assert_eq!(
+        set_password(&filter, "vault", None, Some("hunter2")).await,
+        204
+    );
+
+    // Let the persister start before skipping ahead.
+    time::sleep(Duration::from_millis(50)).await;
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+
+    // Only the hash of the password is stored.
+    let hash = store.load("vault").await?.password;
+    assert!(hash.is_some_and(|hash| !hash.contains("hunter2")));
+
+    let filter = server(config());
+    assert_eq!(text_status(&filter, "/api/text/vault").await, 401);
+    assert_eq!(
+        text_status(&filter, "/api/text/vault?password=hunter2").await,
+        200
+    );
+
+    let resp = warp::test::request()
+        .path("/api/documents")
+        .header("x-forwarded-user", "alice")
+        .reply(&filter)
+        .await;
+    let body: serde_json::Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body["total"], 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_password_attempts() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        rate_limits: RateLimits {
+            ip_password_attempts_per_minute: Some(2),
+            ..RateLimits::default()
+        },
+        ..ServerConfig::default()
+    });
+    assert_eq!(
+        set_password(&filter, "secret", None, Some("hunter2")).await,
+        204
+    );
+
+    // Test clients have no address, so their attempts are all counted together.
+    let good = "/api/text/secret?password=hunter2";
+    let bad = "/api/text/secret?password=nope";
+    assert_eq!(text_status(&filter, good).await, 200);
+    assert_eq!(text_status(&filter, bad).await, 401);
+    assert_eq!(text_status(&filter, bad).await, 429);
+    assert!(connect(&filter, "secret?password=nope").await.is_err());
+
+    // Passwords that were already verified don't count.
+    assert_eq!(text_status(&filter, good).await, 200);
+    let mut client = connect(&filter, "secret?password=hunter2").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+
+    // Attempts are allowed again once the budget refills.
+    time::pause();
+    time::advance(Duration::from_secs(60)).await;
+    time::resume();
+    assert_eq!(text_status(&filter, bad).await, 401);
+
+    Ok(())
+}
+
+/// A store whose documents can't be read while it is broken.
+#[derive(Debug, Default)]
+struct BrokenStore {
+    inner: MemoryStore,
+    broken: AtomicBool,
+}
+
+#[async_trait]
+impl DocumentStore for BrokenStore {
+    async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
+        if self.broken.load(Ordering::Relaxed) {
+            bail!("storage is unavailable");
+        }
+        self.inner.load(document_id).await
+    }
+
+    async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        self.inner.store(document_id, document).await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        self.inner.count().await
+    }
+
+    async fn load_operations(&self, document_id: &str) -> Result<Vec<PersistedOperation>> {
+        self.inner.load_operations(document_id).await
+    }
+
+    async fn store_operations(
+        &self,
+        document_id: &str,
+        start: usize,
+        operations: &[PersistedOperation],
+    ) -> Result<()> {
+        self.inner
+            .store_operations(document_id, start, operations)
+            .await
+    }
+
+    async fn load_snapshots(&self, document_id: &str) -> Result<Vec<PersistedSnapshot>> {
+        self.inner.load_snapshots(document_id).await
+    }
+
+    async fn store_snapshots(
+        &self,
+        document_id: &str,
+        start: usize,
+        snapshots: &[PersistedSnapshot],
+    ) -> Result<()> {
+        self.inner
+            .store_snapshots(document_id, start, snapshots)
+            .await
+    }
+
+    async fn load_acl(&self, document_id: &str) -> Result<PersistedAcl> {
+        self.inner.load_acl(document_id).await
+    }
+
+    async fn store_acl(&self, document_id: &str, acl: &PersistedAcl) -> Result<()> {
+        self.inner.store_acl(document_id, acl).await
+    }
+
+    async fn delete(&self, document_id: &str) -> Result<bool> {
+        self.inner.delete(document_id).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.inner.list().await
+    }
+}
+
+#[tokio::test]
+async fn test_password_load_error() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(BrokenStore::default());
+    let config = || ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..ServerConfig::default()
+    };
+    let filter = server(config());
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/vault")
+        .body("top secret")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        set_password(&filter, "vault", None, Some("hunter2")).await,
+        204
+    );
+    time::sleep(Duration::from_millis(50)).await;
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+
+    let hash = store.load("vault").await?.password;
+    assert!(hash.is_some());
+
+    // Failing to read the document is not mistaken for it being unprotected.
+    store.broken.store(true, Ordering::Relaxed);
+    let filter = server(config());
+    assert_eq!(text_status(&filter, "/api/text/vault").await, 500);
+    assert_eq!(
+        text_status(&filter, "/api/text/vault?revision=1").await,
+        500
+    );
+    assert!(connect(&filter, "vault").await.is_err());
+
+    // Nothing was loaded, so the stored hash is kept.
+    store.broken.store(false, Ordering::Relaxed);
+    assert_eq!(store.load("vault").await?.password, hash);
+    assert_eq!(text_status(&filter, "/api/text/vault").await, 401);
+    assert_eq!(
+        text_status(&filter, "/api/text/vault?password=hunter2").await,
+        200
+    );
+
+    Ok(())
+}
@@ -1,36 +1,26 @@
 //! Tests to ensure that documents are persisted with SQLite.
 
-use std::time::Duration;
+use std::{str::FromStr, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use common::*;
 use operational_transform::OperationSeq;
 use rustpad_server::{
-    database::{Database, PersistedDocument},
+    database::{Database, PersistedAuthor, PersistedDocument, PersistedOperation},
     server, ServerConfig,
 };
 use serde_json::json;
-use tempfile::NamedTempFile;
+use sqlx::any::{AnyConnectOptions, AnyPoolOptions};
 use tokio::time;
 
 pub mod common;
 
-fn temp_sqlite_uri() -> Result<String> {
-    Ok(format!(
-        "sqlite://{}",
-        NamedTempFile::new()?
-            .into_temp_path()
-            .as_os_str()
-            .to_str()
-            .expect("failed to get name of tempfile as &str")
-    ))
-}
-
 #[tokio::test]
 async fn test_database() -> Result<()> {
     pretty_env_logger::try_init().ok();
 
     let database = Database::new(&temp_sqlite_uri()?).await?;
+    assert_eq!(database.count().await?, 0);
 
     assert!(database.load("hello").await.is_err());
     assert!(database.load("world").await.is_err());
@@ -38,6 +28,8 @@ async fn test_database() -> Result<()> {
     let doc1 = PersistedDocument {
         text: "Hello Text".into(),
         language: None,
+        password: None,
+        authors: Default::default(),
     };
 
     assert!(database.store("hello", &doc1).await.is_ok());
@@ -47,6 +39,15 @@ async fn test_database() -> Result<()> {
     let doc2 = PersistedDocument {
         text: "print('World Text :)')".into(),
         language: Some("python".into()),
+        password: None,
+        authors: [(
+            3,
+            PersistedAuthor {
+                name: "Ferris".into(),
+                hue: 20,
+            },
+        )]
+        .into(),
     };
 
     assert!(database.store("world", &doc2).await.is_ok());
@@ -55,6 +56,66 @@ async fn test_database() -> Result<()> {
 
     assert!(database.store("hello", &doc2).await.is_ok());
     assert_eq!(database.load("hello").await?, doc2);
+    assert_eq!(database.count().await?, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_database_migration() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    // Create tables as they were before passwords, timestamps and sessions.
+    let uri = temp_sqlite_uri()?;
+    let mut options = AnyConnectOptions::from_str(&uri)?;
+    if let Some(sqlite) = options.as_sqlite_mut() {
+        *sqlite = sqlite.clone().create_if_missing(true);
+    }
+    let pool = AnyPoolOptions::new().connect_with(options).await?;
+    sqlx::query("CREATE TABLE document (id TEXT PRIMARY KEY, text TEXT NOT NULL, language TEXT)")
+        .execute(&pool)
+        .await?;
+    sqlx::query(
+        "CREATE TABLE operation (document_id TEXT NOT NULL, revision BIGINT NOT NULL, \
+         user_id BIGINT NOT NULL, operation TEXT NOT NULL, PRIMARY KEY (document_id, revision))",
+    )
+    .execute(&pool)
+    .await?;
+    sqlx::query("INSERT INTO document VALUES ('old', 'hi', NULL)")
+        .execute(&pool)
+        .await?;
+    sqlx::query("INSERT INTO operation VALUES ('old', 0, 1, '[\"hi\"]')")
+        .execute(&pool)
+        .await?;
+    pool.close().await;
+
+    let database = Database::new(&uri).await?;
+    let mut operation = OperationSeq::default();
+    operation.insert("hi");
+    let old = PersistedOperation {
+        id: 1,
+        operation,
+        time: 0,
+        session: None,
+        seq: None,
+    };
+    assert_eq!(database.load_operations("old").await?, vec![old.clone()]);
+
+    let mut operation = OperationSeq::default();
+    operation.retain(2);
+    operation.insert("!");
+    let new = PersistedOperation {
+        id: 2,
+        operation,
+        time: 1234,
+        session: Some("abc".into()),
+        seq: Some(7),
+    };
+    database
+        .store_operations("old", 1, std::slice::from_ref(&new))
+        .await?;
+    assert_eq!(database.load_operations("old").await?, vec![old, new]);
+    assert_eq!(database.load("old").await?.password, None);
 
     Ok(())
 }
@@ -65,7 +126,8 @@ async fn test_persist() -> Result<()> {
 
     let filter = server(ServerConfig {
         expiry_days: 2,
-        database: Some(Database::new(&temp_sqlite_uri()?).await?),
+        database: Some(Arc::new(Database::new(&temp_sqlite_uri()?).await?)),
+        ..ServerConfig::default()
     });
 
     expect_text(&filter, "persist", "").await;
@@ -73,6 +135,7 @@ async fn test_persist() -> Result<()> {
     let mut client = connect(&filter, "persist").await?;
     let msg = client.recv().await?;
     assert_eq!(msg, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("hello");
@@ -104,3 +167,185 @@ async fn test_persist() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_persist_history() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let filter = server(ServerConfig {
+        expiry_days: 2,
+        database: Some(Arc::new(Database::new(&temp_sqlite_uri()?).await?)),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "history").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    let mut client2 = connect(&filter, "history").await?;
+    assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_limits().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert(" world");
+    let msg = json!({ "Edit": { "revision": 1, "operation": operation } });
+    client2.send(&msg).await;
+    client2.recv().await?;
+    client2.recv().await?;
+
+    // Expire the in-memory document, giving SQLite time to persist it first.
+    let hour = Duration::from_secs(3600);
+    time::pause();
+    time::advance(hour / 360).await;
+    time::resume();
+    time::sleep(Duration::from_millis(150)).await;
+    time::pause();
+    time::advance(50 * hour).await;
+    time::resume();
+    drop(client);
+    drop(client2);
+
+    // The full history and authorship survive, and new user IDs are fresh.
+    let mut client = connect(&filter, "history?revision=0").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 2 }));
+    client.recv_limits().await?;
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [
+                    { "id": 0, "operation": ["hello"] },
+                    { "id": 1, "operation": [5, " world"] }
+                ]
+            }
+        })
+    );
+    expect_text(&filter, "history", "hello world").await;
+
+    // Clients can continue editing from their old revision.
+    let mut operation = OperationSeq::default();
+    operation.insert("> ");
+    operation.retain(5);
+    let msg = json!({ "Edit": { "revision": 1, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    expect_text(&filter, "history", "> hello world").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resubmit_after_reload() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Arc::new(Database::new(&temp_sqlite_uri()?).await?);
+    let config = || ServerConfig {
+        database: Some(Arc::clone(&database) as _),
+        ..ServerConfig::default()
+    };
+    let filter = server(config());
+
+    let mut client = connect(&filter, "resubmit").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let edit = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation,
+            "seq": 1,
+            "session": "s3cret"
+        }
+    });
+    client.send(&edit).await;
+    client.recv().await?;
+    assert_eq!(client.recv_ack().await?, 1);
+
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The server restarts before the client sees the acknowledgement.
+    drop(client);
+    let filter = server(config());
+    let mut client = connect(&filter, "resubmit?revision=0").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+    client.recv_limits().await?;
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [{ "id": 0, "operation": ["hello"] }]
+            }
+        })
+    );
+    client.send(&edit).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg, json!({ "Ack": { "revision": 1, "seq": 1 } }));
+    expect_text(&filter, "resubmit", "hello").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reconnect_after_reload() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let database = Arc::new(Database::new(&temp_sqlite_uri()?).await?);
+    let config = || ServerConfig {
+        database: Some(Arc::clone(&database) as _),
+        ..ServerConfig::default()
+    };
+    let filter = server(config());
+
+    let mut client = connect(&filter, "epoch").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    let epoch = client.recv_epoch().await?;
+    client.recv_limits().await?;
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+
+    // The second edit is lost, since the server restarts before persisting it.
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert(" world");
+    let msg = json!({ "Edit": { "revision": 1, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+    drop(client);
+    let filter = server(config());
+
+    // Revisions from before the restart can't be trusted, so the client
+    // receives the text instead of an empty history.
+    let path = format!("epoch?revision=2&epoch={}", epoch);
+    let mut client = connect(&filter, &path).await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+    assert_ne!(client.recv_epoch().await?, epoch);
+    client.recv_limits().await?;
+    assert_eq!(
+        client.recv().await?,
+        json!({ "Snapshot": { "revision": 1, "text": "hello" } })
+    );
+
+    Ok(())
+}
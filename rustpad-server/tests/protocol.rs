@@ -0,0 +1,253 @@
+//! Tests for protocol versions, encodings and the schema of WebSocket messages.
+
+use std::path::Path;
+
+use anyhow::Result;
+use common::*;
+use jsonschema::JSONSchema;
+use operational_transform::OperationSeq;
+use rustpad_server::{protocol_schema, server, ServerConfig};
+use serde_json::{json, Value};
+use warp::{test::WsClient, ws::Message};
+
+pub mod common;
+
+/// Checked-in schema, which can be regenerated with `UPDATE_SCHEMA=1`.
+const SCHEMA_PATH: &str = "schema/protocol.json";
+
+/// Magic number at the start of every Zstandard frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Receive a binary MessagePack message, returning it and whether it was compressed.
+async fn recv_msgpack(client: &mut WsClient) -> Result<(Value, bool)> {
+    let msg = client.recv().await?;
+    assert!(msg.is_binary(), "expected a binary message, got {:?}", msg);
+    let bytes = msg.as_bytes();
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        let decompressed = zstd::stream::decode_all(bytes)?;
+        Ok((rmp_serde::from_slice(&decompressed)?, true))
+    } else {
+        Ok((rmp_serde::from_slice(bytes)?, false))
+    }
+}
+
+/// Compile the schema of one side of the protocol.
+fn compile(name: &str) -> JSONSchema {
+    let schema = protocol_schema();
+    let schema = json!({
+        "$ref": format!("#/definitions/{}", name),
+        "definitions": schema["definitions"],
+    });
+    JSONSchema::compile(&schema).expect("schema should be valid")
+}
+
+#[tokio::test]
+async fn test_schema_up_to_date() -> Result<()> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SCHEMA_PATH);
+    let schema = serde_json::to_string_pretty(&protocol_schema())? + "\n";
+    if std::env::var_os("UPDATE_SCHEMA").is_some() {
+        std::fs::write(&path, &schema)?;
+    }
+    let expected = std::fs::read_to_string(&path)?;
+    assert!(
+        schema == expected,
+        "{} is out of date, run tests with UPDATE_SCHEMA=1",
+        SCHEMA_PATH
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_messages_match_schema() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+    let client_schema = compile("ClientMsg");
+    let server_schema = compile("ServerMsg");
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    operation.delete(0);
+    let sent = [
+        json!({ "ClientInfo": { "name": "Alice", "hue": 42 } }),
+        json!({ "CursorData": { "cursors": [1], "selections": [[0, 1]] } }),
+        json!({ "Edit": { "revision": 0, "operation": operation, "seq": 1, "session": "s" } }),
+        json!({ "SetLanguage": "rust" }),
+        json!({ "CreateSnapshot": { "label": "v1" } }),
+        json!({ "Edit": { "revision": 9, "operation": [] } }),
+    ];
+    for msg in &sent {
+        assert!(
+            client_schema.is_valid(msg),
+            "invalid client message {}",
+            msg
+        );
+    }
+    assert!(!client_schema.is_valid(&json!({ "Edit": { "revision": 0 } })));
+
+    let mut client = connect(&filter, "schema").await?;
+    for msg in &sent {
+        client.send(msg).await;
+    }
+    let mut received = Vec::new();
+    while received
+        .last()
+        .and_then(|msg: &Value| msg.get("Error"))
+        .is_none()
+    {
+        received.push(client.recv_raw().await?);
+    }
+    client.recv_closed().await?;
+    for msg in &received {
+        assert!(
+            server_schema.is_valid(msg),
+            "invalid server message {}",
+            msg
+        );
+    }
+    assert!(received.iter().any(|msg| msg.get("Ack").is_some()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_version_1() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    // Clients that don't ask for a version get neither limits nor errors.
+    let mut client = connect_path(&filter, "/api/socket/old").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.send(&json!({ "Unknown": 42 })).await;
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [{ "id": 0, "operation": ["hello"] }]
+            }
+        })
+    );
+    let msg = json!({ "Edit": { "revision": 5, "operation": operation } });
+    client.send(&msg).await;
+    client.recv_closed().await?;
+    expect_text(&filter, "old", "hello").await;
+
+    let mut client = connect_path(&filter, "/api/socket/old?version=1").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+    assert!(client.recv().await?.get("History").is_some());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unsupported_version() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    assert!(connect_path(&filter, "/api/socket/a?version=0")
+        .await
+        .is_err());
+    assert!(connect_path(&filter, "/api/socket/a?version=6")
+        .await
+        .is_err());
+    let resp = warp::test::request()
+        .path("/api/socket/a?version=6")
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-version", "13")
+        .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_msgpack_encoding() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let text = "abcdefgh".repeat(1000);
+    let mut client = connect(&filter, "packed").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    let mut operation = OperationSeq::default();
+    operation.insert(&text);
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+
+    let mut client = warp::test::ws()
+        .path("/api/socket/packed?version=2&encoding=msgpack&compression=zstd")
+        .handshake(filter.clone())
+        .await?;
+    assert_eq!(
+        recv_msgpack(&mut client).await?,
+        (json!({ "Identity": 1 }), false)
+    );
+    let (limits, _) = recv_msgpack(&mut client).await?;
+    assert!(limits.get("Limits").is_some());
+    let (history, compressed) = recv_msgpack(&mut client).await?;
+    assert!(compressed, "large history should be compressed");
+    assert_eq!(
+        history["History"]["operations"][0]["operation"],
+        json!([text])
+    );
+
+    // Clients send MessagePack in binary frames, or JSON in text frames.
+    let mut operation = OperationSeq::default();
+    operation.retain(8000);
+    operation.insert("!");
+    let msg = json!({ "Edit": { "revision": 1, "operation": operation, "seq": 1 } });
+    client
+        .send(Message::binary(rmp_serde::to_vec_named(&msg)?))
+        .await;
+    let (history, compressed) = recv_msgpack(&mut client).await?;
+    assert!(!compressed, "small history should not be compressed");
+    assert_eq!(history["History"]["start"], 1);
+    assert_eq!(
+        recv_msgpack(&mut client).await?.0,
+        json!({ "Ack": { "revision": 2, "seq": 1 } })
+    );
+    let msg = json!({ "SetLanguage": "rust" });
+    client.send(Message::text(msg.to_string())).await;
+    assert_eq!(
+        recv_msgpack(&mut client).await?.0,
+        json!({ "Language": "rust" })
+    );
+
+    client.send(Message::binary(vec![0xc1])).await;
+    let (error, _) = recv_msgpack(&mut client).await?;
+    assert_eq!(error["Error"]["code"], "MalformedMessage");
+
+    expect_text(&filter, "packed", &(text + "!")).await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unsupported_encoding() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    assert!(connect_path(&filter, "/api/socket/a?encoding=cbor")
+        .await
+        .is_err());
+    let resp = warp::test::request()
+        .path("/api/socket/a?encoding=json&compression=zstd")
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-version", "13")
+        .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+    assert_eq!(resp.body(), "compression requires the msgpack encoding");
+
+    Ok(())
+}
@@ -0,0 +1,153 @@
+//! Tests for read-only share links.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{
+    database::PersistedDocument,
+    server,
+    store::{DocumentStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::{json, Value};
+use warp::{filters::BoxedFilter, Reply};
+
+pub mod common;
+
+/// Fetch the read-only share token of a document.
+async fn share_token(filter: &BoxedFilter<(impl Reply + 'static,)>, id: &str) -> Result<String> {
+    let resp = warp::test::request()
+        .path(&format!("/api/share/{}", id))
+        .reply(filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    Ok(body["token"].as_str().unwrap_or_default().to_owned())
+}
+
+#[tokio::test]
+async fn test_readonly() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut editor = connect(&filter, "shared").await?;
+    assert_eq!(editor.recv().await?, json!({ "Identity": 0 }));
+    editor.recv_limits().await?;
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    editor.send(&msg).await;
+    editor.recv().await?;
+    editor.recv_ack().await?;
+
+    let token = share_token(&filter, "shared").await?;
+    assert!(!token.contains(&hex::encode("shared")));
+    // Each token is encrypted with a fresh nonce.
+    let other = share_token(&filter, "shared").await?;
+    assert_ne!(token, other);
+    assert!(connect_readonly(&filter, "bogus").await.is_err());
+
+    // Tokens cannot be altered to view other documents.
+    let mut tampered = token.clone().into_bytes();
+    let last = tampered.last_mut().expect("token should not be empty");
+    *last = if *last == b'0' { b'1' } else { b'0' };
+    let tampered = String::from_utf8(tampered)?;
+    assert!(connect_readonly(&filter, &tampered).await.is_err());
+    assert!(connect_readonly(&filter, &token[..48]).await.is_err());
+
+    let mut viewer = connect_readonly(&filter, &token).await?;
+    assert_eq!(
+        viewer.recv().await?,
+        json!({ "Identity": { "id": 1, "readonly": true } })
+    );
+    viewer.recv_limits().await?;
+    assert_eq!(
+        viewer.recv().await?,
+        json!({ "Snapshot": { "revision": 1, "text": "hello" } })
+    );
+
+    // Viewers can still share their presence.
+    let info = json!({ "name": "Viewer", "hue": 7 });
+    viewer.send(&json!({ "ClientInfo": info })).await;
+    let msg = json!({ "UserInfo": { "id": 1, "info": info } });
+    assert_eq!(editor.recv().await?, msg);
+    assert_eq!(viewer.recv().await?, msg);
+
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert("!");
+    let msg = json!({ "Edit": { "revision": 1, "operation": operation } });
+    editor.send(&msg).await;
+    editor.recv().await?;
+    editor.recv_ack().await?;
+    assert_eq!(
+        viewer.recv().await?,
+        json!({
+            "History": {
+                "start": 1,
+                "operations": [{ "id": 0, "operation": [5, "!"] }]
+            }
+        })
+    );
+
+    // But they cannot change the document, although they stay connected.
+    let mut operation = OperationSeq::default();
+    operation.insert("x");
+    let msg = json!({ "Edit": { "revision": 2, "operation": operation } });
+    viewer.send(&msg).await;
+    let msg = viewer.recv().await?;
+    assert_eq!(msg["Error"]["code"], "Forbidden");
+    assert_eq!(msg["Error"]["fatal"], false);
+    expect_text(&filter, "shared", "hello!").await;
+
+    viewer.send(&json!({ "SetLanguage": "rust" })).await;
+    assert_eq!(viewer.recv().await?["Error"]["code"], "Forbidden");
+    viewer.send(&json!({ "ClientInfo": info })).await;
+    assert_eq!(viewer.recv().await?["UserInfo"]["id"], 1);
+
+    // Every token issued for a document stays valid.
+    let mut viewer = connect_readonly(&filter, &other).await?;
+    assert_eq!(
+        viewer.recv().await?,
+        json!({ "Identity": { "id": 2, "readonly": true } })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_readonly_persisted() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let config = || ServerConfig {
+        share_secret: Some("hunter2".into()),
+        ..ServerConfig::default()
+    };
+    let token = share_token(&server(config()), "stored").await?;
+
+    // Tokens for stored documents stay valid across restarts.
+    let store = Arc::new(MemoryStore::new());
+    let document = PersistedDocument {
+        text: "stored".into(),
+        language: None,
+        password: None,
+        authors: Default::default(),
+    };
+    store.store("stored", &document).await?;
+    let filter = server(ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..config()
+    });
+
+    let mut viewer = connect_readonly(&filter, &token).await?;
+    assert_eq!(
+        viewer.recv().await?,
+        json!({ "Identity": { "id": 0, "readonly": true } })
+    );
+    viewer.recv_limits().await?;
+    let msg = viewer.recv().await?;
+    assert_eq!(msg["Snapshot"]["text"], "stored");
+
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! Tests for shutting down the server gracefully.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{
+    database::{PersistedAcl, PersistedDocument, PersistedOperation, PersistedSnapshot},
+    server,
+    shutdown::Shutdown,
+    store::{DocumentStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::json;
+use tokio::{sync::Notify, time};
+
+pub mod common;
+
+#[tokio::test]
+async fn test_shutdown() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(MemoryStore::new());
+    let shutdown = Shutdown::default();
+    let filter = server(ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        shutdown: Some(shutdown.clone()),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "unsaved").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.insert("not yet stored");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+    assert!(store.load("unsaved").await.is_err());
+
+    // Documents are stored right away, without waiting for the next interval.
+    time::timeout(
+        Duration::from_secs(1),
+        shutdown.shut_down(Duration::from_secs(5)),
+    )
+    .await?;
+    assert_eq!(store.load("unsaved").await?.text, "not yet stored");
+
+    // Connected clients are told when to reconnect before they are disconnected.
+    let msg = client.recv().await?;
+    assert_eq!(msg, json!({ "ServerShutdown": { "retry_after": 5 } }));
+    client.recv_closed().await?;
+
+    // New connections are refused until the server stops.
+    let resp = warp::test::request()
+        .path("/api/socket/unsaved")
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-version", "13")
+        .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 503);
+    assert!(connect(&filter, "unsaved").await.is_err());
+
+    Ok(())
+}
+
+/// A store that takes a while to write the history of a document.
+#[derive(Debug, Default)]
+struct SlowStore {
+    inner: MemoryStore,
+    writing: Notify,
+}
+
+#[async_trait]
+impl DocumentStore for SlowStore {
+    async fn load(&self, document_id: &str) -> Result<PersistedDocument> {
+        self.inner.load(document_id).await
+    }
+
+    async fn store(&self, document_id: &str, document: &PersistedDocument) -> Result<()> {
+        self.inner.store(document_id, document).await
+    }
+
+    async fn count(&self) -> Result<usize> {
+        self.inner.count().await
+    }
+
+    async fn load_operations(&self, document_id: &str) -> Result<Vec<PersistedOperation>> {
+        self.inner.load_operations(document_id).await
+    }
+
+    async fn store_operations(
+        &self,
+        document_id: &str,
+        start: usize,
+        operations: &[PersistedOperation],
+    ) -> Result<()> {
+        self.writing.notify_one();
+        time::sleep(Duration::from_millis(200)).await;
+        self.inner
+            .store_operations(document_id, start, operations)
+            .await
+    }
+
+    async fn load_snapshots(&self, document_id: &str) -> Result<Vec<PersistedSnapshot>> {
+        self.inner.load_snapshots(document_id).await
+    }
+
+    async fn store_snapshots(
+        &self,
+        document_id: &str,
+        start: usize,
+        snapshots: &[PersistedSnapshot],
+    ) -> Result<()> {
+        self.inner
+            .store_snapshots(document_id, start, snapshots)
+            .await
+    }
+
+    async fn load_acl(&self, document_id: &str) -> Result<PersistedAcl> {
+        self.inner.load_acl(document_id).await
+    }
+
+    async fn store_acl(&self, document_id: &str, acl: &PersistedAcl) -> Result<()> {
+        self.inner.store_acl(document_id, acl).await
+    }
+
+    async fn delete(&self, document_id: &str) -> Result<bool> {
+        self.inner.delete(document_id).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        self.inner.list().await
+    }
+}
+
+#[tokio::test]
+async fn test_shutdown_during_persist() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(SlowStore::default());
+    let shutdown = Shutdown::default();
+    let filter = server(ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        shutdown: Some(shutdown.clone()),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "busy").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+
+    // Edit the document while its history is being written.
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    store.writing.notified().await;
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert(" world");
+    let msg = json!({ "Edit": { "revision": 1, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+
+    // The edit is stored along with the rest once the server shuts down.
+    time::timeout(
+        Duration::from_secs(2),
+        shutdown.shut_down(Duration::from_secs(5)),
+    )
+    .await?;
+    assert_eq!(store.load("busy").await?.text, "hello world");
+    assert_eq!(store.load_operations("busy").await?.len(), 2);
+
+    Ok(())
+}
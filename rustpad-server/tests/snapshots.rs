@@ -0,0 +1,194 @@
+//! Tests for named document snapshots.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{
+    server,
+    store::{DocumentStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::{json, Value};
+use tokio::time;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_snapshots() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "snap").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    client.recv().await?;
+    let msg = json!({ "CreateSnapshot": { "label": "first draft" } });
+    client.send(&msg).await;
+    client.send(&json!({ "SetLanguage": "python" })).await;
+    client.recv().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert(" world");
+    let msg = json!({ "Edit": { "revision": 1, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv_ack().await?;
+    expect_text(&filter, "snap", "hello world").await;
+
+    let resp = warp::test::request()
+        .path("/api/snapshots/snap")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let list: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(list.as_array().map(Vec::len), Some(1));
+    assert_eq!(list[0]["index"], 0);
+    assert_eq!(list[0]["label"], "first draft");
+    assert_eq!(list[0]["revision"], 1);
+
+    let resp = warp::test::request()
+        .path("/api/snapshots/snap/0")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let snapshot: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(snapshot["text"], "hello");
+    assert_eq!(snapshot["language"], "rust");
+    assert_eq!(snapshot["id"], 0);
+
+    let resp = warp::test::request()
+        .path("/api/snapshots/snap/1")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    // Restoring is broadcast to clients as a normal edit.
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/snapshots/snap/0/restore")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.body(), r#"{"revision":3}"#);
+    let history = json!({
+        "History": {
+            "start": 2,
+            "operations": [{ "id": 1, "operation": [5, -6] }]
+        }
+    });
+    let language = json!({ "Language": "rust" });
+    let msgs = [client.recv().await?, client.recv().await?];
+    assert!(msgs.contains(&history));
+    assert!(msgs.contains(&language));
+    expect_text(&filter, "snap", "hello").await;
+
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/snapshots/snap/1/restore")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshot_limits() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    // Restoring a snapshot of an unknown document does not create it.
+    let resp = warp::test::request()
+        .method("POST")
+        .path("/api/snapshots/missing/0/restore")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 404);
+    let resp = warp::test::request()
+        .path("/api/stats")
+        .reply(&filter)
+        .await;
+    let stats: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(stats["num_documents"], 0);
+
+    // Documents have a limited number of snapshots.
+    let mut client = connect(&filter, "many").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    for i in 0..100 {
+        let label = format!("snapshot {}", i);
+        client
+            .send(&json!({ "CreateSnapshot": { "label": label } }))
+            .await;
+    }
+    client
+        .send(&json!({ "CreateSnapshot": { "label": "one too many" } }))
+        .await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "TooManySnapshots");
+    assert_eq!(msg["Error"]["fatal"], false);
+
+    let resp = warp::test::request()
+        .path("/api/snapshots/many")
+        .reply(&filter)
+        .await;
+    let list: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(list.as_array().map(Vec::len), Some(100));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_persist_snapshots() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(MemoryStore::new());
+    let filter = server(ServerConfig {
+        expiry_days: 2,
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "snap").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    let msg = json!({ "CreateSnapshot": { "label": "empty" } });
+    client.send(&msg).await;
+    client.send(&json!({ "SetLanguage": "rust" })).await;
+    client.recv().await?;
+
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+
+    let snapshots = store.load_snapshots("snap").await?;
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].label, "empty");
+    assert_eq!(snapshots[0].text, "");
+
+    // Snapshots are restored along with the document after it expires.
+    drop(client);
+    time::pause();
+    time::advance(Duration::from_secs(50 * 3600)).await;
+    time::resume();
+    let resp = warp::test::request()
+        .path("/api/snapshots/snap")
+        .reply(&filter)
+        .await;
+    let list: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(list[0]["label"], "empty");
+
+    Ok(())
+}
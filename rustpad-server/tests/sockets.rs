@@ -22,13 +22,15 @@ async fn test_single_operation() -> Result<()> {
     let mut client = connect(&filter, "foobar").await?;
     let msg = client.recv().await?;
     assert_eq!(msg, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("hello");
     let msg = json!({
         "Edit": {
             "revision": 0,
-            "operation": operation
+            "operation": operation,
+            "seq": 7
         }
     });
     info!("sending ClientMsg {}", msg);
@@ -46,6 +48,8 @@ async fn test_single_operation() -> Result<()> {
             }
         })
     );
+    let msg = client.recv().await?;
+    assert_eq!(msg, json!({ "Ack": { "revision": 1, "seq": 7 } }));
 
     expect_text(&filter, "foobar", "hello").await;
     Ok(())
@@ -61,6 +65,7 @@ async fn test_invalid_operation() -> Result<()> {
     let mut client = connect(&filter, "foobar").await?;
     let msg = client.recv().await?;
     assert_eq!(msg, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("hello");
@@ -73,6 +78,43 @@ async fn test_invalid_operation() -> Result<()> {
     info!("sending ClientMsg {}", msg);
     client.send(&msg).await;
 
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "InvalidRevision");
+    assert_eq!(msg["Error"]["fatal"], true);
+    client.recv_closed().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_malformed_message() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+
+    // Messages that cannot be parsed are reported, but the connection stays open.
+    client.send(&json!({ "Unknown": 42 })).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "MalformedMessage");
+    assert_eq!(msg["Error"]["fatal"], false);
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let msg = json!({ "Edit": { "revision": 0, "operation": operation } });
+    client.send(&msg).await;
+    client.recv().await?;
+    client.recv().await?;
+    expect_text(&filter, "foobar", "hello").await;
+
+    // Edits that do not apply are fatal.
+    let mut operation = OperationSeq::default();
+    operation.retain(10);
+    let msg = json!({ "Edit": { "revision": 1, "operation": operation } });
+    client.send(&msg).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg["Error"]["code"], "InvalidOperation");
     client.recv_closed().await?;
     Ok(())
 }
@@ -86,6 +128,7 @@ async fn test_concurrent_transform() -> Result<()> {
     let mut client = connect(&filter, "foobar").await?;
     let msg = client.recv().await?;
     assert_eq!(msg, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     // Insert the first operation
     let mut operation = OperationSeq::default();
@@ -111,6 +154,8 @@ async fn test_concurrent_transform() -> Result<()> {
             }
         })
     );
+    let msg = client.recv().await?;
+    assert_eq!(msg, json!({ "Ack": { "revision": 1, "seq": null } }));
 
     // Insert the second operation
     let mut operation = OperationSeq::default();
@@ -139,12 +184,15 @@ async fn test_concurrent_transform() -> Result<()> {
             }
         })
     );
+    let msg = client.recv().await?;
+    assert_eq!(msg, json!({ "Ack": { "revision": 2, "seq": null } }));
     expect_text(&filter, "foobar", "henlo").await;
 
     // Connect the second client
     let mut client2 = connect(&filter, "foobar").await?;
     let msg = client2.recv().await?;
     assert_eq!(msg, json!({ "Identity": 1 }));
+    client2.recv_limits().await?;
 
     // Insert a concurrent operation before seeing the existing history
     time::sleep(Duration::from_millis(50)).await;
@@ -159,19 +207,11 @@ async fn test_concurrent_transform() -> Result<()> {
     info!("sending ClientMsg {}", msg);
     client2.send(&msg).await;
 
-    // Receive the existing history
+    // Receive a snapshot of the existing text
     let msg = client2.recv().await?;
     assert_eq!(
         msg,
-        json!({
-            "History": {
-                "start": 0,
-                "operations": [
-                    { "id": 0, "operation": ["hello"] },
-                    { "id": 0, "operation": [2, "n", -1, 2] }
-                ]
-            }
-        })
+        json!({ "Snapshot": { "revision": 2, "text": "henlo" } })
     );
 
     // Expect to receive a transformed operation
@@ -188,9 +228,11 @@ async fn test_concurrent_transform() -> Result<()> {
     let msg = client.recv().await?;
     assert_eq!(msg, transformed_op);
 
-    // ... and in the second client
+    // ... and in the second client, followed by an acknowledgement
     let msg = client2.recv().await?;
     assert_eq!(msg, transformed_op);
+    let msg = client2.recv().await?;
+    assert_eq!(msg, json!({ "Ack": { "revision": 3, "seq": null } }));
 
     expect_text(&filter, "foobar", "~rust~henlo").await;
     Ok(())
@@ -204,6 +246,7 @@ async fn test_set_language() -> Result<()> {
     let mut client = connect(&filter, "foobar").await?;
     let msg = client.recv().await?;
     assert_eq!(msg, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let msg = json!({ "SetLanguage": "javascript" });
     client.send(&msg).await;
@@ -214,6 +257,7 @@ async fn test_set_language() -> Result<()> {
     let mut client2 = connect(&filter, "foobar").await?;
     let msg = client2.recv().await?;
     assert_eq!(msg, json!({ "Identity": 1 }));
+    client2.recv_limits().await?;
     let msg = client2.recv().await?;
     assert_eq!(msg, json!({ "Language": "javascript" }));
 
@@ -228,3 +272,64 @@ async fn test_set_language() -> Result<()> {
     expect_text(&filter, "foobar", "").await;
     Ok(())
 }
+
+#[tokio::test]
+async fn test_resubmit_edit() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    let edit = json!({
+        "Edit": {
+            "revision": 0,
+            "operation": operation,
+            "seq": 1,
+            "session": "s3cret"
+        }
+    });
+    client.send(&edit).await;
+    client.recv().await?;
+    assert_eq!(client.recv_ack().await?, 1);
+
+    // The client reconnects without seeing the acknowledgement, and resends.
+    client.close().await?;
+    let mut client = connect(&filter, "foobar").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 1 }));
+    client.recv_limits().await?;
+    client.recv().await?;
+    client.send(&edit).await;
+    let msg = client.recv().await?;
+    assert_eq!(msg, json!({ "Ack": { "revision": 1, "seq": 1 } }));
+    expect_text(&filter, "foobar", "hello").await;
+
+    // Later edits from the session, and edits from other sessions, apply.
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert("!");
+    let mut edit = json!({
+        "Edit": {
+            "revision": 1,
+            "operation": operation,
+            "seq": 2,
+            "session": "s3cret"
+        }
+    });
+    client.send(&edit).await;
+    client.recv().await?;
+    assert_eq!(client.recv_ack().await?, 2);
+    edit["Edit"]["revision"] = json!(2);
+    operation.retain(1);
+    edit["Edit"]["operation"] = json!(operation);
+    edit["Edit"]["session"] = json!("other");
+    client.send(&edit).await;
+    client.recv().await?;
+    assert_eq!(client.recv_ack().await?, 3);
+    expect_text(&filter, "foobar", "hello!!").await;
+
+    Ok(())
+}
@@ -0,0 +1,329 @@
+//! Tests for the pluggable document storage backends.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{
+    database::{
+        Database, PersistedAcl, PersistedDocument, PersistedOperation, PersistedSnapshot, Role,
+    },
+    server,
+    store::{DocumentPage, DocumentQuery, DocumentStore, DocumentSummary, FileStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::json;
+use tempfile::TempDir;
+use tokio::time;
+
+pub mod common;
+
+/// Query the IDs of stored documents, along with the total number of matches.
+async fn query_ids(
+    store: &dyn DocumentStore,
+    query: DocumentQuery,
+) -> Result<(Vec<String>, usize)> {
+    let page = store.query(&query).await?;
+    let ids = page
+        .documents
+        .into_iter()
+        .map(|summary| summary.id)
+        .collect();
+    Ok((ids, page.total))
+}
+
+/// Check queries on a store holding the documents of [`check_store`].
+async fn check_query(store: &dyn DocumentStore) -> Result<()> {
+    let query = |text: &str| DocumentQuery {
+        text: Some(text.into()),
+        limit: 10,
+        ..DocumentQuery::default()
+    };
+    // Documents protected by a password are never matched.
+    assert_eq!(
+        query_ids(store, query("TEXT")).await?,
+        (vec!["hello".into()], 1)
+    );
+    assert_eq!(query_ids(store, query("world")).await?, (vec![], 0));
+    assert_eq!(
+        store.query(&query("")).await?,
+        DocumentPage {
+            total: 1,
+            documents: vec![DocumentSummary {
+                id: "hello".into(),
+                size: 10,
+                language: None,
+                revision: 0,
+            }],
+        }
+    );
+
+    let mut filter = query("");
+    filter.exclude.insert("hello".into());
+    assert_eq!(query_ids(store, filter).await?, (vec![], 0));
+    let filter = DocumentQuery {
+        offset: 1,
+        ..query("")
+    };
+    assert_eq!(query_ids(store, filter).await?, (vec![], 1));
+
+    // Only principals who can view a document match it.
+    let acl = PersistedAcl {
+        owner: Some("alice".into()),
+        grants: [("bob".into(), Role::Viewer)].into(),
+    };
+    store.store_acl("hello", &acl).await?;
+    for (principal, total) in [
+        (None, 0),
+        (Some("alice"), 1),
+        (Some("bob"), 1),
+        (Some("carol"), 0),
+    ] {
+        let filter = DocumentQuery {
+            principal: principal.map(Into::into),
+            prefix: Some("hel".into()),
+            id: Some("ll".into()),
+            ..query("")
+        };
+        assert_eq!(query_ids(store, filter).await?.1, total);
+    }
+    store.store_acl("hello", &PersistedAcl::default()).await?;
+    Ok(())
+}
+
+async fn check_store(store: &dyn DocumentStore) -> Result<()> {
+    assert_eq!(store.count().await?, 0);
+    assert!(store.load("hello").await.is_err());
+
+    let doc1 = PersistedDocument {
+        text: "Hello Text".into(),
+        language: None,
+        password: None,
+        authors: Default::default(),
+    };
+    let doc2 = PersistedDocument {
+        text: "print('World Text :)')".into(),
+        language: Some("python".into()),
+        password: Some("pbkdf2-sha256$1$00$00".into()),
+        authors: Default::default(),
+    };
+
+    store.store("hello", &doc1).await?;
+    store.store("a/../world", &doc2).await?;
+    assert_eq!(store.load("hello").await?, doc1);
+    assert_eq!(store.load("a/../world").await?, doc2);
+    assert!(store.load("world").await.is_err());
+    assert_eq!(store.list().await?, vec!["a/../world", "hello"]);
+    assert_eq!(store.count().await?, 2);
+    check_query(store).await?;
+
+    store.store("hello", &doc2).await?;
+    assert_eq!(store.load("hello").await?, doc2);
+
+    let mut op1 = OperationSeq::default();
+    op1.insert("ab");
+    let mut op2 = OperationSeq::default();
+    op2.retain(2);
+    op2.insert("c");
+    let ops = vec![
+        PersistedOperation {
+            id: 0,
+            operation: op1,
+            time: 1000,
+            session: None,
+            seq: None,
+        },
+        PersistedOperation {
+            id: 1,
+            operation: op2,
+            time: 2000,
+            session: Some("abc".into()),
+            seq: Some(7),
+        },
+    ];
+    assert!(store.load_operations("hello").await?.is_empty());
+    assert_eq!(store.count_operations("hello").await?, 0);
+    store.store_operations("hello", 0, &ops[..1]).await?;
+    store.store_operations("hello", 0, &ops).await?;
+    store.store_operations("hello", 2, &[]).await?;
+    assert!(store.store_operations("hello", 3, &ops).await.is_err());
+    assert_eq!(store.load_operations("hello").await?, ops);
+    assert_eq!(store.count_operations("hello").await?, 2);
+    assert!(store.load_operations("a/../world").await?.is_empty());
+
+    let snapshot = PersistedSnapshot {
+        label: "v1".into(),
+        revision: 2,
+        id: 1,
+        time: 1234,
+        text: "abc".into(),
+        language: Some("rust".into()),
+    };
+    let snapshots = vec![snapshot.clone(), snapshot];
+    assert!(store.load_snapshots("hello").await?.is_empty());
+    store.store_snapshots("hello", 0, &snapshots[..1]).await?;
+    store.store_snapshots("hello", 1, &snapshots[1..]).await?;
+    store.store_snapshots("hello", 0, &snapshots[..1]).await?;
+    assert!(store.store_snapshots("hello", 3, &snapshots).await.is_err());
+    assert_eq!(store.load_snapshots("hello").await?, snapshots);
+
+    let mut acl = PersistedAcl {
+        owner: Some("alice".into()),
+        grants: [("bob".into(), Role::Viewer)].into(),
+    };
+    assert_eq!(store.load_acl("hello").await?, PersistedAcl::default());
+    store.store_acl("hello", &acl).await?;
+    acl.grants.insert("carol".into(), Role::Editor);
+    store.store_acl("hello", &acl).await?;
+    assert_eq!(store.load_acl("hello").await?, acl);
+
+    assert!(store.delete("hello").await?);
+    assert!(!store.delete("hello").await?);
+    assert!(store.load("hello").await.is_err());
+    assert!(store.load_operations("hello").await?.is_empty());
+    assert_eq!(store.count_operations("hello").await?, 0);
+    assert!(store.load_snapshots("hello").await?.is_empty());
+    assert_eq!(store.load_acl("hello").await?, PersistedAcl::default());
+    assert_eq!(store.list().await?, vec!["a/../world"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_memory_store() -> Result<()> {
+    check_store(&MemoryStore::new()).await
+}
+
+#[tokio::test]
+async fn test_database_store() -> Result<()> {
+    check_store(&Database::new(&temp_sqlite_uri()?).await?).await
+}
+
+#[tokio::test]
+async fn test_file_store() -> Result<()> {
+    let dir = TempDir::new()?;
+    check_store(&FileStore::new(dir.path()).await?).await?;
+
+    // Documents should survive reopening the same directory.
+    let store = FileStore::new(dir.path()).await?;
+    assert_eq!(store.list().await?, vec!["a/../world"]);
+
+    // Concurrent writes to the files of a document do not share temporary files.
+    let document = PersistedDocument {
+        text: "concurrent".into(),
+        language: None,
+        password: None,
+        authors: Default::default(),
+    };
+    let acl = PersistedAcl::default();
+    let writes = (0..10).map(|_| async {
+        tokio::try_join!(
+            store.store("busy", &document),
+            store.store_acl("busy", &acl)
+        )
+    });
+    futures::future::try_join_all(writes).await?;
+    assert_eq!(store.load("busy").await?, document);
+    for entry in std::fs::read_dir(dir.path())? {
+        let name = entry?.file_name();
+        assert!(!name.to_string_lossy().ends_with(".tmp"), "{:?}", name);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_custom_store() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    let store = Arc::new(MemoryStore::new());
+    let filter = server(ServerConfig {
+        expiry_days: 2,
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..ServerConfig::default()
+    });
+
+    let mut client = connect(&filter, "custom").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+
+    let mut operation = OperationSeq::default();
+    operation.insert("hello");
+    client
+        .send(&json!({ "Edit": { "revision": 0, "operation": operation } }))
+        .await;
+    client.recv().await?;
+
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+
+    let document = store.load("custom").await?;
+    assert_eq!(document.text, "hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unreplayable_history() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+
+    // The second operation does not apply to the text after the first one.
+    let store = Arc::new(MemoryStore::new());
+    let mut op1 = OperationSeq::default();
+    op1.insert("hello");
+    let mut op2 = OperationSeq::default();
+    op2.retain(100);
+    let ops: Vec<_> = [op1, op2]
+        .into_iter()
+        .map(|operation| PersistedOperation {
+            id: 0,
+            operation,
+            time: 0,
+            session: None,
+            seq: None,
+        })
+        .collect();
+    store.store_operations("broken", 0, &ops).await?;
+    let document = PersistedDocument {
+        text: "hello".into(),
+        language: None,
+        password: None,
+        authors: Default::default(),
+    };
+    store.store("broken", &document).await?;
+    let filter = server(ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..ServerConfig::default()
+    });
+
+    // The text is restored at the revision after the stored history.
+    let mut client = connect(&filter, "broken").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+    let msg = client.recv().await?;
+    assert_eq!(
+        msg,
+        json!({ "Snapshot": { "revision": 2, "text": "hello" } })
+    );
+
+    let mut operation = OperationSeq::default();
+    operation.retain(5);
+    operation.insert(" world");
+    client
+        .send(&json!({ "Edit": { "revision": 2, "operation": operation } }))
+        .await;
+    client.recv().await?;
+    assert_eq!(client.recv_ack().await?, 3);
+
+    // New edits are appended after the stored history.
+    time::pause();
+    time::advance(Duration::from_secs(10)).await;
+    time::resume();
+    time::sleep(Duration::from_millis(50)).await;
+    let stored = store.load_operations("broken").await?;
+    assert_eq!(stored.len(), 3);
+    assert_eq!(stored[2].operation, operation);
+    assert_eq!(store.load("broken").await?.text, "hello world");
+    Ok(())
+}
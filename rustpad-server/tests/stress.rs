@@ -22,10 +22,12 @@ async fn test_lost_wakeups() -> Result<()> {
     let mut client = connect(&filter, "stress").await?;
     let msg = client.recv().await?;
     assert_eq!(msg, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let mut client2 = connect(&filter, "stress").await?;
     let msg = client2.recv().await?;
     assert_eq!(msg, json!({ "Identity": 1 }));
+    client2.recv_limits().await?;
 
     let mut revision = 0;
     for i in 0..100 {
@@ -53,7 +55,9 @@ async fn test_lost_wakeups() -> Result<()> {
         let mut total = 0;
         while total < num_edits {
             let msg = client.recv().await?;
-            total += num_ops(&msg).ok_or_else(|| anyhow!("missing json key"))?;
+            if msg.get("Ack").is_none() {
+                total += num_ops(&msg).ok_or_else(|| anyhow!("missing json key"))?;
+            }
         }
 
         let mut total2 = 0;
@@ -81,6 +85,7 @@ async fn test_large_document() -> Result<()> {
     let mut client = connect(&filter, "stress").await?;
     let msg = client.recv().await?;
     assert_eq!(msg, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let mut operation = OperationSeq::default();
     operation.insert(&"a".repeat(5000));
@@ -92,6 +97,7 @@ async fn test_large_document() -> Result<()> {
     });
     client.send(&msg).await;
     client.recv().await?;
+    client.recv_ack().await?;
 
     let mut operation = OperationSeq::default();
     operation.insert(&"a".repeat(500000));
@@ -102,6 +108,7 @@ async fn test_large_document() -> Result<()> {
         }
     });
     client.send(&msg).await;
+    assert_eq!(client.recv().await?["Error"]["code"], "TooLarge");
     client.recv_closed().await?;
 
     Ok(())
@@ -19,6 +19,7 @@ async fn test_unicode_length() -> Result<()> {
     let mut client = connect(&filter, "unicode").await?;
     let msg = client.recv().await?;
     assert_eq!(msg, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("h🎉e🎉l👨‍👨‍👦‍👦lo");
@@ -43,6 +44,7 @@ async fn test_unicode_length() -> Result<()> {
             }
         })
     );
+    client.recv_ack().await?;
 
     info!("testing that text length is equal to number of Unicode code points...");
     let mut operation = OperationSeq::default();
@@ -68,6 +70,7 @@ async fn test_unicode_length() -> Result<()> {
             }
         })
     );
+    client.recv_ack().await?;
 
     expect_text(&filter, "unicode", "").await;
 
@@ -84,6 +87,7 @@ async fn test_multiple_operations() -> Result<()> {
     let mut client = connect(&filter, "unicode").await?;
     let msg = client.recv().await?;
     assert_eq!(msg, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("🎉😍𒀇👨‍👨‍👦‍👦"); // Emoticons and Cuneiform
@@ -108,6 +112,7 @@ async fn test_multiple_operations() -> Result<()> {
             }
         })
     );
+    client.recv_ack().await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("👯‍♂️");
@@ -135,6 +140,7 @@ async fn test_multiple_operations() -> Result<()> {
             }
         })
     );
+    client.recv_ack().await?;
 
     expect_text(&filter, "unicode", "👯‍♂️🎉😍𒀇𐅣𐅤𐅥👨‍👨‍👦‍👦").await;
 
@@ -163,6 +169,7 @@ async fn test_multiple_operations() -> Result<()> {
             }
         })
     );
+    client.recv_ack().await?;
 
     expect_text(&filter, "unicode", "👯‍♂️🎉😍h̷̙̤̏͊̑̍̆̃̉͝ĕ̶̠̌̓̃̓̽̃̚l̸̥̊̓̓͝͠l̸̨̠̣̟̥͠ỏ̴̳̖̪̟̱̰̥̞̙̏̓́͗̽̀̈́͛͐̚̕͝͝ ̶̡͍͙͚̞͙̣̘͙̯͇̙̠̀w̷̨̨̪͚̤͙͖̝͕̜̭̯̝̋̋̿̿̀̾͛̐̏͘͘̕͝ǒ̴̙͉͈̗̖͍̘̥̤̒̈́̒͠r̶̨̡̢̦͔̙̮̦͖͔̩͈̗̖̂̀l̶̡̢͚̬̤͕̜̀͛̌̈́̈́͑͋̈̍̇͊͝͠ď̵̛̛̯͕̭̩͖̝̙͎̊̏̈́̎͊̐̏͊̕͜͝͠͝𒀇𐅣𐅤𐅥👨‍👨‍👦‍👦").await;
 
@@ -176,6 +183,7 @@ async fn test_unicode_cursors() -> Result<()> {
 
     let mut client = connect(&filter, "unicode").await?;
     assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let mut operation = OperationSeq::default();
     operation.insert("🎉🎉🎉");
@@ -188,6 +196,7 @@ async fn test_unicode_cursors() -> Result<()> {
     info!("sending ClientMsg {}", msg);
     client.send(&msg).await;
     client.recv().await?;
+    client.recv_ack().await?;
 
     let cursors = json!({
         "cursors": [0, 1, 2, 3],
@@ -205,6 +214,7 @@ async fn test_unicode_cursors() -> Result<()> {
 
     let mut client2 = connect(&filter, "unicode").await?;
     assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_limits().await?;
     client2.recv().await?;
     assert_eq!(client2.recv().await?, cursors_resp);
 
@@ -218,6 +228,7 @@ async fn test_unicode_cursors() -> Result<()> {
 
     let mut client3 = connect(&filter, "unicode").await?;
     assert_eq!(client3.recv().await?, json!({ "Identity": 2 }));
+    client3.recv_limits().await?;
     client3.recv().await?;
 
     let transformed_cursors_resp = json!({
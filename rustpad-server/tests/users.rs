@@ -14,6 +14,7 @@ async fn test_two_users() -> Result<()> {
 
     let mut client = connect(&filter, "foobar").await?;
     assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let alice = json!({
         "name": "Alice",
@@ -31,6 +32,7 @@ async fn test_two_users() -> Result<()> {
 
     let mut client2 = connect(&filter, "foobar").await?;
     assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_limits().await?;
     assert_eq!(client2.recv().await?, alice_info);
 
     let bob = json!({
@@ -58,10 +60,18 @@ async fn test_invalid_user() -> Result<()> {
 
     let mut client = connect(&filter, "foobar").await?;
     assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let alice = json!({ "name": "Alice" }); // no hue
     client.send(&json!({ "ClientInfo": alice })).await;
-    client.recv_closed().await?;
+    let error = client.recv().await?;
+    assert_eq!(error["Error"]["code"], "MalformedMessage");
+    assert_eq!(error["Error"]["fatal"], false);
+
+    client
+        .send(&json!({ "ClientInfo": { "name": "Alice", "hue": 42 } }))
+        .await;
+    assert_eq!(client.recv().await?["UserInfo"]["id"], 0);
 
     Ok(())
 }
@@ -73,6 +83,7 @@ async fn test_leave_rejoin() -> Result<()> {
 
     let mut client = connect(&filter, "foobar").await?;
     assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let alice = json!({
         "name": "Alice",
@@ -88,11 +99,11 @@ async fn test_leave_rejoin() -> Result<()> {
     });
     assert_eq!(client.recv().await?, alice_info);
 
-    client.send(&json!({ "Invalid": "please close" })).await;
-    client.recv_closed().await?;
+    client.close().await?;
 
     let mut client2 = connect(&filter, "foobar").await?;
     assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_limits().await?;
 
     let bob = json!({
         "name": "Bob",
@@ -118,6 +129,7 @@ async fn test_cursors() -> Result<()> {
 
     let mut client = connect(&filter, "foobar").await?;
     assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
 
     let cursors = json!({
         "cursors": [4, 6, 7],
@@ -135,6 +147,7 @@ async fn test_cursors() -> Result<()> {
 
     let mut client2 = connect(&filter, "foobar").await?;
     assert_eq!(client2.recv().await?, json!({ "Identity": 1 }));
+    client2.recv_limits().await?;
     assert_eq!(client2.recv().await?, cursors_resp);
 
     let cursors2 = json!({
@@ -152,8 +165,7 @@ async fn test_cursors() -> Result<()> {
     assert_eq!(client2.recv().await?, cursors2_resp);
     assert_eq!(client.recv().await?, cursors2_resp);
 
-    client.send(&json!({ "Invalid": "please close" })).await;
-    client.recv_closed().await?;
+    client.close().await?;
 
     let msg = json!({
         "Edit": {
@@ -165,6 +177,7 @@ async fn test_cursors() -> Result<()> {
 
     let mut client3 = connect(&filter, "foobar").await?;
     assert_eq!(client3.recv().await?, json!({ "Identity": 2 }));
+    client3.recv_limits().await?;
     client3.recv().await?;
 
     let transformed_cursors2_resp = json!({
@@ -0,0 +1,199 @@
+//! Tests for writing to documents over HTTP.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use common::*;
+use operational_transform::OperationSeq;
+use rustpad_server::{
+    database::PersistedDocument,
+    server,
+    store::{DocumentStore, MemoryStore},
+    ServerConfig,
+};
+use serde_json::{json, Value};
+
+pub mod common;
+
+#[tokio::test]
+async fn test_put_text() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut client = connect(&filter, "put").await?;
+    assert_eq!(client.recv().await?, json!({ "Identity": 0 }));
+    client.recv_limits().await?;
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/put")
+        .body("hello world")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body, json!({ "revision": 1 }));
+    expect_text(&filter, "put", "hello world").await;
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "History": {
+                "start": 0,
+                "operations": [{ "id": 1, "operation": ["hello world"] }]
+            }
+        })
+    );
+
+    // Replacing the text only sends the changed part.
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/put")
+        .body("hello there world")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        client.recv().await?,
+        json!({
+            "History": {
+                "start": 1,
+                "operations": [{ "id": 2, "operation": [6, "there ", 5] }]
+            }
+        })
+    );
+
+    // Writing the same text again does not add a revision.
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/put")
+        .body("hello there world")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body, json!({ "revision": 2 }));
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/put")
+        .body(vec![0xff, 0xfe])
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+    expect_text(&filter, "put", "hello there world").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_patch_text() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig::default());
+
+    let mut operation = OperationSeq::default();
+    operation.insert("abc");
+    let resp = warp::test::request()
+        .method("PATCH")
+        .path("/api/text/patch")
+        .json(&json!({ "revision": 0, "operation": operation }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let body: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(body, json!({ "revision": 1 }));
+
+    // Edits based on older revisions are transformed, as over a socket.
+    let mut operation = OperationSeq::default();
+    operation.insert("x");
+    let resp = warp::test::request()
+        .method("PATCH")
+        .path("/api/text/patch")
+        .json(&json!({ "revision": 0, "operation": operation }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    expect_text(&filter, "patch", "xabc").await;
+
+    let resp = warp::test::request()
+        .method("PATCH")
+        .path("/api/text/patch")
+        .json(&json!({ "revision": 5, "operation": operation }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+
+    let mut operation = OperationSeq::default();
+    operation.retain(10);
+    let resp = warp::test::request()
+        .method("PATCH")
+        .path("/api/text/patch")
+        .json(&json!({ "revision": 2, "operation": operation }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 400);
+    expect_text(&filter, "patch", "xabc").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_persisted() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let store = Arc::new(MemoryStore::new());
+    let document = PersistedDocument {
+        text: "stored".into(),
+        language: None,
+        password: None,
+        authors: Default::default(),
+    };
+    store.store("stored", &document).await?;
+    let filter = server(ServerConfig {
+        database: Some(Arc::clone(&store) as Arc<dyn DocumentStore>),
+        ..ServerConfig::default()
+    });
+
+    // Documents are loaded from storage before being edited.
+    let mut operation = OperationSeq::default();
+    operation.retain(6);
+    operation.insert("!");
+    let resp = warp::test::request()
+        .method("PATCH")
+        .path("/api/text/stored")
+        .json(&json!({ "revision": 1, "operation": operation }))
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    expect_text(&filter, "stored", "stored!").await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_attributed() -> Result<()> {
+    pretty_env_logger::try_init().ok();
+    let filter = server(ServerConfig {
+        principal_header: Some("x-forwarded-user".into()),
+        ..ServerConfig::default()
+    });
+
+    let resp = warp::test::request()
+        .method("PUT")
+        .path("/api/text/signed")
+        .header("x-forwarded-user", "alice")
+        .body("hello")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    // Text written by an authenticated user is attributed to them.
+    let resp = warp::test::request()
+        .path("/api/blame/signed")
+        .header("x-forwarded-user", "alice")
+        .reply(&filter)
+        .await;
+    assert_eq!(resp.status(), 200);
+    let blame: Value = serde_json::from_slice(resp.body())?;
+    assert_eq!(blame[0]["info"]["name"], "alice");
+
+    Ok(())
+}
@@ -22,8 +22,16 @@ function isMobileDevice() {
 }
 
 function getWsUri(id: string) {
-  let url = new URL(`api/socket/${id}`, window.location.href);
+  // Read-only share links look like `#readonly/{token}`.
+  const path = id.startsWith("readonly/") ? id : `socket/${id}`;
+  let url = new URL(`api/${path}`, window.location.href);
   url.protocol = url.protocol == "https:" ? "wss:" : "ws:";
+  // Browsers cannot set headers on sockets, so pass credentials along.
+  const params = new URLSearchParams(window.location.search);
+  for (const key of ["password", "access_token"]) {
+    const value = params.get(key);
+    if (value) url.searchParams.set(key, value);
+  }
   return url.href;
 }
 
@@ -179,6 +187,32 @@ function App() {
             duration: null,
           });
         },
+        onDeleted: () => {
+          setConnection("desynchronized");
+          toast({
+            title: "Document deleted",
+            description: "This document no longer exists on the server.",
+            status: "error",
+            duration: null,
+          });
+        },
+        onError: (code, message, fatal) => {
+          if (!fatal) return;
+          toast({
+            title: code === "RateLimited" ? "Slow down" : "Connection closed",
+            description: `The server closed the connection: ${message}.`,
+            status: "warning",
+            isClosable: true,
+          });
+        },
+        onNearLimit: (limits) => {
+          toast({
+            title: "Document almost full",
+            description: `Documents can be at most ${limits.max_document_size} characters long.`,
+            status: "warning",
+            isClosable: true,
+          });
+        },
         onChangeLanguage: (language) => {
           if (languages.includes(language)) {
             setLanguage(language);
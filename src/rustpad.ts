@@ -13,11 +13,27 @@ export type RustpadOptions = {
   readonly onConnected?: () => void;
   readonly onDisconnected?: () => void;
   readonly onDesynchronized?: () => void;
+  readonly onDeleted?: () => void;
+  readonly onError?: (code: string, message: string, fatal: boolean) => void;
   readonly onChangeLanguage?: (language: string) => void;
   readonly onChangeUsers?: (users: Record<number, UserInfo>) => void;
+  readonly onNearLimit?: (limits: DocumentLimits) => void;
   readonly reconnectInterval?: number;
 };
 
+/** Limits of the document, sent by the server on connect. */
+export type DocumentLimits = {
+  readonly max_document_size: number;
+  readonly max_history: number;
+  readonly max_users: number | null;
+};
+
+/** Version of the WebSocket protocol spoken by this client. */
+export const PROTOCOL_VERSION = 4;
+
+/** Fraction of the maximum document size at which to warn the user. */
+const NEAR_LIMIT_RATIO = 0.9;
+
 /** A user currently editing the document. */
 export type UserInfo = {
   readonly name: string;
@@ -29,6 +45,7 @@ class Rustpad {
   private ws?: WebSocket;
   private connecting?: boolean;
   private recentFailures: number = 0;
+  private retryAt: number = 0;
   private readonly model: editor.ITextModel;
   private readonly onChangeHandle: IDisposable;
   private readonly onCursorHandle: IDisposable;
@@ -38,9 +55,15 @@ class Rustpad {
   private readonly resetFailuresId: number;
 
   // Client-server state
+  private readonly session: string = randomToken();
   private me: number = -1;
+  private previousIds: Set<number> = new Set();
+  private readOnly: boolean = false;
+  private limits?: DocumentLimits;
+  private nearLimit: boolean = false;
   private revision: number = 0;
   private outstanding?: OpSeq;
+  private seq: number = 0;
   private buffer?: OpSeq;
   private users: Record<number, UserInfo> = {};
   private userCursors: Record<number, CursorData> = {};
@@ -102,6 +125,12 @@ class Rustpad {
     return this.ws !== undefined;
   }
 
+  /** Try to save the current text as a named snapshot, if connected. */
+  createSnapshot(label: string): boolean {
+    this.ws?.send(`{"CreateSnapshot":{"label":${JSON.stringify(label)}}}`);
+    return this.ws !== undefined;
+  }
+
   /** Set the user's information. */
   setInfo(info: UserInfo) {
     this.myInfo = info;
@@ -121,8 +150,15 @@ class Rustpad {
    */
   private tryConnect() {
     if (this.connecting || this.ws) return;
+    if (Date.now() < this.retryAt) return;
     this.connecting = true;
-    const ws = new WebSocket(this.options.uri);
+    const uri = new URL(this.options.uri);
+    uri.searchParams.set("version", String(PROTOCOL_VERSION));
+    if (this.me !== -1) {
+      // Only ask for the history we missed since the last connection.
+      uri.searchParams.set("revision", String(this.revision));
+    }
+    const ws = new WebSocket(uri);
     ws.onopen = () => {
       this.connecting = false;
       this.ws = ws;
@@ -139,11 +175,12 @@ class Rustpad {
       if (this.ws) {
         this.ws = undefined;
         this.options.onDisconnected?.();
-        if (++this.recentFailures >= 5) {
+        if (Date.now() < this.retryAt) {
+          // The server is restarting, so this does not count as a failure.
+        } else if (++this.recentFailures >= 5) {
           // If we disconnect 5 times within 15 reconnection intervals, then the
           // client is likely desynchronized and needs to refresh.
-          this.dispose();
-          this.options.onDesynchronized?.();
+          this.desynchronize();
         }
       } else {
         this.connecting = false;
@@ -151,14 +188,73 @@ class Rustpad {
     };
     ws.onmessage = ({ data }) => {
       if (typeof data === "string") {
-        this.handleMessage(JSON.parse(data));
+        const msg = JSON.parse(data);
+        // Unit variants like `"Resync"` are sent as plain strings.
+        this.handleMessage(typeof msg === "string" ? { [msg]: null } : msg);
       }
     };
   }
 
+  /** Stop syncing after the client has fallen out of sync with the server. */
+  private desynchronize() {
+    this.dispose();
+    this.options.onDesynchronized?.();
+  }
+
   private handleMessage(msg: ServerMsg) {
     if (msg.Identity !== undefined) {
-      this.me = msg.Identity;
+      if (this.me !== -1) this.previousIds.add(this.me);
+      if (typeof msg.Identity === "number") {
+        this.me = msg.Identity;
+        this.readOnly = false;
+      } else {
+        // Read-only connections can view the document, but not edit it.
+        this.me = msg.Identity.id;
+        this.readOnly = msg.Identity.readonly;
+        this.options.editor.updateOptions({ readOnly: this.readOnly });
+      }
+    } else if (msg.Limits !== undefined) {
+      this.limits = msg.Limits;
+    } else if (msg.Checkpoint !== undefined) {
+      const { revision, text } = msg.Checkpoint;
+      if (revision <= this.revision) return;
+      if (this.revision > 0 || this.outstanding) {
+        // History we have not seen was compacted, so we cannot catch up.
+        this.desynchronize();
+        return;
+      }
+      this.ignoreChanges = true;
+      this.model.setValue(text);
+      this.lastValue = text;
+      this.ignoreChanges = false;
+      this.revision = revision;
+    } else if (msg.Snapshot !== undefined) {
+      const { revision, text } = msg.Snapshot;
+      if (this.revision > 0 || this.outstanding) {
+        // History we missed while disconnected was compacted.
+        this.desynchronize();
+        return;
+      }
+      this.ignoreChanges = true;
+      this.model.setValue(text);
+      this.lastValue = text;
+      this.ignoreChanges = false;
+      this.revision = revision;
+    } else if (msg.Resync !== undefined) {
+      this.desynchronize();
+    } else if (msg.Deleted !== undefined) {
+      // Stop reconnecting, since the document is gone.
+      this.dispose();
+      this.options.onDeleted?.();
+    } else if (msg.ServerShutdown !== undefined) {
+      // Wait for the server to come back before reconnecting.
+      this.retryAt = Date.now() + msg.ServerShutdown.retry_after * 1000;
+    } else if (msg.Error !== undefined) {
+      // After a fatal error, the server closes the connection next, and we
+      // reconnect as usual.
+      const { code, message, fatal } = msg.Error;
+      console.warn(`Server error ${code}: ${message}`);
+      this.options.onError?.(code, message, fatal);
     } else if (msg.History !== undefined) {
       const { start, operations } = msg.History;
       if (start > this.revision) {
@@ -170,12 +266,21 @@ class Rustpad {
         let { id, operation } = operations[i];
         this.revision++;
         if (id === this.me) {
+          // Our own edits are acknowledged separately, by an `Ack` message.
+        } else if (this.previousIds.has(id)) {
+          // Only the outstanding edit can be unseen from an earlier connection.
           this.serverAck();
         } else {
           operation = OpSeq.from_str(JSON.stringify(operation));
           this.applyServer(operation);
         }
       }
+    } else if (msg.Ack !== undefined) {
+      // Resubmitted edits that were already seen in the history are
+      // acknowledged again, after we have moved on.
+      if (msg.Ack.seq === this.seq && this.outstanding) {
+        this.serverAck();
+      }
     } else if (msg.Language !== undefined) {
       this.options.onChangeLanguage?.(msg.Language);
     } else if (msg.UserInfo !== undefined) {
@@ -208,6 +313,7 @@ class Rustpad {
     this.outstanding = this.buffer;
     this.buffer = undefined;
     if (this.outstanding) {
+      this.seq++;
       this.sendOperation(this.outstanding);
     }
   }
@@ -228,6 +334,7 @@ class Rustpad {
 
   private applyClient(operation: OpSeq) {
     if (!this.outstanding) {
+      this.seq++;
       this.sendOperation(operation);
       this.outstanding = operation;
     } else if (!this.buffer) {
@@ -240,7 +347,10 @@ class Rustpad {
 
   private sendOperation(operation: OpSeq) {
     const op = operation.to_string();
-    this.ws?.send(`{"Edit":{"revision":${this.revision},"operation":${op}}}`);
+    const { revision, seq, session } = this;
+    this.ws?.send(
+      `{"Edit":{"revision":${revision},"operation":${op},"seq":${seq},"session":"${session}"}}`,
+    );
   }
 
   private sendInfo() {
@@ -250,7 +360,8 @@ class Rustpad {
   }
 
   private sendCursorData() {
-    if (!this.buffer) {
+    // Viewers are disconnected if they share their cursor.
+    if (!this.buffer && !this.readOnly) {
       this.ws?.send(`{"CursorData":${JSON.stringify(this.cursorData)}}`);
     }
   }
@@ -427,7 +538,18 @@ class Rustpad {
       }
       this.applyClient(operation);
       this.lastValue = this.model.getValue();
+      this.checkLimits(operation.target_len());
+    }
+  }
+
+  /** Warns once when local edits bring the document close to its size limit. */
+  private checkLimits(length: number) {
+    if (!this.limits) return;
+    const near = length >= NEAR_LIMIT_RATIO * this.limits.max_document_size;
+    if (near && !this.nearLimit) {
+      this.options.onNearLimit?.(this.limits);
     }
+    this.nearLimit = near;
   }
 
   private onCursor(event: editor.ICursorPositionChangedEvent) {
@@ -447,6 +569,7 @@ class Rustpad {
 type UserOperation = {
   id: number;
   operation: any;
+  time: number;
 };
 
 type CursorData = {
@@ -455,11 +578,34 @@ type CursorData = {
 };
 
 type ServerMsg = {
-  Identity?: number;
+  Identity?: number | { id: number; readonly: boolean };
+  Checkpoint?: {
+    revision: number;
+    text: string;
+  };
+  Snapshot?: {
+    revision: number;
+    text: string;
+  };
+  Resync?: null;
+  Deleted?: null;
+  ServerShutdown?: {
+    retry_after: number;
+  };
+  Limits?: DocumentLimits;
+  Error?: {
+    code: string;
+    message: string;
+    fatal: boolean;
+  };
   History?: {
     start: number;
     operations: UserOperation[];
   };
+  Ack?: {
+    revision: number;
+    seq: number | null;
+  };
   Language?: string;
   UserInfo?: {
     id: number;
@@ -471,6 +617,12 @@ type ServerMsg = {
   };
 };
 
+/** Returns a random hexadecimal token, to identify a client session. */
+function randomToken(): string {
+  const bytes = crypto.getRandomValues(new Uint8Array(16));
+  return Array.from(bytes, (b) => b.toString(16).padStart(2, "0")).join("");
+}
+
 /** Returns the number of Unicode codepoints in a string. */
 function unicodeLength(str: string): number {
   let length = 0;